@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+///
+/// tracks when each instance was last used through the proxy, so instances nobody is using
+/// can be removed before they expire. keyed by instance name
+///
+#[derive(Clone)]
+pub struct ActivityTracker {
+    instances: Arc<Mutex<HashMap<String, Activity>>>,
+
+    /// when ink started. activity is not kept across restarts, so instances that have not
+    /// been used since then are treated as last used at this time
+    started: SystemTime,
+}
+
+#[derive(Default)]
+struct Activity {
+    /// last time a request was proxied or a websocket closed
+    last_seen: Option<SystemTime>,
+
+    /// how many websockets are open to the instance
+    connections: usize,
+}
+
+///
+/// held for as long as a websocket to an instance is open. the instance is not idle while any are held
+///
+pub struct ConnectionGuard {
+    tracker: ActivityTracker,
+    name: String,
+}
+
+impl ActivityTracker {
+    pub fn new() -> Self {
+        return ActivityTracker {
+            instances: Arc::new(Mutex::new(HashMap::new())),
+            started: SystemTime::now(),
+        };
+    }
+
+    ///
+    /// record that `name` was just used
+    ///
+    pub fn touch(&self, name: &str) {
+        self.instances
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .last_seen = Some(SystemTime::now());
+    }
+
+    ///
+    /// record that a websocket to `name` was opened, which lasts until the returned guard is dropped
+    ///
+    pub fn connect(&self, name: &str) -> ConnectionGuard {
+        let mut instances = self.instances.lock().unwrap();
+        let activity = instances.entry(name.to_string()).or_default();
+        activity.connections += 1;
+        activity.last_seen = Some(SystemTime::now());
+
+        return ConnectionGuard {
+            tracker: self.clone(),
+            name: name.to_string(),
+        };
+    }
+
+    ///
+    /// when `name` was last used, or None if it is in use right now. `created_on` is used
+    /// for instances that have not been used since ink started
+    ///
+    pub fn idle_since(&self, name: &str, created_on: SystemTime) -> Option<SystemTime> {
+        let fallback = created_on.max(self.started);
+
+        return match self.instances.lock().unwrap().get(name) {
+            Some(activity) if activity.connections > 0 => None,
+            Some(activity) => Some(activity.last_seen.unwrap_or(fallback)),
+            None => Some(fallback),
+        };
+    }
+
+    ///
+    /// stop tracking `name`, such as when it is removed
+    ///
+    pub fn forget(&self, name: &str) {
+        let mut instances = self.instances.lock().unwrap();
+
+        // open websockets still need their entry so the count stays right when they close
+        if instances.get(name).is_some_and(|a| a.connections == 0) {
+            instances.remove(name);
+        }
+    }
+}
+
+impl Default for ActivityTracker {
+    fn default() -> Self {
+        return ActivityTracker::new();
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut instances = self.tracker.instances.lock().unwrap();
+        if let Some(activity) = instances.get_mut(&self.name) {
+            activity.connections = activity.connections.saturating_sub(1);
+            activity.last_seen = Some(SystemTime::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn never_used() {
+        let tracker = ActivityTracker::new();
+
+        // created before ink started, so idle since ink started
+        let created_on = tracker.started - Duration::from_secs(60);
+        assert_eq!(tracker.idle_since("a", created_on), Some(tracker.started));
+
+        let created_on = tracker.started + Duration::from_secs(60);
+        assert_eq!(tracker.idle_since("a", created_on), Some(created_on));
+    }
+
+    #[test]
+    fn touched() {
+        let tracker = ActivityTracker::new();
+        let before = SystemTime::now();
+
+        tracker.touch("a");
+        let idle_since = tracker.idle_since("a", tracker.started).unwrap();
+        assert!(idle_since >= before);
+
+        // other instances are not touched
+        assert_eq!(
+            tracker.idle_since("b", tracker.started),
+            Some(tracker.started)
+        );
+    }
+
+    #[test]
+    fn not_idle_while_connected() {
+        let tracker = ActivityTracker::new();
+
+        let first = tracker.connect("a");
+        let second = tracker.connect("a");
+        assert_eq!(tracker.idle_since("a", tracker.started), None);
+
+        drop(first);
+        assert_eq!(tracker.idle_since("a", tracker.started), None);
+
+        // idle from when the last websocket closed
+        let before = SystemTime::now();
+        drop(second);
+        assert!(tracker.idle_since("a", tracker.started).unwrap() >= before);
+    }
+
+    #[test]
+    fn forget() {
+        let tracker = ActivityTracker::new();
+        tracker.touch("a");
+        tracker.forget("a");
+        assert_eq!(
+            tracker.idle_since("a", tracker.started),
+            Some(tracker.started)
+        );
+
+        // the count of open websockets is kept until they close
+        let guard = tracker.connect("b");
+        tracker.forget("b");
+        assert_eq!(tracker.idle_since("b", tracker.started), None);
+        drop(guard);
+        assert!(tracker.idle_since("b", tracker.started).is_some());
+    }
+}
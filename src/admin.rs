@@ -0,0 +1,346 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, anyhow};
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::response::IntoResponse;
+use http::StatusCode;
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::api::RemovedInstance;
+use crate::api_error::{ApiError, ErrorBody};
+use crate::app_state::AppState;
+use crate::db::UserRole;
+use crate::instance::{InstanceStatus, SquittalInstance};
+use crate::roles::{Admin, Role};
+use crate::{api, docker, waitlist};
+
+///
+/// list all instances with full details, including the name and port that are hidden from users
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/instances",
+    tag = "admin",
+    responses(
+        (status = 200, description = "every instance", body = Vec<SquittalInstance>),
+        (status = 403, description = "not an admin", body = ErrorBody),
+    )
+)]
+pub async fn list_instances(
+    State(state): State<AppState>,
+    _: Admin,
+) -> Result<impl IntoResponse, ApiError> {
+    let thresholds = &state.config.instances.warning_thresholds_secs;
+    let instances: Vec<SquittalInstance> = state
+        .index
+        .all()
+        .into_iter()
+        .map(|i| i.with_warning(thresholds))
+        .collect();
+
+    return Ok(Json(instances));
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct RemoveInstanceQuery {
+    /// why the instance was removed, kept in the DB
+    reason: Option<String>,
+}
+
+///
+/// remove any instance, no matter who owns it
+///
+#[utoipa::path(
+    delete,
+    path = "/api/v1/admin/instances/{name}",
+    tag = "admin",
+    params(("name" = String, Path, description = "name of the instance"), RemoveInstanceQuery),
+    responses(
+        (status = 200, description = "the instance was removed", body = RemovedInstance),
+        (status = 403, description = "not an admin", body = ErrorBody),
+        (status = 404, description = "the instance does not exist", body = ErrorBody),
+        (status = 409, description = "the instance is still being created", body = ErrorBody),
+    )
+)]
+pub async fn remove_instance(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<RemoveInstanceQuery>,
+    Admin(admin): Admin,
+) -> Result<impl IntoResponse, ApiError> {
+    let instance = state
+        .index
+        .get(&name)
+        .ok_or_else(|| ApiError::NotFound(format!("instance {name} does not exist")))?;
+
+    let reason = match query.reason.as_deref().map(str::trim) {
+        Some(r) if !r.is_empty() => format!("removed by admin {}: {r}", admin.id),
+        _ => format!("removed by admin {}", admin.id),
+    };
+
+    if instance.status == InstanceStatus::Failed {
+        state.index.remove_pending(&name);
+        return Ok(Json(RemovedInstance { name, reason }));
+    }
+    api::ensure_ready(&instance, &name)?;
+
+    tracing::info!(
+        "admin {}/{} is removing instance {}",
+        &admin.id,
+        &admin.username,
+        &name
+    );
+    docker::remove_container(&state, &docker::container_name(&name), &reason)
+        .await
+        .map_err(|e| anyhow!("failed to remove instance {name}: {e}"))?;
+
+    let provision_state = state.clone();
+    tokio::spawn(async move { waitlist::provision_next(&provision_state).await });
+
+    return Ok(Json(RemovedInstance { name, reason }));
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ExtendInstanceQuery {
+    /// how long to extend the instance by, in seconds. defaults to instances.extend_secs, and cannot be more
+    /// than instances.admin_max_extend_secs
+    secs: Option<u64>,
+}
+
+///
+/// push back when any instance expires. unlike owners, admins are not held to the max lifetime
+///
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/instances/{name}/extend",
+    tag = "admin",
+    params(("name" = String, Path, description = "name of the instance"), ExtendInstanceQuery),
+    responses(
+        (status = 200, description = "the instance, with when it now expires", body = SquittalInstance),
+        (status = 400, description = "the extension is too long", body = ErrorBody),
+        (status = 403, description = "not an admin", body = ErrorBody),
+        (status = 404, description = "the instance does not exist", body = ErrorBody),
+        (status = 409, description = "the instance is not ready", body = ErrorBody),
+    )
+)]
+pub async fn extend_instance(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<ExtendInstanceQuery>,
+    Admin(admin): Admin,
+) -> Result<impl IntoResponse, ApiError> {
+    let instance = state
+        .index
+        .get(&name)
+        .ok_or_else(|| ApiError::NotFound(format!("instance {name} does not exist")))?;
+    api::ensure_ready(&instance, &name)?;
+
+    let config = &state.config.instances;
+    let extend = query.secs.unwrap_or(config.extend_secs);
+    if extend > config.admin_max_extend_secs {
+        return Err(ApiError::BadRequest(format!(
+            "cannot extend an instance by more than {} seconds at once",
+            config.admin_max_extend_secs
+        )));
+    }
+    let expires_at = std::cmp::max(instance.expires_at, SystemTime::now())
+        .checked_add(Duration::from_secs(extend))
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!("cannot extend instance {name} by {extend} seconds"))
+        })?;
+
+    state
+        .db
+        .set_expires_at(&name, expires_at, &admin.id)
+        .context("failed to save new expiration")?;
+    state.index.update(&name, |i| i.expires_at = expires_at);
+
+    tracing::info!(
+        "admin {}/{} extended instance {} to {:?}",
+        &admin.id,
+        &admin.username,
+        &name,
+        expires_at
+    );
+
+    let mut instance = instance;
+    instance.expires_at = expires_at;
+    let instance = instance.with_warning(&config.warning_thresholds_secs);
+
+    return Ok(Json(instance));
+}
+
+///
+/// list every user who has been given a role from the admin API
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/roles",
+    tag = "admin",
+    responses(
+        (status = 200, description = "every user with a role", body = Vec<UserRole>),
+        (status = 403, description = "not an admin", body = ErrorBody),
+    )
+)]
+pub async fn list_roles(
+    State(state): State<AppState>,
+    _: Admin,
+) -> Result<impl IntoResponse, ApiError> {
+    return Ok(Json(state.db.roles()?));
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SetRole {
+    role: Role,
+
+    /// why the role was given, such as why a user was banned
+    reason: Option<String>,
+}
+
+///
+/// set the role of a user. banning a user also removes their instance and takes them out of the waitlist
+///
+#[utoipa::path(
+    put,
+    path = "/api/v1/admin/roles/{user_id}",
+    tag = "admin",
+    params(("user_id" = String, Path, description = "ID of the user")),
+    request_body = SetRole,
+    responses(
+        (status = 204, description = "the role was set"),
+        (status = 400, description = "the user is an admin in the config", body = ErrorBody),
+        (status = 403, description = "not an admin", body = ErrorBody),
+    )
+)]
+pub async fn set_role(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+    Admin(admin): Admin,
+    Json(body): Json<SetRole>,
+) -> Result<impl IntoResponse, ApiError> {
+    if state.config.access.admins.contains(&user_id) {
+        return Err(ApiError::BadRequest(format!(
+            "{user_id} is an admin in the config, and cannot be changed here"
+        )));
+    }
+
+    let reason = body
+        .reason
+        .as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty());
+
+    state
+        .db
+        .set_role(&user_id, body.role, reason, &admin.id)
+        .context("failed to save role")?;
+
+    if body.role == Role::Banned {
+        state.waitlist.remove(&user_id);
+
+        for instance in state.index.by_owner(&user_id) {
+            let name = docker::instance_name(&instance.name).to_string();
+            tracing::info!("removing instance {} of banned user {}", &name, &user_id);
+
+            // instances still being created are removed once the container is made
+            if instance.status != InstanceStatus::Ready {
+                state.index.remove_pending(&name);
+                continue;
+            }
+
+            if let Err(e) =
+                docker::remove_container(&state, &docker::container_name(&name), "owner banned")
+                    .await
+            {
+                tracing::error!("failed to remove instance {} of banned user: {}", &name, e);
+            }
+        }
+
+        let provision_state = state.clone();
+        tokio::spawn(async move { waitlist::provision_next(&provision_state).await });
+    }
+
+    return Ok(StatusCode::NO_CONTENT);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::fake_backend::FakeFailure;
+    use crate::test_util;
+
+    use super::*;
+
+    fn remove_query(reason: Option<&str>) -> Query<RemoveInstanceQuery> {
+        return Query(RemoveInstanceQuery {
+            reason: reason.map(str::to_string),
+        });
+    }
+
+    async fn removed(response: impl IntoResponse) -> serde_json::Value {
+        let response = response.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        return serde_json::from_slice(&body).unwrap();
+    }
+
+    #[tokio::test]
+    async fn remove_instance_of_someone_else() {
+        let state = test_util::state(Config::default()).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        let response = remove_instance(
+            State(state.clone()),
+            Path(name.clone()),
+            remove_query(Some(" stuck ")),
+            Admin(test_util::user("9")),
+        )
+        .await
+        .unwrap();
+
+        // the same body as when the owner removes it
+        let body = removed(response).await;
+        assert_eq!(body["name"], name);
+        assert_eq!(body["reason"], "removed by admin 9: stuck");
+        assert!(state.index.get(&name).is_none());
+    }
+
+    #[tokio::test]
+    async fn remove_failed_instance() {
+        let (state, backend) = test_util::state_with_backend(Config::default()).await;
+        backend.fail(FakeFailure::Start);
+        api::create_instance(State(state.clone()), test_util::user("1"), None)
+            .await
+            .unwrap();
+
+        let instance = state.index.by_owner("1").remove(0);
+        let name = docker::instance_name(&instance.name).to_string();
+        let failed = async {
+            while state.index.get(&name).unwrap().status != InstanceStatus::Failed {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), failed)
+            .await
+            .unwrap();
+
+        let response = remove_instance(
+            State(state.clone()),
+            Path(name.clone()),
+            remove_query(None),
+            Admin(test_util::user("9")),
+        )
+        .await
+        .unwrap();
+
+        let body = removed(response).await;
+        assert_eq!(body["name"], name);
+        assert_eq!(body["reason"], "removed by admin 9");
+        assert!(state.index.get(&name).is_none());
+    }
+}
@@ -1,18 +1,44 @@
+use std::time::SystemTime;
+
 use anyhow::{Context, Result, anyhow};
 use axum::Json;
+use axum::extract::{Path, Query, State};
 use axum::response::IntoResponse;
-use http::StatusCode;
+use http::{StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
-use crate::instance::SquittalInstance;
-use crate::{AppError, User, docker};
+use crate::api_error::{ApiError, ErrorBody};
+use crate::app_state::AppState;
+use crate::identity::ProviderInfo;
+use crate::instance::{InstanceStatus, SquittalInstance};
+use crate::roles::{self, Role};
+use crate::waitlist::QueuePosition;
+use crate::{User, docker, snapshots, waitlist};
 
 ///
 /// list all instances currently running. requires an authed user, and removes instance name and port from response
 ///
-pub async fn list_instances(_: User) -> Result<impl IntoResponse, AppError> {
-    let instances: Vec<SquittalInstance> = docker::get_instances()
-        .await
-        .context("failed to get running instances")?;
+#[utoipa::path(
+    get,
+    path = "/api/v1/instances",
+    tag = "instances",
+    responses(
+        (status = 200, description = "every instance, without the name and port", body = Vec<SquittalInstance>),
+        (status = 401, description = "not logged in", body = ErrorBody),
+    )
+)]
+pub async fn list_instances(
+    State(state): State<AppState>,
+    _: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let thresholds = &state.config.instances.warning_thresholds_secs;
+    let instances: Vec<SquittalInstance> = state
+        .index
+        .all()
+        .into_iter()
+        .map(|i| i.with_warning(thresholds))
+        .collect();
 
     // remove name and port from the instances so others cannot find exposed instances and mess with them
     let instances = instances
@@ -21,6 +47,7 @@ pub async fn list_instances(_: User) -> Result<impl IntoResponse, AppError> {
             let mut i = iter.clone();
             i.name = "".to_string();
             i.port = 0;
+            i.failure = None;
             return i;
         })
         .collect::<Vec<SquittalInstance>>();
@@ -28,76 +55,549 @@ pub async fn list_instances(_: User) -> Result<impl IntoResponse, AppError> {
     return Ok(Json(instances));
 }
 
-pub async fn get_instance(user: User) -> Result<impl IntoResponse, AppError> {
-    let owner_instances = docker::get_instance_by_owner(&user.id)
-        .await
-        .context("failed to get instances of user")?;
+///
+/// get the instance of the user, with the name and port needed to connect to it
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/instances/mine",
+    tag = "instances",
+    responses(
+        (status = 200, description = "the instance of the user", body = SquittalInstance),
+        (status = 404, description = "the user does not have an instance", body = ErrorBody),
+    )
+)]
+pub async fn get_instance(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let owner_instances = state.index.by_owner(&user.id);
 
-    if owner_instances.len() == 0 {
-        return Ok(StatusCode::NO_CONTENT.into_response());
+    if owner_instances.is_empty() {
+        return Err(ApiError::NotFound(
+            "you do not have an instance".to_string(),
+        ));
     }
 
-    return Ok(Json(&owner_instances[0]).into_response());
+    let instance = owner_instances[0]
+        .clone()
+        .with_warning(&state.config.instances.warning_thresholds_secs);
+
+    return Ok(Json(instance));
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct CreateInstance {
+    /// ID of a snapshot of the user to restore into the data volume of the new instance
+    snapshot: Option<i64>,
 }
 
 ///
-/// create a new instance of the squittal container, and update the tracking in the DB
+/// start creating a new instance of the squittal container. the instance is returned at once in the
+/// provisioning state, and is ready once it has a port. if ink is at the max number of instances, or
+/// others are already waiting, a 503 is returned, and the user can join the waitlist instead
 ///
-pub async fn create_instance(user: User) -> Result<impl IntoResponse, AppError> {
+#[utoipa::path(
+    post,
+    path = "/api/v1/instances",
+    tag = "instances",
+    request_body(content = Option<CreateInstance>, description = "optional, to start from a snapshot"),
+    responses(
+        (status = 202, description = "the new instance, which is still being created", body = SquittalInstance),
+        (status = 400, description = "instances have no data volume to restore a snapshot into", body = ErrorBody),
+        (status = 404, description = "the snapshot does not exist", body = ErrorBody),
+        (status = 409, description = "the user already has an instance", body = ErrorBody),
+        (status = 503, description = "all instances are in use", body = ErrorBody),
+    )
+)]
+pub async fn create_instance(
+    State(state): State<AppState>,
+    user: User,
+    body: Option<Json<CreateInstance>>,
+) -> Result<impl IntoResponse, ApiError> {
     tracing::info!("creating new instance for {}/{}", &user.id, &user.username);
 
-    // check if owner already has an instance up
-    let owner_instances = docker::get_instance_by_owner(&user.id)
-        .await
-        .context("failed to get instances of user")?;
+    // check if owner already has an instance up. one that failed to be created is replaced
+    let owner_instances = state.index.active_by_owner(&user.id);
 
-    if owner_instances.len() >= 1 {
-        return Ok((
-            StatusCode::BAD_REQUEST,
-            format!("user already has instance {}", owner_instances[0].name),
-        )
-            .into_response());
+    if !owner_instances.is_empty() {
+        return Err(ApiError::AlreadyHasInstance(format!(
+            "user already has instance {}",
+            docker::instance_name(&owner_instances[0].name)
+        )));
     }
 
-    // make sure ink is not capped on instances created
-    let instances = docker::get_instances()
-        .await
-        .context("failed to get running instances")?;
+    let snapshot = match body.unwrap_or_default().snapshot {
+        Some(id) => Some(snapshots::find(&state, &user.id, id)?),
+        None => None,
+    };
 
-    if instances.len() >= 5 {
-        return Ok((StatusCode::BAD_REQUEST, "already running max instances").into_response());
+    // make sure ink is not capped on instances created, and nobody is ahead of this user
+    if state.index.len() >= state.config.instances.max_instances || !state.waitlist.is_empty() {
+        return Err(ApiError::AtCapacity);
     }
 
     // user has no instances, and there is room for another one, make it!
-    let container = docker::create_container(&user.id).await;
-    if container.is_err() {
-        return Err(anyhow!("cannot create new instance: {}", container.unwrap_err()).into());
+    let instance = docker::provision_instance(&state, &user.id, &user.username, snapshot)?;
+
+    return Ok((StatusCode::ACCEPTED, Json(instance)));
+}
+
+///
+/// get where the user is in the waitlist
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/queue",
+    tag = "queue",
+    responses(
+        (status = 200, description = "where the user is in the waitlist", body = QueuePosition),
+        (status = 404, description = "the user is not in the waitlist", body = ErrorBody),
+    )
+)]
+pub async fn get_queue_position(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    return match state.waitlist.position(&user.id) {
+        Some(position) => Ok(Json(position)),
+        None => Err(ApiError::NotFound(
+            "you are not in the waitlist".to_string(),
+        )),
+    };
+}
+
+///
+/// put the user in the waitlist, to get an instance once one frees up. users already waiting
+/// keep their place, and the snapshot they asked for first. the waitlist is only kept in memory,
+/// so it is lost if ink restarts
+///
+#[utoipa::path(
+    post,
+    path = "/api/v1/queue",
+    tag = "queue",
+    request_body(content = Option<CreateInstance>, description = "optional, to start from a snapshot"),
+    responses(
+        (status = 202, description = "where the user is in the waitlist", body = QueuePosition),
+        (status = 400, description = "instances have no data volume to restore a snapshot into", body = ErrorBody),
+        (status = 404, description = "the snapshot does not exist", body = ErrorBody),
+        (status = 409, description = "the user already has an instance", body = ErrorBody),
+    )
+)]
+pub async fn join_queue(
+    State(state): State<AppState>,
+    user: User,
+    body: Option<Json<CreateInstance>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let owner_instances = state.index.active_by_owner(&user.id);
+    if !owner_instances.is_empty() {
+        return Err(ApiError::AlreadyHasInstance(format!(
+            "user already has instance {}",
+            docker::instance_name(&owner_instances[0].name)
+        )));
     }
 
-    let container: (String, u16) = container.unwrap();
+    let snapshot = match body.unwrap_or_default().snapshot {
+        Some(id) => Some(snapshots::find(&state, &user.id, id)?),
+        None => None,
+    };
 
-    let instance: SquittalInstance = SquittalInstance {
-        name: container.0,
-        port: container.1.into(),
-        created_by: user.id,
-        created_on: std::time::SystemTime::now(),
+    let position = state.waitlist.push(&user.id, &user.username, snapshot);
+    tracing::info!(
+        "{}/{} is waiting for an instance at position {}",
+        &user.id,
+        &user.username,
+        position.position
+    );
+
+    // there may be room already, such as when nobody else is waiting
+    let provision_state = state.clone();
+    tokio::spawn(async move { waitlist::provision_next(&provision_state).await });
+
+    return Ok((StatusCode::ACCEPTED, Json(position)));
+}
+
+///
+/// take the user out of the waitlist
+///
+#[utoipa::path(
+    delete,
+    path = "/api/v1/queue",
+    tag = "queue",
+    responses(
+        (status = 204, description = "the user left the waitlist"),
+        (status = 404, description = "the user is not in the waitlist", body = ErrorBody),
+    )
+)]
+pub async fn leave_queue(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    if !state.waitlist.remove(&user.id) {
+        return Err(ApiError::NotFound(
+            "you are not in the waitlist".to_string(),
+        ));
+    }
+
+    tracing::info!("{}/{} left the waitlist", &user.id, &user.username);
+    return Ok(StatusCode::NO_CONTENT);
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct RemoveInstanceQuery {
+    /// optional note from the owner on why they removed the instance, kept in the DB
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RemovedInstance {
+    pub name: String,
+
+    /// why the instance was removed, as kept in the DB
+    pub reason: String,
+}
+
+///
+/// remove an instance owned by the user, freeing up a slot for them to create another
+///
+#[utoipa::path(
+    delete,
+    path = "/api/v1/instances/{name}",
+    tag = "instances",
+    params(("name" = String, Path, description = "name of the instance"), RemoveInstanceQuery),
+    responses(
+        (status = 200, description = "the instance was removed", body = RemovedInstance),
+        (status = 403, description = "the instance is owned by someone else", body = ErrorBody),
+        (status = 404, description = "the instance does not exist", body = ErrorBody),
+        (status = 409, description = "the instance is still being created", body = ErrorBody),
+    )
+)]
+pub async fn remove_instance(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<RemoveInstanceQuery>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let instance = state
+        .index
+        .get(&name)
+        .ok_or_else(|| ApiError::NotFound(format!("instance {name} does not exist")))?;
+
+    if instance.created_by != user.id {
+        return Err(ApiError::Forbidden(format!(
+            "instance {name} is not owned by you"
+        )));
+    }
+
+    let reason = match query.reason.as_deref().map(str::trim) {
+        Some(r) if !r.is_empty() => format!("removed by owner: {r}"),
+        _ => "removed by owner".to_string(),
     };
 
+    // there is no container to stop, the owner is only dismissing the failure
+    if instance.status == InstanceStatus::Failed {
+        state.index.remove_pending(&name);
+        return Ok(Json(RemovedInstance { name, reason }));
+    }
+    ensure_ready(&instance, &name)?;
+
     tracing::info!(
-        "created instance {} for {}/{} on port {}",
-        instance.name,
-        &instance.created_by,
+        "{}/{} is removing instance {}",
+        &user.id,
         &user.username,
-        instance.port
+        &name
     );
+    docker::remove_container(&state, &docker::container_name(&name), &reason)
+        .await
+        .map_err(|e| anyhow!("failed to remove instance {name}: {e}"))?;
+
+    // give the freed slot to whoever is waiting
+    let provision_state = state.clone();
+    tokio::spawn(async move { waitlist::provision_next(&provision_state).await });
 
-    return Ok(Json(instance).into_response());
+    return Ok(Json(RemovedInstance { name, reason }));
 }
 
-pub async fn whoami(user: Option<User>) -> impl IntoResponse {
-    if user.is_some() {
-        return Json(user.unwrap()).into_response();
+///
+/// push back when an instance owned by the user expires, up to the max lifetime of an instance
+///
+#[utoipa::path(
+    post,
+    path = "/api/v1/instances/{name}/extend",
+    tag = "instances",
+    params(("name" = String, Path, description = "name of the instance")),
+    responses(
+        (status = 200, description = "the instance, with when it now expires", body = SquittalInstance),
+        (status = 403, description = "the instance is owned by someone else", body = ErrorBody),
+        (status = 404, description = "the instance does not exist", body = ErrorBody),
+        (status = 409, description = "the instance cannot be extended any further, or is not ready", body = ErrorBody),
+    )
+)]
+pub async fn extend_instance(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let instance = state
+        .index
+        .get(&name)
+        .ok_or_else(|| ApiError::NotFound(format!("instance {name} does not exist")))?;
+
+    if instance.created_by != user.id {
+        return Err(ApiError::Forbidden(format!(
+            "instance {name} is not owned by you"
+        )));
     }
+    ensure_ready(&instance, &name)?;
 
-    return StatusCode::NO_CONTENT.into_response();
+    let config = &state.config.instances;
+    let max_expires_at = instance.created_on + config.max_lifetime();
+    if instance.expires_at >= max_expires_at {
+        return Err(ApiError::MaxLifetime(format!(
+            "instance {name} cannot be extended any further"
+        )));
+    }
+
+    let expires_at = std::cmp::min(
+        std::cmp::max(instance.expires_at, SystemTime::now()) + config.extend(),
+        max_expires_at,
+    );
+
+    state
+        .db
+        .set_expires_at(&name, expires_at, &user.id)
+        .context("failed to save new expiration")?;
+    state.index.update(&name, |i| i.expires_at = expires_at);
+
+    tracing::info!(
+        "{}/{} extended instance {} to {:?}",
+        &user.id,
+        &user.username,
+        &name,
+        expires_at
+    );
+
+    let mut instance = instance;
+    instance.expires_at = expires_at;
+    let instance = instance.with_warning(&config.warning_thresholds_secs);
+
+    return Ok(Json(instance));
+}
+
+///
+/// make sure an instance is ready, as those still being created (or that failed to be) cannot be
+/// removed or extended
+///
+pub fn ensure_ready(instance: &SquittalInstance, name: &str) -> Result<(), ApiError> {
+    return match instance.status {
+        InstanceStatus::Ready => Ok(()),
+        InstanceStatus::Provisioning | InstanceStatus::Starting => Err(ApiError::NotReady(
+            format!("instance {name} is still being created"),
+        )),
+        InstanceStatus::Failed => Err(ApiError::NotReady(format!(
+            "instance {name} failed to be created"
+        ))),
+    };
+}
+
+///
+/// list the providers users can log in with
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/providers",
+    tag = "users",
+    security(()),
+    responses((status = 200, description = "providers users can log in with, at /auth/{id}", body = Vec<ProviderInfo>))
+)]
+pub async fn list_providers(State(state): State<AppState>) -> impl IntoResponse {
+    return Json(state.identity.list());
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WhoAmI {
+    #[serde(flatten)]
+    user: User,
+    role: Role,
+}
+
+///
+/// get the logged in user, and their role
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/whoami",
+    tag = "users",
+    responses(
+        (status = 200, description = "the logged in user", body = WhoAmI),
+        (status = 401, description = "not logged in", body = ErrorBody),
+    )
+)]
+pub async fn whoami(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let role = roles::role_of(&state.config, &state.db, &user.id)?;
+    return Ok(Json(WhoAmI { user, role }));
+}
+
+///
+/// anything under /api that is not an endpoint, so scripts get a JSON error instead of a page
+///
+pub async fn unknown_endpoint(uri: Uri) -> ApiError {
+    return ApiError::NotFound(format!("{} is not an API endpoint", uri.path()));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::fake_backend::FakeFailure;
+    use crate::test_util;
+
+    use super::*;
+
+    fn remove_query() -> Query<RemoveInstanceQuery> {
+        return Query(RemoveInstanceQuery { reason: None });
+    }
+
+    #[tokio::test]
+    async fn create_at_max_instances() {
+        let mut config = Config::default();
+        config.instances.max_instances = 1;
+        let state = test_util::state(config).await;
+
+        test_util::running_instance(&state, "1").await;
+
+        let result = create_instance(State(state.clone()), test_util::user("2"), None).await;
+        assert!(matches!(result.err(), Some(ApiError::AtCapacity)));
+        assert!(state.index.by_owner("2").is_empty());
+    }
+
+    #[tokio::test]
+    async fn create_while_others_wait() {
+        let state = test_util::state(Config::default()).await;
+        state.waitlist.push("1", "user1", None);
+
+        let result = create_instance(State(state.clone()), test_util::user("2"), None).await;
+        assert!(matches!(result.err(), Some(ApiError::AtCapacity)));
+    }
+
+    #[tokio::test]
+    async fn create_second_instance() {
+        let state = test_util::state(Config::default()).await;
+
+        test_util::running_instance(&state, "1").await;
+
+        let result = create_instance(State(state.clone()), test_util::user("1"), None).await;
+        assert!(matches!(
+            result.err(),
+            Some(ApiError::AlreadyHasInstance(_))
+        ));
+        assert_eq!(state.index.by_owner("1").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn remove_instance_of_someone_else() {
+        let state = test_util::state(Config::default()).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        let result = remove_instance(
+            State(state.clone()),
+            Path(name.clone()),
+            remove_query(),
+            test_util::user("2"),
+        )
+        .await;
+        assert!(matches!(result.err(), Some(ApiError::Forbidden(_))));
+
+        let containers = state
+            .backend
+            .list_containers(&state.config.docker.image)
+            .await
+            .unwrap();
+        assert_eq!(containers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn extend_instance_of_someone_else() {
+        let state = test_util::state(Config::default()).await;
+        let name = test_util::running_instance(&state, "1").await;
+        let expires_at = state.index.get(&name).unwrap().expires_at;
+
+        let result = extend_instance(
+            State(state.clone()),
+            Path(name.clone()),
+            test_util::user("2"),
+        )
+        .await;
+        assert!(matches!(result.err(), Some(ApiError::Forbidden(_))));
+        assert_eq!(state.index.get(&name).unwrap().expires_at, expires_at);
+    }
+
+    #[tokio::test]
+    async fn dismiss_failed_instance() {
+        let (state, backend) = test_util::state_with_backend(Config::default()).await;
+        backend.fail(FakeFailure::Start);
+
+        if let Err(e) = create_instance(State(state.clone()), test_util::user("1"), None).await {
+            panic!("failed to create instance: {e:?}");
+        }
+        let instance = state.index.by_owner("1").remove(0);
+        let name = docker::instance_name(&instance.name).to_string();
+        let failed = async {
+            while state.index.get(&name).unwrap().status != InstanceStatus::Failed {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), failed)
+            .await
+            .unwrap();
+
+        // only the owner can dismiss it
+        let result = remove_instance(
+            State(state.clone()),
+            Path(name.clone()),
+            remove_query(),
+            test_util::user("2"),
+        )
+        .await;
+        assert!(matches!(result.err(), Some(ApiError::Forbidden(_))));
+        assert!(state.index.get(&name).is_some());
+
+        remove_instance(
+            State(state.clone()),
+            Path(name.clone()),
+            remove_query(),
+            test_util::user("1"),
+        )
+        .await
+        .map_err(|e| format!("{e:?}"))
+        .unwrap();
+        assert!(state.index.get(&name).is_none());
+        assert!(state.index.by_owner("1").is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove_own_instance() {
+        let state = test_util::state(Config::default()).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        remove_instance(
+            State(state.clone()),
+            Path(name.clone()),
+            remove_query(),
+            test_util::user("1"),
+        )
+        .await
+        .map_err(|e| format!("{e:?}"))
+        .unwrap();
+
+        let containers = state
+            .backend
+            .list_containers(&state.config.docker.image)
+            .await
+            .unwrap();
+        assert!(containers.is_empty());
+        assert!(state.index.get(&name).is_none());
+    }
 }
@@ -0,0 +1,150 @@
+use axum::{
+    Json,
+    response::{IntoResponse, Response},
+};
+use http::{StatusCode, header};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+///
+/// everything that can go wrong in the API. each kind has its own status code and a code string, and is
+/// sent back as {"error": {"code": "...", "message": "..."}} so scripts can tell errors apart
+///
+#[derive(Debug)]
+pub enum ApiError {
+    /// no session cookie or API token
+    NotLoggedIn,
+
+    /// the API token does not exist, was revoked, or has expired
+    InvalidToken,
+
+    /// logged in, but banned from ink
+    Banned,
+
+    /// the API token cannot be used for this request
+    MissingScope(String),
+
+    /// logged in, but not allowed to do this, such as removing someone else's instance
+    Forbidden(String),
+
+    NotFound(String),
+
+    BadRequest(String),
+
+    /// the user already has an instance, and can only have one
+    AlreadyHasInstance(String),
+
+    /// the instance has been extended as far as it can be
+    MaxLifetime(String),
+
+    /// the instance is still being created, or failed to be
+    NotReady(String),
+
+    /// ink is running the max number of instances, or others are already waiting for one
+    AtCapacity,
+
+    /// anything else. the error is logged, but not sent back
+    Internal(anyhow::Error),
+}
+
+///
+/// what every error from the API looks like
+///
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorDetail {
+    /// what went wrong, such as at_capacity. these do not change, unlike the message
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        return match self {
+            ApiError::NotLoggedIn | ApiError::InvalidToken => StatusCode::UNAUTHORIZED,
+            ApiError::Banned | ApiError::MissingScope(_) | ApiError::Forbidden(_) => {
+                StatusCode::FORBIDDEN
+            }
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::AlreadyHasInstance(_) | ApiError::MaxLifetime(_) | ApiError::NotReady(_) => {
+                StatusCode::CONFLICT
+            }
+            ApiError::AtCapacity => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+    }
+
+    pub fn code(&self) -> &'static str {
+        return match self {
+            ApiError::NotLoggedIn => "not_logged_in",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::Banned => "banned",
+            ApiError::MissingScope(_) => "missing_scope",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::AlreadyHasInstance(_) => "already_has_instance",
+            ApiError::MaxLifetime(_) => "max_lifetime",
+            ApiError::NotReady(_) => "not_ready",
+            ApiError::AtCapacity => "at_capacity",
+            ApiError::Internal(_) => "internal",
+        };
+    }
+
+    pub fn message(&self) -> String {
+        return match self {
+            ApiError::NotLoggedIn => "not logged in".to_string(),
+            ApiError::InvalidToken => "invalid API token".to_string(),
+            ApiError::Banned => "you are banned from ink".to_string(),
+            ApiError::AtCapacity => {
+                "all instances are in use, join the queue to get one when a slot frees up"
+                    .to_string()
+            }
+            ApiError::Internal(_) => "something went wrong".to_string(),
+            ApiError::MissingScope(msg)
+            | ApiError::Forbidden(msg)
+            | ApiError::NotFound(msg)
+            | ApiError::BadRequest(msg)
+            | ApiError::AlreadyHasInstance(msg)
+            | ApiError::MaxLifetime(msg)
+            | ApiError::NotReady(msg) => msg.clone(),
+        };
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Internal(e) = &self {
+            tracing::error!("application error: {:#}", e);
+        }
+
+        let body = Json(ErrorBody {
+            error: ErrorDetail {
+                code: self.code(),
+                message: self.message(),
+            },
+        });
+
+        return match self {
+            ApiError::InvalidToken => {
+                (self.status(), [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response()
+            }
+            _ => (self.status(), body).into_response(),
+        };
+    }
+}
+
+// lets `?` be used on anything that turns into an anyhow error, which are all internal errors
+impl<E> From<E> for ApiError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        return ApiError::Internal(err.into());
+    }
+}
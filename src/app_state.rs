@@ -1,24 +1,47 @@
-use async_session::MemoryStore;
+use std::sync::Arc;
+
 use axum::extract::FromRef;
-use oauth2::basic::BasicClient;
 
-use crate::proxy;
+use crate::{
+    activity::ActivityTracker, backend::ContainerBackend, config::Config, db::Database,
+    events::EventBus, identity::IdentityProviders, instance_index::InstanceIndex,
+    mssql::InstanceDatabases, notify::Notifier, proxy, session_store::SqliteSessionStore,
+    waitlist::Waitlist,
+};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub store: MemoryStore,
-    pub oauth_client: BasicClient,
+    pub store: SqliteSessionStore,
+    pub identity: IdentityProviders,
     pub client: proxy::Client,
+    pub db: Database,
+    pub config: Arc<Config>,
+    pub backend: Arc<dyn ContainerBackend>,
+
+    /// creates the database of each instance, or None if instances share the one built into the image
+    pub databases: Option<Arc<dyn InstanceDatabases>>,
+
+    pub index: InstanceIndex,
+    pub notifier: Notifier,
+    pub activity: ActivityTracker,
+    pub waitlist: Waitlist,
+    pub events: EventBus,
 }
 
-impl FromRef<AppState> for MemoryStore {
+impl FromRef<AppState> for SqliteSessionStore {
     fn from_ref(state: &AppState) -> Self {
         return state.store.clone();
     }
 }
 
-impl FromRef<AppState> for BasicClient {
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        return state.config.clone();
+    }
+}
+
+impl FromRef<AppState> for Database {
     fn from_ref(state: &AppState) -> Self {
-        return state.oauth_client.clone();
+        return state.db.clone();
     }
 }
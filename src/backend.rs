@@ -0,0 +1,269 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use bollard::{
+    Docker,
+    query_parameters::{
+        CreateContainerOptionsBuilder, DownloadFromContainerOptionsBuilder, EventsOptionsBuilder,
+        InspectContainerOptions, ListContainersOptions, ListContainersOptionsBuilder,
+        ListImagesOptions, ListImagesOptionsBuilder, RemoveContainerOptions, StartContainerOptions,
+        StopContainerOptionsBuilder, UploadToContainerOptionsBuilder,
+    },
+    secret::{
+        ContainerCreateBody, ContainerInspectResponse, ContainerSummary, VolumeCreateOptions,
+    },
+};
+use futures_util::{StreamExt, TryStreamExt, stream::BoxStream};
+
+///
+/// something that happened to an ink container
+///
+#[derive(Debug, Clone)]
+pub struct ContainerEvent {
+    /// name of the container, without the leading /
+    pub name: String,
+
+    /// what happened, such as start, die or destroy
+    pub action: String,
+}
+
+///
+/// the container operations ink needs. docker is the real implementation, and
+/// [`crate::fake_backend::FakeBackend`] keeps containers in memory for running without a daemon
+///
+#[async_trait]
+pub trait ContainerBackend: Send + Sync {
+    ///
+    /// list the containers created by ink (those with the ink_tag label) from `image`
+    ///
+    async fn list_containers(&self, image: &str) -> Result<Vec<ContainerSummary>>;
+
+    ///
+    /// check if `image` exists, and can be used to create containers
+    ///
+    async fn image_exists(&self, image: &str) -> Result<bool>;
+
+    ///
+    /// create a container named `name`, returning the ID of the new container
+    ///
+    async fn create_container(&self, name: &str, body: ContainerCreateBody) -> Result<String>;
+
+    async fn start_container(&self, name: &str) -> Result<()>;
+
+    async fn inspect_container(&self, name: &str) -> Result<ContainerInspectResponse>;
+
+    ///
+    /// stop a container, giving it `grace` to shut down before it is killed
+    ///
+    async fn stop_container(&self, name: &str, grace: Duration) -> Result<()>;
+
+    async fn remove_container(&self, name: &str) -> Result<()>;
+
+    ///
+    /// create a named volume, which is kept until removed even if the container using it is removed
+    ///
+    async fn create_volume(&self, name: &str, labels: HashMap<String, String>) -> Result<()>;
+
+    async fn remove_volume(&self, name: &str) -> Result<()>;
+
+    ///
+    /// freeze every process in a running container, until it is unpaused
+    ///
+    async fn pause_container(&self, name: &str) -> Result<()>;
+
+    async fn unpause_container(&self, name: &str) -> Result<()>;
+
+    ///
+    /// get `path` in a container as a tar, with `path` itself at the top of the tar. the tar is streamed
+    /// in chunks, so that a large data volume is never held in memory
+    ///
+    fn download_archive(&self, container: &str, path: &str) -> BoxStream<'static, Result<Bytes>>;
+
+    ///
+    /// extract a tar into the directory `path` in a container. the container does not need to be running
+    ///
+    async fn upload_archive(
+        &self,
+        container: &str,
+        path: &str,
+        tar: BoxStream<'static, Result<Bytes>>,
+    ) -> Result<()>;
+
+    ///
+    /// stream of events for ink containers. the stream ends if the connection to the backend is lost
+    ///
+    fn container_events(&self) -> BoxStream<'static, Result<ContainerEvent>>;
+}
+
+///
+/// backend that talks to the docker daemon over the default socket
+///
+pub struct DockerBackend {
+    docker: Docker,
+}
+
+impl DockerBackend {
+    pub fn connect() -> Result<Self> {
+        return Ok(DockerBackend {
+            docker: Docker::connect_with_socket_defaults()?,
+        });
+    }
+}
+
+#[async_trait]
+impl ContainerBackend for DockerBackend {
+    async fn list_containers(&self, image: &str) -> Result<Vec<ContainerSummary>> {
+        let container_filter: ListContainersOptions = ListContainersOptionsBuilder::new()
+            .filters(&HashMap::from([
+                ("ancestor", vec![image]),
+                ("label", vec!["ink_tag=true"]),
+            ]))
+            .build();
+
+        return Ok(self.docker.list_containers(Some(container_filter)).await?);
+    }
+
+    async fn image_exists(&self, image: &str) -> Result<bool> {
+        let image_filter: ListImagesOptions = ListImagesOptionsBuilder::new()
+            .filters(&HashMap::from([("reference", vec![image])]))
+            .build();
+
+        let images = self.docker.list_images(Some(image_filter)).await?;
+        if let Some(found) = images.first() {
+            tracing::debug!("found {image} image: {:?}", found);
+        }
+
+        // more than one image matches when the image has several tags
+        return Ok(!images.is_empty());
+    }
+
+    async fn create_container(&self, name: &str, body: ContainerCreateBody) -> Result<String> {
+        let options = CreateContainerOptionsBuilder::new().name(name).build();
+        let container = self.docker.create_container(Some(options), body).await?;
+
+        return Ok(container.id);
+    }
+
+    async fn start_container(&self, name: &str) -> Result<()> {
+        self.docker
+            .start_container(name, None::<StartContainerOptions>)
+            .await?;
+
+        return Ok(());
+    }
+
+    async fn inspect_container(&self, name: &str) -> Result<ContainerInspectResponse> {
+        return Ok(self
+            .docker
+            .inspect_container(name, None::<InspectContainerOptions>)
+            .await?);
+    }
+
+    async fn stop_container(&self, name: &str, grace: Duration) -> Result<()> {
+        let options = StopContainerOptionsBuilder::new()
+            .t(grace.as_secs().try_into().unwrap_or(i32::MAX))
+            .build();
+
+        self.docker.stop_container(name, Some(options)).await?;
+
+        return Ok(());
+    }
+
+    async fn remove_container(&self, name: &str) -> Result<()> {
+        self.docker
+            .remove_container(name, None::<RemoveContainerOptions>)
+            .await?;
+
+        return Ok(());
+    }
+
+    async fn create_volume(&self, name: &str, labels: HashMap<String, String>) -> Result<()> {
+        self.docker
+            .create_volume(VolumeCreateOptions {
+                name: Some(name.to_string()),
+                labels: Some(labels),
+                ..Default::default()
+            })
+            .await?;
+
+        return Ok(());
+    }
+
+    // bollard 0.19 still takes the old options type here, not the one from query_parameters
+    #[allow(deprecated)]
+    async fn remove_volume(&self, name: &str) -> Result<()> {
+        self.docker
+            .remove_volume(name, None::<bollard::volume::RemoveVolumeOptions>)
+            .await?;
+
+        return Ok(());
+    }
+
+    async fn pause_container(&self, name: &str) -> Result<()> {
+        self.docker.pause_container(name).await?;
+
+        return Ok(());
+    }
+
+    async fn unpause_container(&self, name: &str) -> Result<()> {
+        self.docker.unpause_container(name).await?;
+
+        return Ok(());
+    }
+
+    fn download_archive(&self, container: &str, path: &str) -> BoxStream<'static, Result<Bytes>> {
+        let options = DownloadFromContainerOptionsBuilder::new()
+            .path(path)
+            .build();
+
+        return self
+            .docker
+            .download_from_container(container, Some(options))
+            .map_err(anyhow::Error::from)
+            .boxed();
+    }
+
+    async fn upload_archive(
+        &self,
+        container: &str,
+        path: &str,
+        tar: BoxStream<'static, Result<Bytes>>,
+    ) -> Result<()> {
+        let options = UploadToContainerOptionsBuilder::new().path(path).build();
+        let body = bollard::body_try_stream(tar.map_err(std::io::Error::other));
+        self.docker
+            .upload_to_container(container, Some(options), body)
+            .await?;
+
+        return Ok(());
+    }
+
+    fn container_events(&self) -> BoxStream<'static, Result<ContainerEvent>> {
+        let options = EventsOptionsBuilder::new()
+            .filters(&HashMap::from([
+                ("type", vec!["container"]),
+                ("label", vec!["ink_tag=true"]),
+            ]))
+            .build();
+
+        return self
+            .docker
+            .events(Some(options))
+            .map(|event| {
+                let event = event?;
+                let name = event
+                    .actor
+                    .and_then(|actor| actor.attributes)
+                    .and_then(|mut attributes| attributes.remove("name"))
+                    .unwrap_or_default();
+
+                return Ok(ContainerEvent {
+                    name,
+                    action: event.action.unwrap_or_default(),
+                });
+            })
+            .boxed();
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    app_state::AppState,
+    db, docker,
+    events::{InstanceUpdate, UpdateKind},
+    instance::{ExpiryWarning, SquittalInstance},
+    instance_index, waitlist,
+};
+
+///
+/// smallest warning threshold each instance has been warned about, so each is only sent once
+///
+#[derive(Default)]
+struct Warned(HashMap<String, u64>);
+
+impl Warned {
+    ///
+    /// forget instances that are no longer running
+    ///
+    fn retain(&mut self, instances: &[SquittalInstance]) {
+        self.0.retain(|name, _| {
+            instances
+                .iter()
+                .any(|i| docker::instance_name(&i.name) == name)
+        });
+    }
+
+    ///
+    /// if the instance `name` should be warned about `warning` now, which it is once for each threshold
+    ///
+    fn check(&mut self, name: &str, warning: &ExpiryWarning) -> bool {
+        if self
+            .0
+            .get(name)
+            .is_some_and(|&t| t <= warning.threshold_secs)
+        {
+            return false;
+        }
+
+        self.0.insert(name.to_string(), warning.threshold_secs);
+        return true;
+    }
+
+    ///
+    /// forget the warnings sent for `name`, such as when it is extended past all the thresholds, so it is
+    /// warned again if it gets close
+    ///
+    fn forget(&mut self, name: &str) {
+        self.0.remove(name);
+    }
+}
+
+///
+/// remove an instance in a task of its own, so one that takes a while to stop does not hold up the
+/// others. the freed slot goes to whoever is waiting
+///
+fn spawn_removal(state: &AppState, name: String, reason: &'static str) -> JoinHandle<()> {
+    let state = state.clone();
+    return tokio::spawn(async move {
+        if let Err(e) = docker::remove_container(&state, &name, reason).await {
+            tracing::error!("failed to remove instance {}: {}", name, e);
+            return;
+        }
+
+        waitlist::provision_next(&state).await;
+    });
+}
+
+/// background thread that cleans up old and idle squittal instances, warning owners before they expire
+pub async fn instance_cleanup(state: AppState) {
+    let (config, db) = (&state.config, &state.db);
+
+    let mut warned = Warned::default();
+
+    // removals that are still going, so an instance that is slow to stop is not removed twice
+    let mut removing: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    loop {
+        // also resyncs the index in case an event from docker was missed
+        if let Err(e) = instance_index::refresh(&state).await {
+            tracing::error!("failed to perform cleanup: {}", e);
+            tokio::time::sleep(config.instances.cleanup_interval()).await;
+            continue;
+        }
+
+        let instances = state.index.running();
+        warned.retain(&instances);
+        removing.retain(|_, removal| !removal.is_finished());
+
+        let now: SystemTime = SystemTime::now();
+        for instance in instances {
+            let diff: Duration = now.duration_since(instance.created_on).unwrap_or_default();
+            let expires_at = instance.expires_at;
+
+            let name: String = if instance.name.starts_with("/") {
+                instance.name[1..].to_string()
+            } else {
+                instance.name.clone()
+            };
+
+            let key = docker::instance_name(&name).to_string();
+            if removing.contains_key(&key) {
+                continue;
+            }
+
+            if now >= expires_at {
+                tracing::info!("killing {}, diff={:?}", name, diff);
+                if let Err(e) = db.record_event(
+                    Some(&key),
+                    None,
+                    db::InstanceEventKind::Expired,
+                    Some(&format!(
+                        "age {}s, expired at {}",
+                        diff.as_secs(),
+                        db::unix_time(expires_at)
+                    )),
+                ) {
+                    tracing::error!("failed to record expiry of {}: {}", name, e);
+                }
+
+                removing.insert(key, spawn_removal(&state, name, "expired"));
+                continue;
+            }
+
+            if let Some(idle_timeout) = config.instances.idle_timeout()
+                && let Some(idle_since) = state.activity.idle_since(&key, instance.created_on)
+            {
+                let idle: Duration = now.duration_since(idle_since).unwrap_or_default();
+                if idle >= idle_timeout {
+                    tracing::info!("killing {}, idle for {:?}", name, idle);
+                    if let Err(e) = db.record_event(
+                        Some(&key),
+                        None,
+                        db::InstanceEventKind::Idle,
+                        Some(&format!("idle for {}s", idle.as_secs())),
+                    ) {
+                        tracing::error!("failed to record idle removal of {}: {}", name, e);
+                    }
+
+                    removing.insert(key, spawn_removal(&state, name, "idle"));
+                    continue;
+                }
+            }
+
+            let Some(warning) =
+                instance.expiry_warning(&config.instances.warning_thresholds_secs, now)
+            else {
+                // extended past all the thresholds, so warn again if it gets close
+                warned.forget(&key);
+                continue;
+            };
+            if !warned.check(&key, &warning) {
+                continue;
+            }
+
+            state.events.publish(
+                InstanceUpdate::new(UpdateKind::Expiring, &key, &instance.created_by)
+                    .with_expires_at(instance.expires_at),
+            );
+            let notifier = state.notifier.clone();
+            tokio::spawn(async move { notifier.expiring(instance, warning).await });
+        }
+
+        // give any freed slots to whoever is waiting
+        if !state.waitlist.is_empty() {
+            let provision_state = state.clone();
+            tokio::spawn(async move { waitlist::provision_next(&provision_state).await });
+        }
+
+        tokio::time::sleep(config.instances.cleanup_interval()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use http::StatusCode;
+
+    use crate::{config::Config, test_util};
+
+    use super::*;
+
+    fn warning(threshold_secs: u64) -> ExpiryWarning {
+        return ExpiryWarning {
+            threshold_secs,
+            expires_in_secs: threshold_secs - 1,
+        };
+    }
+
+    ///
+    /// run the cleanup until `done` is true of the containers in the backend
+    ///
+    async fn cleanup_until(
+        state: &AppState,
+        done: impl Fn(&[bollard::secret::ContainerSummary]) -> bool,
+    ) {
+        let cleanup = tokio::spawn(instance_cleanup(state.clone()));
+
+        let wait = async {
+            loop {
+                let containers = state
+                    .backend
+                    .list_containers(&state.config.docker.image)
+                    .await
+                    .unwrap();
+                if done(&containers) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        let result = tokio::time::timeout(Duration::from_secs(10), wait).await;
+        cleanup.abort();
+
+        result.expect("cleanup did not finish in time");
+    }
+
+    #[tokio::test]
+    async fn removes_expired_instances() {
+        let state = test_util::state(Config::default()).await;
+        let expired = test_util::running_instance(&state, "1").await;
+        let current = test_util::running_instance(&state, "2").await;
+
+        // extensions are kept in the DB, so this is also how an instance is made to expire early
+        state
+            .db
+            .set_expires_at(&expired, SystemTime::now() - Duration::from_secs(1), "1")
+            .unwrap();
+
+        cleanup_until(&state, |containers| containers.len() == 1).await;
+
+        assert!(state.index.get(&expired).is_none());
+        assert!(state.index.get(&current).is_some());
+    }
+
+    #[test]
+    fn warned_once_per_threshold() {
+        let mut warned = Warned::default();
+
+        assert!(warned.check("a", &warning(600)));
+        assert!(!warned.check("a", &warning(600)));
+        assert!(warned.check("b", &warning(600)));
+
+        // crossing a smaller threshold is warned about, but not going back to a bigger one
+        assert!(warned.check("a", &warning(300)));
+        assert!(!warned.check("a", &warning(300)));
+        assert!(!warned.check("a", &warning(600)));
+
+        warned.forget("a");
+        assert!(warned.check("a", &warning(600)));
+    }
+
+    #[tokio::test]
+    async fn warned_forgotten_once_removed() {
+        let state = test_util::state(Config::default()).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        let mut warned = Warned::default();
+        assert!(warned.check(&name, &warning(600)));
+        assert!(warned.check("removed", &warning(600)));
+
+        warned.retain(&state.index.running());
+        assert!(!warned.check(&name, &warning(600)));
+        assert!(warned.check("removed", &warning(600)));
+    }
+
+    #[tokio::test]
+    async fn warns_before_expiry() {
+        let (url, mut received) = test_util::webhook(StatusCode::OK).await;
+        let mut config = Config::default();
+        config.instances.cleanup_interval_secs = 1;
+        config.instances.warning_thresholds_secs = vec![600, 300];
+        config.notifications.webhook_url = Some(url);
+        let state = test_util::state(config).await;
+        let name = test_util::running_instance(&state, "1").await;
+        let mut events = state.events.subscribe();
+
+        state
+            .db
+            .set_expires_at(&name, SystemTime::now() + Duration::from_secs(500), "1")
+            .unwrap();
+        let cleanup = tokio::spawn(instance_cleanup(state.clone()));
+
+        let body = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body["instance"], name);
+        assert_eq!(body["threshold_secs"], 600);
+
+        let update = events.recv().await.unwrap();
+        assert_eq!(update.kind, UpdateKind::Expiring);
+        assert_eq!(update.instance, name);
+
+        // the cleanup runs a few more times without warning again, until the next threshold is crossed
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(received.try_recv().is_err());
+
+        state
+            .db
+            .set_expires_at(&name, SystemTime::now() + Duration::from_secs(200), "1")
+            .unwrap();
+        let body = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body["threshold_secs"], 300);
+        cleanup.abort();
+    }
+
+    #[tokio::test]
+    async fn removes_every_expired_instance() {
+        let state = test_util::state(Config::default()).await;
+        let first = test_util::running_instance(&state, "1").await;
+        let second = test_util::running_instance(&state, "2").await;
+
+        for name in [&first, &second] {
+            state
+                .db
+                .set_expires_at(name, SystemTime::now() - Duration::from_secs(1), "1")
+                .unwrap();
+        }
+
+        cleanup_until(&state, |containers| containers.is_empty()).await;
+
+        assert!(state.index.get(&first).is_none());
+        assert!(state.index.get(&second).is_none());
+    }
+
+    #[tokio::test]
+    async fn removes_idle_instances() {
+        let mut config = Config::default();
+        config.instances.cleanup_interval_secs = 1;
+        config.instances.idle_timeout_secs = 1;
+        let state = test_util::state(config).await;
+        let idle = test_util::running_instance(&state, "1").await;
+        let connected = test_util::running_instance(&state, "2").await;
+        let _guard = state.activity.connect(&connected);
+
+        cleanup_until(&state, |containers| containers.len() == 1).await;
+
+        // an open websocket keeps an instance in use, however long ago it was opened
+        assert!(state.index.get(&idle).is_none());
+        assert!(state.index.get(&connected).is_some());
+    }
+
+    #[tokio::test]
+    async fn keeps_used_instances() {
+        let mut config = Config::default();
+        config.instances.cleanup_interval_secs = 1;
+        config.instances.idle_timeout_secs = 2;
+        let state = test_util::state(config).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        let cleanup = tokio::spawn(instance_cleanup(state.clone()));
+        for _ in 0..8 {
+            state.activity.touch(&name);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        cleanup.abort();
+
+        assert!(state.index.get(&name).is_some());
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_off() {
+        let mut config = Config::default();
+        config.instances.cleanup_interval_secs = 1;
+        config.instances.idle_timeout_secs = 0;
+        let state = test_util::state(config).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        let cleanup = tokio::spawn(instance_cleanup(state.clone()));
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        cleanup.abort();
+
+        assert!(state.index.get(&name).is_some());
+    }
+}
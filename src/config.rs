@@ -0,0 +1,1250 @@
+use std::{collections::HashMap, env, net::SocketAddr, path::Path, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::instance::InstanceLimits;
+
+///
+/// ink configuration, loaded from a TOML file (ink.toml by default, or the path in INK_CONFIG).
+/// any value can be overridden with an env var named after its path, using __ between tables:
+///     INK_BIND_ADDRESS="127.0.0.1:8001"
+///     INK_INSTANCES__MAX_INSTANCES=10
+///     INK_PROXY__BLOCKED_PATHS='["/DbAdmin"]'
+/// the value is read as the type of the setting, and INK_ env vars that are not a setting are ignored
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// address ink listens on
+    pub bind_address: String,
+
+    /// path to the sqlite database for sessions and instance tracking
+    pub database: String,
+
+    /// URL users reach ink at, used to build the oauth callback URLs (such as {public_url}/auth/github/callback)
+    pub public_url: String,
+
+    pub instances: InstanceConfig,
+    pub docker: DockerConfig,
+    pub limits: LimitsConfig,
+    pub mssql: MssqlConfig,
+    pub snapshots: SnapshotConfig,
+    pub exports: ExportConfig,
+    pub proxy: ProxyConfig,
+    pub notifications: NotificationConfig,
+    pub access: AccessConfig,
+    pub discord: DiscordConfig,
+    pub github: GithubConfig,
+
+    /// OpenID Connect providers, keyed by the name used in the login URL (such as /auth/{name})
+    pub oidc: HashMap<String, OidcConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstanceConfig {
+    /// how many instances can be running at once
+    pub max_instances: usize,
+
+    /// how long an instance lives before it is removed, in seconds
+    pub ttl_secs: u64,
+
+    /// how much time an owner adds to their instance each time they extend it, in seconds
+    pub extend_secs: u64,
+
+    /// the longest an instance can live (from when it was created) with extensions, in seconds
+    pub max_lifetime_secs: u64,
+
+    /// the most time an admin can add to an instance at once, in seconds. admins are not held to the max
+    /// lifetime, so this stops an instance from being kept forever by mistake
+    pub admin_max_extend_secs: u64,
+
+    /// how often the cleanup loop checks for expired instances, in seconds
+    pub cleanup_interval_secs: u64,
+
+    /// how long before an instance expires to warn the owner, in seconds. each threshold
+    /// is shown in the API once it is crossed, and notified once
+    pub warning_thresholds_secs: Vec<u64>,
+
+    /// how long an instance can go without any requests or open websockets before it is removed,
+    /// in seconds. 0 turns this off, leaving only the TTL
+    pub idle_timeout_secs: u64,
+
+    /// how long squittal gets to shut down cleanly when stopped before it is killed, in seconds
+    pub stop_grace_secs: u64,
+
+    /// path on squittal that is polled once the container is started. the instance is not routed to
+    /// until this answers without a server error
+    pub ready_path: String,
+
+    /// how long an instance has to become ready after it is started, in seconds. instances that are
+    /// not ready by then are marked failed and removed. 0 turns the check off, so instances are ready
+    /// as soon as they have a port
+    pub ready_timeout_secs: u64,
+
+    /// how often a starting instance is checked, in seconds
+    pub ready_interval_secs: u64,
+
+    /// word lists used to generate instance names, one word per line
+    pub first_word_list: String,
+    pub second_word_list: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DockerConfig {
+    /// what runs the containers
+    pub backend: BackendKind,
+
+    /// image instances are created from
+    pub image: String,
+
+    /// docker network instances are attached to
+    pub network: String,
+
+    /// port squittal listens on inside the container
+    pub container_port: u16,
+
+    /// how ink reaches squittal in each container
+    pub route: RouteMode,
+
+    /// command docker runs to check if squittal is healthy, set as the healthcheck of each container,
+    /// such as ["CMD-SHELL", "curl -f http://localhost:8080/ || exit 1"]. if empty, the healthcheck
+    /// from the image is used, if it has one. instances with a healthcheck must pass it to be ready
+    pub healthcheck: Vec<String>,
+
+    /// how often docker runs the healthcheck, in seconds
+    pub healthcheck_interval_secs: u64,
+
+    /// directory in the container that is kept in a docker volume of its own, which owners can snapshot
+    /// and start new instances from. if empty, instances have no volume and cannot be snapshotted
+    pub data_path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteMode {
+    /// publish the squittal port of each container on a random port on 127.0.0.1, and reach it there.
+    /// anyone who can run commands on the host can still reach the instances without going through ink
+    Port,
+
+    /// publish nothing, and reach each container by its IP on the docker network. ink must be able to
+    /// reach that network, such as by running on the docker host
+    Ip,
+
+    /// publish nothing, and reach each container by its name. ink must be attached to the docker network,
+    /// such as by running in a container on it
+    Name,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// most memory each container can use, in MiB. 0 is no limit
+    pub memory_mb: u64,
+
+    /// how many CPUs each container can use, such as 1.5. 0 is no limit
+    pub cpus: f64,
+
+    /// most processes and threads each container can have. 0 is no limit
+    pub pids: i64,
+
+    /// make the root filesystem of each container read-only. squittal can still write to the data volume
+    /// and the tmpfs mounts
+    pub read_only: bool,
+
+    /// directories mounted as tmpfs in each container, with mount options after a colon if needed,
+    /// such as "/tmp:size=64m"
+    pub tmpfs: Vec<String>,
+
+    /// linux capabilities dropped from each container, such as ["ALL"]
+    pub cap_drop: Vec<String>,
+
+    /// stop processes in the container from gaining privileges, such as through setuid binaries
+    pub no_new_privileges: bool,
+
+    /// what docker does when squittal exits
+    pub restart_policy: RestartPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// never restart the container
+    No,
+
+    /// restart the container when squittal exits with an error
+    OnFailure,
+
+    /// restart the container unless ink stopped it
+    UnlessStopped,
+
+    /// always restart the container
+    Always,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// the docker daemon on the default socket
+    Docker,
+
+    /// containers kept in memory, for working on ink without docker. nothing is actually run
+    Fake,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MssqlConfig {
+    /// where instances keep their data
+    pub backend: MssqlBackendKind,
+
+    /// host and port squittal reaches the MSSQL server at, such as the name of the mssql container
+    /// on the docker network
+    pub host: String,
+    pub port: u16,
+
+    /// host and port ink reaches the MSSQL server at, to create and drop databases
+    pub admin_host: String,
+    pub admin_port: u16,
+
+    /// login ink uses to create and drop databases and logins, such as sa
+    pub admin_user: String,
+    pub admin_password: String,
+
+    /// accept the certificate of the server without checking it. the mssql image uses a self-signed one
+    pub trust_server_certificate: bool,
+
+    /// env var squittal reads the connection string of the database from. the connection string itself
+    /// is not put in an env var, which anyone who can inspect the container can see. instead it is
+    /// mounted at `connection_file`, and {connection_env}_FILE is set to that path
+    pub connection_env: String,
+
+    /// path in the container the connection string is mounted at
+    pub connection_file: String,
+
+    /// directory the connection string of each instance is written to, to be mounted into its container.
+    /// docker has to see it at the same path, so ink has to run on the docker host
+    pub secrets_dir: String,
+
+    /// what happens to the database of an instance when the instance is removed
+    pub on_remove: RemoveAction,
+
+    /// directory on the MSSQL server that archived databases are backed up to
+    pub archive_dir: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MssqlBackendKind {
+    /// every instance uses the database built into the image, which is the same one for all of them
+    Shared,
+
+    /// each instance gets its own database and login on an MSSQL server
+    Server,
+
+    /// databases kept in memory, for working on ink without an MSSQL server. nothing is actually created
+    Fake,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoveAction {
+    /// drop the database
+    Drop,
+
+    /// back up the database to `archive_dir`, then drop it
+    Archive,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// directory ink keeps snapshots in, as tarballs of the data volume of an instance
+    pub dir: String,
+
+    /// how many snapshots each user can keep
+    pub max_per_user: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    /// directory exports are kept in, with a directory for each owner
+    pub dir: String,
+
+    /// paths on squittal that are fetched and saved before an instance is removed, such as an endpoint
+    /// that exports match results
+    pub endpoints: Vec<String>,
+
+    /// files or directories in the container that are copied out and saved (as a tar) before an
+    /// instance is removed
+    pub files: Vec<String>,
+
+    /// how long each endpoint or file gets to be exported, in seconds
+    pub timeout_secs: u64,
+
+    /// how many exports each user can keep. the oldest are deleted to make room for new ones
+    pub max_per_user: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// paths within an instance that are not proxied
+    pub blocked_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// URL that expiry warnings are POSTed to as JSON. if not set, warnings are only logged
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// IDs of users who are always admins (github:1234 and such for providers other than discord).
+    /// admins can also be added from the admin API, but these cannot be changed or banned from there
+    pub admins: Vec<String>,
+
+    /// longest an API token can last, in seconds. when set, tokens made without an expiry last this long.
+    /// 0 lets tokens last until revoked
+    pub token_max_lifetime_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordConfig {
+    /// oauth client of the discord app. if not set, the DISCORD_CLIENT_ID and DISCORD_SECRET env vars
+    /// are used, and discord login is turned off if neither are set
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+
+    /// base URL of the discord API, used for oauth and getting the user. only changed to point
+    /// ink at a stand-in for discord when testing
+    pub api_url: String,
+
+    /// IDs of the discord servers (guilds) a user must be in one of to log in. if empty, anyone can log in
+    pub allowed_guilds: Vec<String>,
+
+    /// IDs of roles in the allowed guilds. if set, a user must also have one of these roles
+    /// in one of the allowed guilds to log in
+    pub allowed_roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GithubConfig {
+    /// oauth client of the github app. github login is turned off if not set
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+
+    /// base URL of the github API, used to get the user
+    pub api_url: String,
+
+    /// base URL of github oauth, used to authorize and get tokens
+    pub oauth_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    /// name shown on the login button
+    pub display_name: String,
+
+    /// issuer of the provider. the endpoints are found from {issuer_url}/.well-known/openid-configuration
+    pub issuer_url: String,
+
+    pub client_id: String,
+    pub client_secret: String,
+
+    /// scopes requested when logging in
+    pub scopes: Vec<String>,
+
+    /// claim from the userinfo endpoint that is the ID of the user. the ID in ink is {name}:{claim}
+    pub id_claim: String,
+
+    /// claim from the userinfo endpoint that is shown as the username. the ID is used if missing
+    pub username_claim: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        return Config {
+            bind_address: "0.0.0.0:8000".to_string(),
+            database: "ink.db".to_string(),
+            public_url: "http://localhost:8000".to_string(),
+            instances: InstanceConfig::default(),
+            docker: DockerConfig::default(),
+            limits: LimitsConfig::default(),
+            mssql: MssqlConfig::default(),
+            snapshots: SnapshotConfig::default(),
+            exports: ExportConfig::default(),
+            proxy: ProxyConfig::default(),
+            notifications: NotificationConfig::default(),
+            access: AccessConfig::default(),
+            discord: DiscordConfig::default(),
+            github: GithubConfig::default(),
+            oidc: HashMap::new(),
+        };
+    }
+}
+
+impl Default for InstanceConfig {
+    fn default() -> Self {
+        return InstanceConfig {
+            max_instances: 5,
+            ttl_secs: 60 * 60 * 4,
+            extend_secs: 60 * 60,
+            max_lifetime_secs: 60 * 60 * 12,
+            admin_max_extend_secs: 60 * 60 * 24 * 7,
+            cleanup_interval_secs: 5,
+            warning_thresholds_secs: vec![60 * 30, 60 * 10],
+            idle_timeout_secs: 60 * 60,
+            stop_grace_secs: 30,
+            ready_path: "/".to_string(),
+            ready_timeout_secs: 60 * 5,
+            ready_interval_secs: 2,
+            first_word_list: "first_word_list.txt".to_string(),
+            second_word_list: "second_word_list.txt".to_string(),
+        };
+    }
+}
+
+impl Default for DockerConfig {
+    fn default() -> Self {
+        return DockerConfig {
+            backend: BackendKind::Docker,
+            image: "squittal".to_string(),
+            network: "ink".to_string(),
+            container_port: 8080,
+            route: RouteMode::Port,
+            healthcheck: vec![],
+            healthcheck_interval_secs: 5,
+            data_path: String::new(),
+        };
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        return LimitsConfig {
+            memory_mb: 2048,
+            cpus: 2.0,
+            pids: 1024,
+            read_only: false,
+            tmpfs: vec!["/tmp".to_string()],
+            cap_drop: vec!["ALL".to_string()],
+            no_new_privileges: true,
+            restart_policy: RestartPolicy::OnFailure,
+        };
+    }
+}
+
+impl Default for MssqlConfig {
+    fn default() -> Self {
+        return MssqlConfig {
+            backend: MssqlBackendKind::Shared,
+            host: "mssql".to_string(),
+            port: 1433,
+            admin_host: "127.0.0.1".to_string(),
+            admin_port: 1433,
+            admin_user: "sa".to_string(),
+            admin_password: String::new(),
+            trust_server_certificate: true,
+            connection_env: "ConnectionStrings__PlanetmansDbContext".to_string(),
+            connection_file: "/run/secrets/connection_string".to_string(),
+            secrets_dir: "secrets".to_string(),
+            on_remove: RemoveAction::Drop,
+            archive_dir: "/var/opt/mssql/backup".to_string(),
+        };
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        return SnapshotConfig {
+            dir: "snapshots".to_string(),
+            max_per_user: 5,
+        };
+    }
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        return ExportConfig {
+            dir: "exports".to_string(),
+            endpoints: vec![],
+            files: vec![],
+            timeout_secs: 30,
+            max_per_user: 50,
+        };
+    }
+}
+
+impl Default for DiscordConfig {
+    fn default() -> Self {
+        return DiscordConfig {
+            client_id: None,
+            client_secret: None,
+            api_url: "https://discord.com/api".to_string(),
+            allowed_guilds: vec![],
+            allowed_roles: vec![],
+        };
+    }
+}
+
+impl Default for GithubConfig {
+    fn default() -> Self {
+        return GithubConfig {
+            client_id: None,
+            client_secret: None,
+            api_url: "https://api.github.com".to_string(),
+            oauth_url: "https://github.com/login/oauth".to_string(),
+        };
+    }
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        return OidcConfig {
+            display_name: String::new(),
+            issuer_url: String::new(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            scopes: vec!["openid".to_string(), "profile".to_string()],
+            id_claim: "sub".to_string(),
+            username_claim: "preferred_username".to_string(),
+        };
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        return ProxyConfig {
+            blocked_paths: vec![
+                "/DbAdmin".to_string(),
+                "/rulesets".to_string(),
+                "/TeamBuilder".to_string(),
+            ],
+        };
+    }
+}
+
+impl ExportConfig {
+    ///
+    /// if anything is exported from instances before they are removed
+    ///
+    pub fn enabled(&self) -> bool {
+        return !self.endpoints.is_empty() || !self.files.is_empty();
+    }
+
+    pub fn timeout(&self) -> Duration {
+        return Duration::from_secs(self.timeout_secs);
+    }
+}
+
+impl InstanceConfig {
+    pub fn ttl(&self) -> Duration {
+        return Duration::from_secs(self.ttl_secs);
+    }
+
+    pub fn extend(&self) -> Duration {
+        return Duration::from_secs(self.extend_secs);
+    }
+
+    pub fn max_lifetime(&self) -> Duration {
+        return Duration::from_secs(self.max_lifetime_secs);
+    }
+
+    pub fn cleanup_interval(&self) -> Duration {
+        return Duration::from_secs(self.cleanup_interval_secs);
+    }
+
+    ///
+    /// how long an instance can be idle before it is removed, or None if idle instances are kept
+    ///
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        return match self.idle_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+    }
+
+    pub fn stop_grace(&self) -> Duration {
+        return Duration::from_secs(self.stop_grace_secs);
+    }
+
+    ///
+    /// how long an instance has to become ready, or None if instances are not checked
+    ///
+    pub fn ready_timeout(&self) -> Option<Duration> {
+        return match self.ready_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+    }
+
+    pub fn ready_interval(&self) -> Duration {
+        return Duration::from_secs(self.ready_interval_secs);
+    }
+}
+
+impl LimitsConfig {
+    ///
+    /// the limits an instance created now has, as shown in the API
+    ///
+    pub fn instance_limits(&self) -> InstanceLimits {
+        return InstanceLimits {
+            memory_mb: (self.memory_mb > 0).then_some(self.memory_mb),
+            cpus: (self.cpus > 0.0).then_some(self.cpus),
+            pids: (self.pids > 0).then_some(self.pids),
+            read_only: self.read_only,
+        };
+    }
+
+    ///
+    /// the tmpfs mounts as docker takes them, from each path to its mount options
+    ///
+    pub fn tmpfs_mounts(&self) -> HashMap<String, String> {
+        return self
+            .tmpfs
+            .iter()
+            .map(|mount| match mount.split_once(':') {
+                Some((path, options)) => (path.to_string(), options.to_string()),
+                None => (mount.clone(), String::new()),
+            })
+            .collect();
+    }
+}
+
+impl DockerConfig {
+    /// the port key docker uses for the squittal port, such as "8080/tcp"
+    pub fn port_key(&self) -> String {
+        return format!("{}/tcp", self.container_port);
+    }
+}
+
+/// prefix of env vars that override config values
+static ENV_PREFIX: &str = "INK_";
+
+/// env var that holds the path to the config file
+static CONFIG_PATH_ENV: &str = "INK_CONFIG";
+
+impl Config {
+    ///
+    /// load the config file, apply env var overrides, and validate the result. a missing config
+    /// file is fine (the defaults are used), unless INK_CONFIG points at it
+    ///
+    pub fn load() -> Result<Config> {
+        return Config::load_from(env::vars());
+    }
+
+    ///
+    /// load the config as [`Config::load`] does, with `vars` in place of the env vars
+    ///
+    fn load_from(vars: impl IntoIterator<Item = (String, String)>) -> Result<Config> {
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+        let path = vars
+            .iter()
+            .find(|(key, _)| key == CONFIG_PATH_ENV)
+            .map(|(_, value)| value.as_str());
+
+        let mut table: toml::Table = match path {
+            Some(path) => read_table(path)?,
+            None if Path::new("ink.toml").exists() => read_table("ink.toml")?,
+            None => toml::Table::new(),
+        };
+
+        // checked before the overrides, so a mistake in the file is not blamed on one of them
+        table
+            .clone()
+            .try_into::<Config>()
+            .context("failed to parse config")?;
+
+        for (key, value) in &vars {
+            if key == CONFIG_PATH_ENV {
+                continue;
+            }
+
+            if let Some(name) = key.strip_prefix(ENV_PREFIX) {
+                let applied = apply_override(&mut table, name, value)
+                    .with_context(|| format!("invalid config override {key}"))?;
+                if !applied {
+                    tracing::warn!("ignoring env var {}, which is not a config setting", key);
+                }
+            }
+        }
+
+        let config: Config = table.try_into().context("failed to parse config")?;
+        config.validate()?;
+
+        return Ok(config);
+    }
+
+    ///
+    /// make sure the values in the config make sense before ink starts using them
+    ///
+    pub fn validate(&self) -> Result<()> {
+        self.bind_address.parse::<SocketAddr>().with_context(|| {
+            format!(
+                "bind_address '{}' is not a valid address",
+                self.bind_address
+            )
+        })?;
+
+        if self.database.is_empty() {
+            bail!("database cannot be empty");
+        }
+
+        url::Url::parse(&self.public_url)
+            .with_context(|| format!("public_url '{}' is not a valid URL", self.public_url))?;
+        if self.public_url.ends_with('/') {
+            bail!("public_url cannot end with /");
+        }
+
+        if self.instances.max_instances == 0 {
+            bail!("instances.max_instances must be at least 1");
+        }
+        if self.instances.ttl_secs == 0 {
+            bail!("instances.ttl_secs must be greater than 0");
+        }
+        if self.instances.extend_secs == 0 {
+            bail!("instances.extend_secs must be greater than 0");
+        }
+        if self.instances.admin_max_extend_secs < self.instances.extend_secs {
+            bail!("instances.admin_max_extend_secs cannot be less than instances.extend_secs");
+        }
+        if self.instances.max_lifetime_secs < self.instances.ttl_secs {
+            bail!("instances.max_lifetime_secs cannot be less than instances.ttl_secs");
+        }
+        if self.instances.cleanup_interval_secs == 0 {
+            bail!("instances.cleanup_interval_secs must be greater than 0");
+        }
+        if self.instances.warning_thresholds_secs.contains(&0) {
+            bail!("instances.warning_thresholds_secs cannot contain 0");
+        }
+        if self.instances.stop_grace_secs > i32::MAX as u64 {
+            bail!("instances.stop_grace_secs is too large");
+        }
+        if !self.instances.ready_path.starts_with('/') {
+            bail!("instances.ready_path must start with /");
+        }
+        if self.instances.ready_interval_secs == 0 {
+            bail!("instances.ready_interval_secs must be greater than 0");
+        }
+        // a name is known before the container starts, so only the readiness check finds out if
+        // the container can be reached by it
+        if self.docker.route == RouteMode::Name && self.instances.ready_timeout_secs == 0 {
+            bail!("instances.ready_timeout_secs cannot be 0 when docker.route is name");
+        }
+
+        for path in [
+            &self.instances.first_word_list,
+            &self.instances.second_word_list,
+        ] {
+            let words = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read word list {path}"))?;
+            if words.lines().all(|l| l.trim().is_empty()) {
+                bail!("word list {path} is empty");
+            }
+        }
+
+        if self.docker.image.is_empty() {
+            bail!("docker.image cannot be empty");
+        }
+        if self.docker.network.is_empty() {
+            bail!("docker.network cannot be empty");
+        }
+        if self.docker.container_port == 0 {
+            bail!("docker.container_port cannot be 0");
+        }
+        if let Some(kind) = self.docker.healthcheck.first()
+            && !["CMD", "CMD-SHELL", "NONE"].contains(&kind.as_str())
+        {
+            bail!("docker.healthcheck must start with CMD, CMD-SHELL or NONE, not '{kind}'");
+        }
+        if self.docker.healthcheck_interval_secs == 0 {
+            bail!("docker.healthcheck_interval_secs must be greater than 0");
+        }
+        if !self.docker.data_path.is_empty()
+            && (!self.docker.data_path.starts_with('/') || self.docker.data_path == "/")
+        {
+            bail!("docker.data_path must be an absolute path, and cannot be /");
+        }
+        if !self.limits.cpus.is_finite() || self.limits.cpus < 0.0 {
+            bail!("limits.cpus must be 0 or more");
+        }
+        if self.limits.pids < 0 {
+            bail!("limits.pids must be 0 or more");
+        }
+        if let Some(mount) = self
+            .limits
+            .tmpfs_mounts()
+            .into_keys()
+            .find(|path| !path.starts_with('/') || path == "/")
+        {
+            bail!("limits.tmpfs entry '{mount}' must be an absolute path, and cannot be /");
+        }
+        if self.limits.cap_drop.iter().any(|c| c.is_empty()) {
+            bail!("limits.cap_drop cannot have an empty entry");
+        }
+        if !self.docker.data_path.is_empty() && self.snapshots.dir.is_empty() {
+            bail!("snapshots.dir cannot be empty when docker.data_path is set");
+        }
+
+        if self.mssql.backend == MssqlBackendKind::Server {
+            if self.mssql.admin_password.is_empty() {
+                bail!("mssql.admin_password must be set when mssql.backend is server");
+            }
+            if self.mssql.host.is_empty() || self.mssql.admin_host.is_empty() {
+                bail!("mssql.host and mssql.admin_host cannot be empty");
+            }
+        }
+        if self.mssql.connection_env.is_empty() {
+            bail!("mssql.connection_env cannot be empty");
+        }
+        if !self.mssql.connection_file.starts_with('/') {
+            bail!("mssql.connection_file must be an absolute path");
+        }
+        if self.mssql.secrets_dir.is_empty() {
+            bail!("mssql.secrets_dir cannot be empty");
+        }
+        if self.mssql.on_remove == RemoveAction::Archive && self.mssql.archive_dir.is_empty() {
+            bail!("mssql.archive_dir must be set when mssql.on_remove is archive");
+        }
+
+        if let Some(path) = self.exports.endpoints.iter().find(|p| !p.starts_with('/')) {
+            bail!("exports.endpoints entry '{path}' must start with /");
+        }
+        if let Some(path) = self.exports.files.iter().find(|p| !p.starts_with('/')) {
+            bail!("exports.files entry '{path}' must be an absolute path");
+        }
+        if self.exports.enabled() {
+            if self.exports.dir.is_empty() {
+                bail!("exports.dir cannot be empty when anything is exported");
+            }
+            if self.exports.timeout_secs == 0 {
+                bail!("exports.timeout_secs must be greater than 0");
+            }
+            if self.exports.max_per_user == 0 {
+                bail!("exports.max_per_user must be at least 1");
+            }
+        }
+
+        if let Some(path) = self
+            .proxy
+            .blocked_paths
+            .iter()
+            .find(|p| !p.starts_with('/'))
+        {
+            bail!("proxy.blocked_paths entry '{path}' must start with /");
+        }
+
+        if let Some(url) = &self.notifications.webhook_url {
+            url::Url::parse(url)
+                .with_context(|| format!("notifications.webhook_url '{url}' is not a valid URL"))?;
+        }
+
+        if self.access.admins.iter().any(|a| a.trim().is_empty()) {
+            bail!("access.admins cannot contain an empty ID");
+        }
+
+        url::Url::parse(&self.discord.api_url).with_context(|| {
+            format!(
+                "discord.api_url '{}' is not a valid URL",
+                self.discord.api_url
+            )
+        })?;
+        if self.discord.api_url.ends_with('/') {
+            bail!("discord.api_url cannot end with /");
+        }
+        if !self.discord.allowed_roles.is_empty() && self.discord.allowed_guilds.is_empty() {
+            bail!("discord.allowed_roles needs discord.allowed_guilds to be set");
+        }
+
+        for (name, url) in [
+            ("github.api_url", &self.github.api_url),
+            ("github.oauth_url", &self.github.oauth_url),
+        ] {
+            url::Url::parse(url).with_context(|| format!("{name} '{url}' is not a valid URL"))?;
+            if url.ends_with('/') {
+                bail!("{name} cannot end with /");
+            }
+        }
+        if self.github.client_id.is_some() != self.github.client_secret.is_some() {
+            bail!("github.client_id and github.client_secret must be set together");
+        }
+
+        for (name, oidc) in &self.oidc {
+            // the name is used in the login URL, and cannot hide another provider
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            {
+                bail!("oidc provider name '{name}' can only use a-z, 0-9 and -");
+            }
+            if ["discord", "github", "callback"].contains(&name.as_str()) {
+                bail!("oidc provider name '{name}' is reserved");
+            }
+
+            url::Url::parse(&oidc.issuer_url).with_context(|| {
+                format!(
+                    "oidc.{name}.issuer_url '{}' is not a valid URL",
+                    oidc.issuer_url
+                )
+            })?;
+            if oidc.client_id.is_empty() || oidc.client_secret.is_empty() {
+                bail!("oidc.{name}.client_id and oidc.{name}.client_secret must be set");
+            }
+            if oidc.id_claim.is_empty() {
+                bail!("oidc.{name}.id_claim cannot be empty");
+            }
+            // without it there is no ID token to check the login against
+            if !oidc.scopes.iter().any(|s| s == "openid") {
+                bail!("oidc.{name}.scopes must include openid");
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+fn read_table(path: &str) -> Result<toml::Table> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read config {path}"))?;
+
+    return toml::from_str(&contents).with_context(|| format!("failed to parse config {path}"));
+}
+
+///
+/// set the value at `name` (such as INSTANCES__MAX_INSTANCES) in the table, as the type of the setting
+/// it is for. the value is tried as TOML (so numbers, bools and arrays work), then as a string, then as
+/// an array of strings, so all digit IDs and passwords stay strings. returns false if there is no such
+/// setting
+///
+fn apply_override(table: &mut toml::Table, name: &str, value: &str) -> Result<bool> {
+    let path: Vec<String> = name.split("__").map(|p| p.to_lowercase()).collect();
+    if path.iter().any(|p| p.is_empty()) {
+        return Err(anyhow!("empty key in override path"));
+    }
+
+    let mut error: Option<toml::de::Error> = None;
+    for candidate in override_values(value) {
+        let mut updated = table.clone();
+        set_path(&mut updated, &path, candidate)?;
+
+        match updated.clone().try_into::<Config>() {
+            Ok(_) => {
+                *table = updated;
+                return Ok(true);
+            }
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+
+    // the string candidate is always tried, so there is an error
+    let error = error.unwrap();
+    if error.message().starts_with("unknown field") {
+        return Ok(false);
+    }
+
+    return Err(error.into());
+}
+
+///
+/// the values an override can be read as, in the order they are tried
+///
+fn override_values(value: &str) -> Vec<toml::Value> {
+    let parsed: Option<toml::Value> = toml::from_str::<toml::Table>(&format!("v = {value}"))
+        .ok()
+        .and_then(|mut t| t.remove("v"));
+
+    let mut values: Vec<toml::Value> = vec![];
+    let mut strings: Option<toml::Value> = None;
+    if let Some(parsed) = parsed {
+        // such as [123, 456] for a list of discord IDs
+        if let toml::Value::Array(items) = &parsed {
+            strings = Some(toml::Value::Array(
+                items
+                    .iter()
+                    .map(|item| match item {
+                        toml::Value::String(s) => toml::Value::String(s.clone()),
+                        other => toml::Value::String(other.to_string()),
+                    })
+                    .collect(),
+            ));
+        }
+        values.push(parsed);
+    }
+
+    values.push(toml::Value::String(value.to_string()));
+    values.extend(strings);
+
+    return values;
+}
+
+///
+/// set `value` at `path` in the table, creating the tables along the way
+///
+fn set_path(table: &mut toml::Table, path: &[String], value: toml::Value) -> Result<()> {
+    let (last, tables) = path.split_last().context("empty override path")?;
+
+    let mut current = table;
+    for key in tables {
+        current = current
+            .entry(key.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .with_context(|| format!("{key} is not a table"))?;
+    }
+
+    current.insert(last.clone(), value);
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// a config file with `contents`, removed when dropped
+    ///
+    struct ConfigFile(std::path::PathBuf);
+
+    impl ConfigFile {
+        fn new(contents: &str) -> Self {
+            let path =
+                env::temp_dir().join(format!("ink-config-test-{}.toml", rand::random::<u64>()));
+            std::fs::write(&path, contents).unwrap();
+            return ConfigFile(path);
+        }
+
+        fn var(&self) -> (String, String) {
+            return (
+                CONFIG_PATH_ENV.to_string(),
+                self.0.to_str().unwrap().to_string(),
+            );
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn load(contents: &str, vars: &[(&str, &str)]) -> Result<Config> {
+        let file = ConfigFile::new(contents);
+        let mut all = vec![file.var()];
+        all.extend(vars.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+
+        return Config::load_from(all);
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load("", &[]).unwrap();
+        assert_eq!(config.bind_address, Config::default().bind_address);
+        assert_eq!(
+            config.instances.max_instances,
+            InstanceConfig::default().max_instances
+        );
+    }
+
+    #[test]
+    fn file_values() {
+        let config = load(
+            "bind_address = \"127.0.0.1:9000\"\n[instances]\nmax_instances = 3\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.bind_address, "127.0.0.1:9000");
+        assert_eq!(config.instances.max_instances, 3);
+    }
+
+    #[test]
+    fn missing_file() {
+        let result = Config::load_from([(
+            CONFIG_PATH_ENV.to_string(),
+            "/nonexistent/ink.toml".to_string(),
+        )]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unknown_file_setting() {
+        assert!(load("[instances]\nmax_instance = 3\n", &[]).is_err());
+    }
+
+    #[test]
+    fn overrides_replace_file_values() {
+        let config = load(
+            "[instances]\nmax_instances = 3\nttl_secs = 100\n",
+            &[("INK_INSTANCES__MAX_INSTANCES", "7")],
+        )
+        .unwrap();
+        assert_eq!(config.instances.max_instances, 7);
+        assert_eq!(config.instances.ttl_secs, 100);
+    }
+
+    #[test]
+    fn override_types() {
+        let config = load(
+            "",
+            &[
+                ("INK_BIND_ADDRESS", "127.0.0.1:8001"),
+                ("INK_LIMITS__READ_ONLY", "true"),
+                ("INK_LIMITS__CPUS", "1.5"),
+                ("INK_PROXY__BLOCKED_PATHS", "[\"/DbAdmin\", \"/api/admin\"]"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.bind_address, "127.0.0.1:8001");
+        assert!(config.limits.read_only);
+        assert_eq!(config.limits.cpus, 1.5);
+        assert_eq!(config.proxy.blocked_paths, vec!["/DbAdmin", "/api/admin"]);
+    }
+
+    #[test]
+    fn numeric_strings_stay_strings() {
+        let config = load(
+            "",
+            &[
+                ("INK_DISCORD__CLIENT_ID", "123456789012345678"),
+                ("INK_DISCORD__CLIENT_SECRET", "0042"),
+                ("INK_GITHUB__CLIENT_ID", "1234"),
+                ("INK_GITHUB__CLIENT_SECRET", "true"),
+                ("INK_MSSQL__ADMIN_PASSWORD", "12345678"),
+                ("INK_DISCORD__ALLOWED_GUILDS", "[123, 456]"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            config.discord.client_id.as_deref(),
+            Some("123456789012345678")
+        );
+        assert_eq!(config.discord.client_secret.as_deref(), Some("0042"));
+        assert_eq!(config.github.client_id.as_deref(), Some("1234"));
+        assert_eq!(config.github.client_secret.as_deref(), Some("true"));
+        assert_eq!(config.mssql.admin_password, "12345678");
+        assert_eq!(config.discord.allowed_guilds, vec!["123", "456"]);
+    }
+
+    #[test]
+    fn override_creates_tables() {
+        let config = load(
+            "",
+            &[
+                ("INK_OIDC__CORP__ISSUER_URL", "https://id.example.com"),
+                ("INK_OIDC__CORP__CLIENT_ID", "1234"),
+                ("INK_OIDC__CORP__CLIENT_SECRET", "secret"),
+            ],
+        )
+        .unwrap();
+        let corp = &config.oidc["corp"];
+        assert_eq!(corp.issuer_url, "https://id.example.com");
+        assert_eq!(corp.client_id, "1234");
+    }
+
+    #[test]
+    fn unrelated_vars_are_ignored() {
+        let config = load(
+            "",
+            &[
+                ("INK_SOMETHING_ELSE", "1"),
+                ("INK_INSTANCES__NOT_A_SETTING", "1"),
+                ("INKWELL", "1"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            config.instances.max_instances,
+            InstanceConfig::default().max_instances
+        );
+    }
+
+    #[test]
+    fn invalid_override_values() {
+        assert!(load("", &[("INK_INSTANCES__MAX_INSTANCES", "many")]).is_err());
+        assert!(load("", &[("INK_INSTANCES__TTL_SECS", "-5")]).is_err());
+        assert!(load("", &[("INK_BIND_ADDRESS__PORT", "1")]).is_err());
+        assert!(load("", &[("INK_INSTANCES____TTL_SECS", "1")]).is_err());
+    }
+
+    #[test]
+    fn overrides_are_validated() {
+        let result = load("", &[("INK_INSTANCES__MAX_INSTANCES", "0")]);
+        assert!(result.is_err());
+    }
+
+    fn invalid(f: impl FnOnce(&mut Config)) -> bool {
+        let mut config = Config::default();
+        f(&mut config);
+        return config.validate().is_err();
+    }
+
+    #[test]
+    fn validate() {
+        Config::default().validate().unwrap();
+
+        assert!(invalid(|c| c.bind_address = "localhost".to_string()));
+        assert!(invalid(
+            |c| c.public_url = "http://localhost:8000/".to_string()
+        ));
+        assert!(invalid(|c| c.instances.ttl_secs = 0));
+        assert!(invalid(
+            |c| c.instances.max_lifetime_secs = c.instances.ttl_secs - 1
+        ));
+        assert!(invalid(|c| c.instances.admin_max_extend_secs = 0));
+        assert!(invalid(
+            |c| c.instances.warning_thresholds_secs = vec![60, 0]
+        ));
+        assert!(invalid(|c| c.instances.ready_path = "ready".to_string()));
+        assert!(invalid(
+            |c| c.instances.first_word_list = "/nonexistent".to_string()
+        ));
+        assert!(invalid(|c| c.docker.container_port = 0));
+        assert!(invalid(|c| c.docker.healthcheck = vec!["RUN".to_string()]));
+        assert!(invalid(|c| c.docker.data_path = "/".to_string()));
+        assert!(invalid(|c| {
+            c.docker.route = RouteMode::Name;
+            c.instances.ready_timeout_secs = 0;
+        }));
+        assert!(invalid(|c| c.limits.cpus = -1.0));
+        assert!(invalid(|c| c.limits.tmpfs = vec!["tmp".to_string()]));
+        assert!(invalid(|c| c.mssql.backend = MssqlBackendKind::Server));
+        assert!(invalid(
+            |c| c.mssql.connection_file = "connection_string".to_string()
+        ));
+        assert!(invalid(|c| c.mssql.secrets_dir = String::new()));
+        assert!(invalid(
+            |c| c.exports.endpoints = vec!["results".to_string()]
+        ));
+        assert!(invalid(
+            |c| c.proxy.blocked_paths = vec!["DbAdmin".to_string()]
+        ));
+        assert!(invalid(|c| c.discord.allowed_roles = vec!["1".to_string()]));
+        assert!(invalid(|c| c.github.client_id = Some("1".to_string())));
+        assert!(invalid(|c| {
+            c.oidc.insert("github".to_string(), OidcConfig::default());
+        }));
+        assert!(invalid(|c| {
+            let corp = OidcConfig {
+                issuer_url: "https://id.example.com".to_string(),
+                client_id: "ink".to_string(),
+                client_secret: "secret".to_string(),
+                scopes: vec!["profile".to_string()],
+                ..Default::default()
+            };
+            c.oidc.insert("corp".to_string(), corp);
+        }));
+    }
+}
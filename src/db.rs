@@ -0,0 +1,1029 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sqlite::{ConnectionThreadSafe, State};
+use utoipa::ToSchema;
+
+use crate::{exports::ExportKind, roles::Role, tokens::TokenScope};
+
+///
+/// things that happen to an instance over its lifetime, recorded in the instance_event table
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceEventKind {
+    Created,
+    Proxied,
+    Extended,
+    Expired,
+    Idle,
+    Removed,
+    Failed,
+}
+
+impl InstanceEventKind {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            InstanceEventKind::Created => "created",
+            InstanceEventKind::Proxied => "proxied",
+            InstanceEventKind::Extended => "extended",
+            InstanceEventKind::Expired => "expired",
+            InstanceEventKind::Idle => "idle",
+            InstanceEventKind::Removed => "removed",
+            InstanceEventKind::Failed => "failed",
+        };
+    }
+}
+
+impl fmt::Display for InstanceEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(self.as_str());
+    }
+}
+
+///
+/// a role given to a user from the admin API
+///
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UserRole {
+    pub user_id: String,
+
+    /// last known username, if they have ever created an instance
+    pub username: Option<String>,
+
+    pub role: Role,
+    pub reason: Option<String>,
+
+    /// ID of the admin who set the role
+    pub set_by: String,
+    #[schema(value_type = crate::openapi::Timestamp)]
+    pub set_on: SystemTime,
+}
+
+///
+/// an API token, without the token itself
+///
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    #[schema(value_type = crate::openapi::Timestamp)]
+    pub created_on: SystemTime,
+    #[schema(value_type = Option<crate::openapi::Timestamp>)]
+    pub expires_at: Option<SystemTime>,
+    #[schema(value_type = Option<crate::openapi::Timestamp>)]
+    pub last_used: Option<SystemTime>,
+}
+
+///
+/// a snapshot of the data volume of an instance
+///
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Snapshot {
+    pub id: i64,
+    pub name: String,
+
+    /// instance the snapshot was taken of
+    pub instance: String,
+
+    /// size of the tarball, in bytes
+    pub size: i64,
+
+    #[schema(value_type = crate::openapi::Timestamp)]
+    pub created_on: SystemTime,
+
+    /// file name of the tarball in the snapshot directory
+    #[serde(skip)]
+    pub file: String,
+}
+
+///
+/// something saved from an instance before it was removed
+///
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Export {
+    pub id: i64,
+
+    /// instance it was saved from
+    pub instance: String,
+
+    pub kind: ExportKind,
+
+    /// the endpoint or file it was saved from
+    pub source: String,
+
+    /// size of what was saved, in bytes
+    pub size: i64,
+
+    #[schema(value_type = crate::openapi::Timestamp)]
+    pub created_on: SystemTime,
+
+    /// path of the file in the export directory
+    #[serde(skip)]
+    pub file: String,
+}
+
+///
+/// who an API token belongs to, and what it can do
+///
+#[derive(Debug, Clone)]
+pub struct TokenOwner {
+    pub owner_id: String,
+    pub username: String,
+    pub scopes: Vec<TokenScope>,
+}
+
+///
+/// schema changes, applied in order. the index of the last one applied is kept in user_version,
+/// so add new changes to the end instead of editing old ones
+///
+static MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS owner (
+        id TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS instance (
+        name TEXT PRIMARY KEY NOT NULL,
+        owner_id TEXT NOT NULL REFERENCES owner(id),
+        port INTEGER NULL,
+        created_on INTEGER NOT NULL,
+        removed_on INTEGER NULL,
+        removal_reason TEXT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS instance_event (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        instance_name TEXT NULL,
+        owner_id TEXT NULL,
+        kind TEXT NOT NULL,
+        detail TEXT NULL,
+        timestamp INTEGER NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS instance_owner_id ON instance (owner_id);
+    CREATE INDEX IF NOT EXISTS instance_event_instance_name ON instance_event (instance_name);",
+    "ALTER TABLE instance ADD COLUMN expires_at INTEGER NULL;",
+    "CREATE TABLE user_role (
+        user_id TEXT PRIMARY KEY NOT NULL,
+        role TEXT NOT NULL,
+        reason TEXT NULL,
+        set_by TEXT NOT NULL,
+        set_on INTEGER NOT NULL
+    );",
+    "CREATE TABLE api_token (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        owner_id TEXT NOT NULL,
+        username TEXT NOT NULL,
+        name TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        scopes TEXT NOT NULL,
+        created_on INTEGER NOT NULL,
+        expires_at INTEGER NULL,
+        last_used INTEGER NULL,
+        revoked_on INTEGER NULL
+    );
+
+    CREATE INDEX api_token_owner_id ON api_token (owner_id);",
+    "CREATE TABLE snapshot (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        owner_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        instance_name TEXT NOT NULL,
+        file TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        created_on INTEGER NOT NULL
+    );
+
+    CREATE INDEX snapshot_owner_id ON snapshot (owner_id);",
+    "CREATE TABLE export (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        owner_id TEXT NOT NULL,
+        instance_name TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        source TEXT NOT NULL,
+        file TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        created_on INTEGER NOT NULL
+    );
+
+    CREATE INDEX export_owner_id ON export (owner_id);",
+];
+
+fn migrate(conn: &ConnectionThreadSafe) -> Result<()> {
+    let mut stmt = conn.prepare("PRAGMA user_version")?;
+    stmt.next()?;
+    let version = stmt.read::<i64, _>(0)? as usize;
+    drop(stmt);
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!("applying database migration {}", i + 1);
+        conn.execute(format!(
+            "BEGIN; {migration}; PRAGMA user_version = {}; COMMIT;",
+            i + 1
+        ))
+        .with_context(|| format!("failed to apply migration {}", i + 1))?;
+    }
+
+    return Ok(());
+}
+
+///
+/// sqlite backed record of instances, who owns them and what happened to them. docker labels are
+/// still the source of truth for what is running, this is what is left once the container is gone
+///
+#[derive(Clone)]
+pub struct Database {
+    conn: Arc<ConnectionThreadSafe>,
+
+    /// instances that already have a proxied event, so it is only recorded on the first request
+    proxied: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Database {
+    pub fn open(path: &str) -> Result<Self> {
+        let mut conn = sqlite::Connection::open_thread_safe(path)
+            .with_context(|| format!("failed to open database {path}"))?;
+        conn.set_busy_timeout(5000)?;
+
+        migrate(&conn).context("failed to migrate database")?;
+
+        return Ok(Database {
+            conn: Arc::new(conn),
+            proxied: Arc::new(Mutex::new(HashSet::new())),
+        });
+    }
+
+    ///
+    /// insert or update the owner, keeping the username current
+    ///
+    pub fn upsert_owner(&self, id: &str, username: &str) -> Result<()> {
+        let now = unix_time(SystemTime::now());
+
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO owner (id, username, first_seen, last_seen) VALUES (?, ?, ?, ?)
+                ON CONFLICT(id) DO UPDATE SET username = excluded.username, last_seen = excluded.last_seen",
+        )?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, username))?;
+        stmt.bind((3, now))?;
+        stmt.bind((4, now))?;
+        stmt.next()?;
+
+        return Ok(());
+    }
+
+    ///
+    /// record a newly created instance, along with a created event
+    ///
+    pub fn insert_instance(
+        &self,
+        name: &str,
+        owner_id: &str,
+        port: u16,
+        created_on: SystemTime,
+        expires_at: SystemTime,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO instance (name, owner_id, port, created_on, expires_at) VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT(name) DO UPDATE SET owner_id = excluded.owner_id, port = excluded.port,
+                    created_on = excluded.created_on, expires_at = excluded.expires_at,
+                    removed_on = NULL, removal_reason = NULL",
+        )?;
+        stmt.bind((1, name))?;
+        stmt.bind((2, owner_id))?;
+        stmt.bind((3, port as i64))?;
+        stmt.bind((4, unix_time(created_on)))?;
+        stmt.bind((5, unix_time(expires_at)))?;
+        stmt.next()?;
+
+        return self.record_event(Some(name), Some(owner_id), InstanceEventKind::Created, None);
+    }
+
+    ///
+    /// update when an instance expires, recording who extended it
+    ///
+    pub fn set_expires_at(
+        &self,
+        name: &str,
+        expires_at: SystemTime,
+        extended_by: &str,
+    ) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare("UPDATE instance SET expires_at = ? WHERE name = ? AND removed_on IS NULL")?;
+        stmt.bind((1, unix_time(expires_at)))?;
+        stmt.bind((2, name))?;
+        stmt.next()?;
+
+        return self.record_event(
+            Some(name),
+            None,
+            InstanceEventKind::Extended,
+            Some(&format!(
+                "extended to {} by {}",
+                unix_time(expires_at),
+                extended_by
+            )),
+        );
+    }
+
+    ///
+    /// get when each instance that has not been removed expires, keyed by instance name
+    ///
+    pub fn expires_at_by_name(&self) -> Result<HashMap<String, SystemTime>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name, expires_at FROM instance WHERE removed_on IS NULL AND expires_at IS NOT NULL",
+        )?;
+
+        let mut map: HashMap<String, SystemTime> = HashMap::new();
+        while let State::Row = stmt.next()? {
+            let name: String = stmt.read(0)?;
+            let expires_at: i64 = stmt.read(1)?;
+            map.insert(name, from_unix_time(expires_at));
+        }
+
+        return Ok(map);
+    }
+
+    ///
+    /// mark an instance as removed, and record why
+    ///
+    pub fn mark_removed(&self, name: &str, reason: &str) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "UPDATE instance SET removed_on = ?, removal_reason = ? WHERE name = ? AND removed_on IS NULL",
+        )?;
+        stmt.bind((1, unix_time(SystemTime::now())))?;
+        stmt.bind((2, reason))?;
+        stmt.bind((3, name))?;
+        stmt.next()?;
+
+        self.proxied.lock().unwrap().remove(name);
+
+        return self.record_event(Some(name), None, InstanceEventKind::Removed, Some(reason));
+    }
+
+    ///
+    /// record a proxied event the first time an instance is proxied to
+    ///
+    pub fn record_proxied(&self, name: &str) -> Result<()> {
+        if !self.proxied.lock().unwrap().insert(name.to_string()) {
+            return Ok(());
+        }
+
+        return self.record_event(Some(name), None, InstanceEventKind::Proxied, None);
+    }
+
+    ///
+    /// get the role set for a user, or None if they have not been given one
+    ///
+    pub fn get_role(&self, user_id: &str) -> Result<Option<Role>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT role FROM user_role WHERE user_id = ?")?;
+        stmt.bind((1, user_id))?;
+
+        if let State::Done = stmt.next()? {
+            return Ok(None);
+        }
+
+        let role: String = stmt.read(0)?;
+        return Ok(Some(Role::parse(&role)?));
+    }
+
+    ///
+    /// set the role of a user, replacing any role they already had
+    ///
+    pub fn set_role(
+        &self,
+        user_id: &str,
+        role: Role,
+        reason: Option<&str>,
+        set_by: &str,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO user_role (user_id, role, reason, set_by, set_on) VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT(user_id) DO UPDATE SET role = excluded.role, reason = excluded.reason,
+                    set_by = excluded.set_by, set_on = excluded.set_on",
+        )?;
+        stmt.bind((1, user_id))?;
+        stmt.bind((2, role.as_str()))?;
+        stmt.bind((3, reason))?;
+        stmt.bind((4, set_by))?;
+        stmt.bind((5, unix_time(SystemTime::now())))?;
+        stmt.next()?;
+
+        tracing::info!(
+            "{} set role of {} to {} ({:?})",
+            set_by,
+            user_id,
+            role,
+            reason
+        );
+
+        return Ok(());
+    }
+
+    ///
+    /// get every user who has been given a role
+    ///
+    pub fn roles(&self) -> Result<Vec<UserRole>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT r.user_id, o.username, r.role, r.reason, r.set_by, r.set_on
+                FROM user_role r LEFT JOIN owner o ON o.id = r.user_id
+                ORDER BY r.set_on",
+        )?;
+
+        let mut roles: Vec<UserRole> = vec![];
+        while let State::Row = stmt.next()? {
+            let role: String = stmt.read(2)?;
+            roles.push(UserRole {
+                user_id: stmt.read(0)?,
+                username: stmt.read(1)?,
+                role: Role::parse(&role)?,
+                reason: stmt.read(3)?,
+                set_by: stmt.read(4)?,
+                set_on: from_unix_time(stmt.read(5)?),
+            });
+        }
+
+        return Ok(roles);
+    }
+
+    ///
+    /// save a new API token, returning its ID. only the hash of the token is kept
+    ///
+    pub fn insert_token(
+        &self,
+        owner_id: &str,
+        username: &str,
+        name: &str,
+        token_hash: &str,
+        scopes: &[TokenScope],
+        expires_at: Option<SystemTime>,
+    ) -> Result<i64> {
+        let scopes: Vec<&str> = scopes.iter().map(|s| s.as_str()).collect();
+
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO api_token (owner_id, username, name, token_hash, scopes, created_on, expires_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING id",
+        )?;
+        stmt.bind((1, owner_id))?;
+        stmt.bind((2, username))?;
+        stmt.bind((3, name))?;
+        stmt.bind((4, token_hash))?;
+        stmt.bind((5, scopes.join(",").as_str()))?;
+        stmt.bind((6, unix_time(SystemTime::now())))?;
+        stmt.bind((7, expires_at.map(unix_time)))?;
+        stmt.next()?;
+
+        return Ok(stmt.read(0)?);
+    }
+
+    ///
+    /// get the tokens of a user that have not been revoked
+    ///
+    pub fn tokens_by_owner(&self, owner_id: &str) -> Result<Vec<ApiToken>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, scopes, created_on, expires_at, last_used FROM api_token
+                WHERE owner_id = ? AND revoked_on IS NULL ORDER BY id",
+        )?;
+        stmt.bind((1, owner_id))?;
+
+        let mut tokens: Vec<ApiToken> = vec![];
+        while let State::Row = stmt.next()? {
+            let scopes: String = stmt.read(2)?;
+            tokens.push(ApiToken {
+                id: stmt.read(0)?,
+                name: stmt.read(1)?,
+                scopes: parse_scopes(&scopes)?,
+                created_on: from_unix_time(stmt.read(3)?),
+                expires_at: stmt.read::<Option<i64>, _>(4)?.map(from_unix_time),
+                last_used: stmt.read::<Option<i64>, _>(5)?.map(from_unix_time),
+            });
+        }
+
+        return Ok(tokens);
+    }
+
+    ///
+    /// revoke a token owned by `owner_id`, returning false if there is no such token
+    ///
+    pub fn revoke_token(&self, id: i64, owner_id: &str) -> Result<bool> {
+        let mut stmt = self.conn.prepare(
+            "UPDATE api_token SET revoked_on = ? WHERE id = ? AND owner_id = ? AND revoked_on IS NULL
+                RETURNING id",
+        )?;
+        stmt.bind((1, unix_time(SystemTime::now())))?;
+        stmt.bind((2, id))?;
+        stmt.bind((3, owner_id))?;
+
+        return Ok(stmt.next()? == State::Row);
+    }
+
+    ///
+    /// find who a token belongs to from its hash, marking it as used. None if the token does not exist,
+    /// was revoked, or has expired
+    ///
+    pub fn use_token(&self, token_hash: &str) -> Result<Option<TokenOwner>> {
+        let now = unix_time(SystemTime::now());
+
+        let mut stmt = self.conn.prepare(
+            "UPDATE api_token SET last_used = ?1
+                WHERE token_hash = ?2 AND revoked_on IS NULL AND (expires_at IS NULL OR expires_at > ?1)
+                RETURNING owner_id, username, scopes",
+        )?;
+        stmt.bind((1, now))?;
+        stmt.bind((2, token_hash))?;
+
+        if let State::Done = stmt.next()? {
+            return Ok(None);
+        }
+
+        let scopes: String = stmt.read(2)?;
+        return Ok(Some(TokenOwner {
+            owner_id: stmt.read(0)?,
+            username: stmt.read(1)?,
+            scopes: parse_scopes(&scopes)?,
+        }));
+    }
+
+    ///
+    /// save a new snapshot, returning its ID
+    ///
+    pub fn insert_snapshot(
+        &self,
+        owner_id: &str,
+        name: &str,
+        instance_name: &str,
+        file: &str,
+        size: i64,
+    ) -> Result<i64> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO snapshot (owner_id, name, instance_name, file, size, created_on)
+                VALUES (?, ?, ?, ?, ?, ?) RETURNING id",
+        )?;
+        stmt.bind((1, owner_id))?;
+        stmt.bind((2, name))?;
+        stmt.bind((3, instance_name))?;
+        stmt.bind((4, file))?;
+        stmt.bind((5, size))?;
+        stmt.bind((6, unix_time(SystemTime::now())))?;
+        stmt.next()?;
+
+        return Ok(stmt.read(0)?);
+    }
+
+    pub fn snapshots_by_owner(&self, owner_id: &str) -> Result<Vec<Snapshot>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, instance_name, size, created_on, file FROM snapshot
+                WHERE owner_id = ? ORDER BY id",
+        )?;
+        stmt.bind((1, owner_id))?;
+
+        let mut snapshots: Vec<Snapshot> = vec![];
+        while let State::Row = stmt.next()? {
+            snapshots.push(read_snapshot(&stmt)?);
+        }
+
+        return Ok(snapshots);
+    }
+
+    ///
+    /// get a snapshot owned by `owner_id`, or None if there is no such snapshot
+    ///
+    pub fn get_snapshot(&self, id: i64, owner_id: &str) -> Result<Option<Snapshot>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, instance_name, size, created_on, file FROM snapshot
+                WHERE id = ? AND owner_id = ?",
+        )?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, owner_id))?;
+
+        if let State::Done = stmt.next()? {
+            return Ok(None);
+        }
+
+        return Ok(Some(read_snapshot(&stmt)?));
+    }
+
+    ///
+    /// delete a snapshot owned by `owner_id`, returning false if there is no such snapshot. the
+    /// tarball itself is not touched
+    ///
+    pub fn delete_snapshot(&self, id: i64, owner_id: &str) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare("DELETE FROM snapshot WHERE id = ? AND owner_id = ? RETURNING id")?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, owner_id))?;
+
+        return Ok(stmt.next()? == State::Row);
+    }
+
+    ///
+    /// save a new export, returning its ID
+    ///
+    pub fn insert_export(
+        &self,
+        owner_id: &str,
+        instance_name: &str,
+        kind: ExportKind,
+        source: &str,
+        file: &str,
+        size: i64,
+    ) -> Result<i64> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO export (owner_id, instance_name, kind, source, file, size, created_on)
+                VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING id",
+        )?;
+        stmt.bind((1, owner_id))?;
+        stmt.bind((2, instance_name))?;
+        stmt.bind((3, kind.as_str()))?;
+        stmt.bind((4, source))?;
+        stmt.bind((5, file))?;
+        stmt.bind((6, size))?;
+        stmt.bind((7, unix_time(SystemTime::now())))?;
+        stmt.next()?;
+
+        return Ok(stmt.read(0)?);
+    }
+
+    ///
+    /// get the exports of a user, oldest first
+    ///
+    pub fn exports_by_owner(&self, owner_id: &str) -> Result<Vec<Export>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, instance_name, kind, source, size, created_on, file FROM export
+                WHERE owner_id = ? ORDER BY id",
+        )?;
+        stmt.bind((1, owner_id))?;
+
+        let mut exports: Vec<Export> = vec![];
+        while let State::Row = stmt.next()? {
+            exports.push(read_export(&stmt)?);
+        }
+
+        return Ok(exports);
+    }
+
+    ///
+    /// get an export owned by `owner_id`, or None if there is no such export
+    ///
+    pub fn get_export(&self, id: i64, owner_id: &str) -> Result<Option<Export>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, instance_name, kind, source, size, created_on, file FROM export
+                WHERE id = ? AND owner_id = ?",
+        )?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, owner_id))?;
+
+        if let State::Done = stmt.next()? {
+            return Ok(None);
+        }
+
+        return Ok(Some(read_export(&stmt)?));
+    }
+
+    ///
+    /// delete an export owned by `owner_id`, returning false if there is no such export. the file
+    /// itself is not touched
+    ///
+    pub fn delete_export(&self, id: i64, owner_id: &str) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare("DELETE FROM export WHERE id = ? AND owner_id = ? RETURNING id")?;
+        stmt.bind((1, id))?;
+        stmt.bind((2, owner_id))?;
+
+        return Ok(stmt.next()? == State::Row);
+    }
+
+    ///
+    /// append an event to the audit trail. an event may not have an instance (creation failed
+    /// before a name was picked). if no owner is given, it is taken from the instance
+    ///
+    pub fn record_event(
+        &self,
+        instance_name: Option<&str>,
+        owner_id: Option<&str>,
+        kind: InstanceEventKind,
+        detail: Option<&str>,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO instance_event (instance_name, owner_id, kind, detail, timestamp)
+                VALUES (?1, COALESCE(?2, (SELECT owner_id FROM instance WHERE name = ?1)), ?3, ?4, ?5)",
+        )?;
+        stmt.bind((1, instance_name))?;
+        stmt.bind((2, owner_id))?;
+        stmt.bind((3, kind.as_str()))?;
+        stmt.bind((4, detail))?;
+        stmt.bind((5, unix_time(SystemTime::now())))?;
+        stmt.next()?;
+
+        tracing::debug!(
+            "recorded {} event for instance={:?} owner={:?} detail={:?}",
+            kind,
+            instance_name,
+            owner_id,
+            detail
+        );
+
+        return Ok(());
+    }
+}
+
+///
+/// read a row of id, name, instance_name, size, created_on, file from the snapshot table
+///
+fn read_snapshot(stmt: &sqlite::Statement) -> Result<Snapshot> {
+    return Ok(Snapshot {
+        id: stmt.read(0)?,
+        name: stmt.read(1)?,
+        instance: stmt.read(2)?,
+        size: stmt.read(3)?,
+        created_on: from_unix_time(stmt.read(4)?),
+        file: stmt.read(5)?,
+    });
+}
+
+///
+/// read a row of id, instance_name, kind, source, size, created_on, file from the export table
+///
+fn read_export(stmt: &sqlite::Statement) -> Result<Export> {
+    let kind: String = stmt.read(2)?;
+    return Ok(Export {
+        id: stmt.read(0)?,
+        instance: stmt.read(1)?,
+        kind: ExportKind::parse(&kind)?,
+        source: stmt.read(3)?,
+        size: stmt.read(4)?,
+        created_on: from_unix_time(stmt.read(5)?),
+        file: stmt.read(6)?,
+    });
+}
+
+fn parse_scopes(scopes: &str) -> Result<Vec<TokenScope>> {
+    return scopes
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(TokenScope::parse)
+        .collect();
+}
+
+///
+/// seconds since the unix epoch, which is how all times are stored in the DB
+///
+pub fn unix_time(time: SystemTime) -> i64 {
+    return time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+}
+
+pub fn from_unix_time(secs: i64) -> SystemTime {
+    return UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> Database {
+        return Database::open(":memory:").unwrap();
+    }
+
+    fn user_version(db: &Database) -> i64 {
+        let mut stmt = db.conn.prepare("PRAGMA user_version").unwrap();
+        stmt.next().unwrap();
+        return stmt.read(0).unwrap();
+    }
+
+    /// instance, owner, kind and detail of an event
+    type Event = (Option<String>, Option<String>, String, Option<String>);
+
+    ///
+    /// every event, oldest first
+    ///
+    fn events(db: &Database) -> Vec<Event> {
+        let mut stmt = db
+            .conn
+            .prepare("SELECT instance_name, owner_id, kind, detail FROM instance_event ORDER BY id")
+            .unwrap();
+
+        let mut events = vec![];
+        while let State::Row = stmt.next().unwrap() {
+            events.push((
+                stmt.read(0).unwrap(),
+                stmt.read(1).unwrap(),
+                stmt.read(2).unwrap(),
+                stmt.read(3).unwrap(),
+            ));
+        }
+        return events;
+    }
+
+    #[test]
+    fn migrations_apply_once() {
+        let path = std::env::temp_dir().join(format!("ink-db-test-{}.db", rand::random::<u64>()));
+        let path = path.to_str().unwrap();
+
+        let db = Database::open(path).unwrap();
+        assert_eq!(user_version(&db), MIGRATIONS.len() as i64);
+        db.upsert_owner("1", "one").unwrap();
+        drop(db);
+
+        // opening again does not apply the migrations again, which would fail on the ALTER TABLE
+        let db = Database::open(path).unwrap();
+        assert_eq!(user_version(&db), MIGRATIONS.len() as i64);
+        db.upsert_owner("1", "one").unwrap();
+        drop(db);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn instance_lifecycle() {
+        let db = db();
+        let created_on = from_unix_time(1000);
+
+        db.upsert_owner("1", "one").unwrap();
+        db.insert_instance("a-b", "1", 40000, created_on, from_unix_time(2000))
+            .unwrap();
+        assert_eq!(
+            db.expires_at_by_name().unwrap(),
+            HashMap::from([("a-b".to_string(), from_unix_time(2000))])
+        );
+
+        db.set_expires_at("a-b", from_unix_time(3000), "admin")
+            .unwrap();
+        assert_eq!(
+            db.expires_at_by_name().unwrap()["a-b"],
+            from_unix_time(3000)
+        );
+
+        db.record_proxied("a-b").unwrap();
+        db.record_proxied("a-b").unwrap();
+
+        db.mark_removed("a-b", "expired").unwrap();
+        assert!(db.expires_at_by_name().unwrap().is_empty());
+
+        // removed instances cannot be extended
+        db.set_expires_at("a-b", from_unix_time(4000), "admin")
+            .unwrap();
+        assert!(db.expires_at_by_name().unwrap().is_empty());
+
+        let kinds: Vec<String> = events(&db).into_iter().map(|e| e.2).collect();
+        assert_eq!(
+            kinds,
+            vec!["created", "extended", "proxied", "removed", "extended"]
+        );
+
+        // the owner of an event is taken from the instance if not given
+        assert!(
+            events(&db)
+                .iter()
+                .all(|e| e.0.as_deref() == Some("a-b") && e.1.as_deref() == Some("1"))
+        );
+    }
+
+    #[test]
+    fn event_without_instance() {
+        let db = db();
+        db.record_event(None, Some("1"), InstanceEventKind::Failed, Some("no image"))
+            .unwrap();
+
+        assert_eq!(
+            events(&db),
+            vec![(
+                None,
+                Some("1".to_string()),
+                "failed".to_string(),
+                Some("no image".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn roles() {
+        let db = db();
+        db.upsert_owner("2", "two").unwrap();
+        assert_eq!(db.get_role("2").unwrap(), None);
+
+        db.set_role("2", Role::Banned, Some("spam"), "1").unwrap();
+        db.set_role("3", Role::Admin, None, "1").unwrap();
+        assert_eq!(db.get_role("2").unwrap(), Some(Role::Banned));
+
+        db.set_role("2", Role::User, None, "1").unwrap();
+        assert_eq!(db.get_role("2").unwrap(), Some(Role::User));
+
+        let roles = db.roles().unwrap();
+        assert_eq!(roles.len(), 2);
+        let two = roles.iter().find(|r| r.user_id == "2").unwrap();
+        assert_eq!(two.username.as_deref(), Some("two"));
+        assert_eq!(two.reason, None);
+        let three = roles.iter().find(|r| r.user_id == "3").unwrap();
+        assert_eq!(three.username, None);
+    }
+
+    #[test]
+    fn tokens() {
+        let db = db();
+        let scopes = [TokenScope::Read, TokenScope::Write];
+        let id = db
+            .insert_token("1", "one", "script", "hash", &scopes, None)
+            .unwrap();
+
+        let owner = db.use_token("hash").unwrap().unwrap();
+        assert_eq!(owner.owner_id, "1");
+        assert_eq!(owner.username, "one");
+        assert_eq!(owner.scopes, scopes);
+        assert!(db.use_token("other").unwrap().is_none());
+
+        let tokens = db.tokens_by_owner("1").unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].id, id);
+        assert!(tokens[0].last_used.is_some());
+
+        // only the owner can revoke, and only once
+        assert!(!db.revoke_token(id, "2").unwrap());
+        assert!(db.revoke_token(id, "1").unwrap());
+        assert!(!db.revoke_token(id, "1").unwrap());
+
+        assert!(db.use_token("hash").unwrap().is_none());
+        assert!(db.tokens_by_owner("1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn expired_tokens() {
+        let db = db();
+        let expired = SystemTime::now() - Duration::from_secs(1);
+        db.insert_token(
+            "1",
+            "one",
+            "old",
+            "hash",
+            &[TokenScope::Read],
+            Some(expired),
+        )
+        .unwrap();
+
+        assert!(db.use_token("hash").unwrap().is_none());
+    }
+
+    #[test]
+    fn snapshots() {
+        let db = db();
+        let id = db.insert_snapshot("1", "before", "a-b", "1/a", 10).unwrap();
+        db.insert_snapshot("2", "other", "c-d", "2/c", 20).unwrap();
+
+        let snapshot = db.get_snapshot(id, "1").unwrap().unwrap();
+        assert_eq!(snapshot.name, "before");
+        assert_eq!(snapshot.instance, "a-b");
+        assert_eq!(snapshot.file, "1/a");
+        assert_eq!(snapshot.size, 10);
+        assert!(db.get_snapshot(id, "2").unwrap().is_none());
+        assert_eq!(db.snapshots_by_owner("1").unwrap().len(), 1);
+
+        assert!(!db.delete_snapshot(id, "2").unwrap());
+        assert!(db.delete_snapshot(id, "1").unwrap());
+        assert!(!db.delete_snapshot(id, "1").unwrap());
+        assert!(db.get_snapshot(id, "1").unwrap().is_none());
+        assert_eq!(db.snapshots_by_owner("2").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn exports() {
+        let db = db();
+        let first = db
+            .insert_export("1", "a-b", ExportKind::Endpoint, "/results", "1/a", 10)
+            .unwrap();
+        let second = db
+            .insert_export("1", "a-b", ExportKind::File, "/data", "1/b", 20)
+            .unwrap();
+
+        let exports = db.exports_by_owner("1").unwrap();
+        assert_eq!(
+            exports.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![first, second]
+        );
+
+        let export = db.get_export(second, "1").unwrap().unwrap();
+        assert_eq!(export.kind, ExportKind::File);
+        assert_eq!(export.source, "/data");
+        assert!(db.get_export(second, "2").unwrap().is_none());
+
+        assert!(!db.delete_export(first, "2").unwrap());
+        assert!(db.delete_export(first, "1").unwrap());
+        assert!(!db.delete_export(first, "1").unwrap());
+        assert_eq!(db.exports_by_owner("1").unwrap().len(), 1);
+    }
+}
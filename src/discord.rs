@@ -0,0 +1,418 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use http::StatusCode;
+use oauth2::Scope;
+use serde::Deserialize;
+
+use crate::{
+    User,
+    config::DiscordConfig,
+    identity::{self, IdentityProvider, OauthClient},
+};
+
+/// most guilds discord returns in one page of the guilds of a user
+const GUILD_PAGE_SIZE: usize = 200;
+
+///
+/// log in with discord, optionally only letting in members of some guilds
+///
+pub struct DiscordProvider {
+    config: DiscordConfig,
+    oauth: OauthClient,
+    client: reqwest::Client,
+}
+
+// https://discord.com/developers/docs/resources/user#object-user-structure
+#[derive(Debug, Deserialize)]
+struct DiscordUser {
+    id: String,
+    username: String,
+    discriminator: String,
+}
+
+// https://discord.com/developers/docs/resources/user#get-current-user-guilds
+#[derive(Debug, Deserialize)]
+struct PartialGuild {
+    id: String,
+}
+
+// https://discord.com/developers/docs/resources/guild#guild-member-object
+#[derive(Debug, Deserialize)]
+struct GuildMember {
+    roles: Vec<String>,
+}
+
+impl DiscordProvider {
+    pub fn new(
+        config: &DiscordConfig,
+        client_id: String,
+        client_secret: String,
+        redirect_url: String,
+    ) -> Result<Self> {
+        return Ok(DiscordProvider {
+            config: config.clone(),
+            oauth: identity::oauth_client(
+                client_id,
+                client_secret,
+                format!("{}/oauth2/authorize?response_type=code", config.api_url),
+                format!("{}/oauth2/token", config.api_url),
+                redirect_url,
+            )?,
+            client: reqwest::Client::new(),
+        });
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for DiscordProvider {
+    fn id(&self) -> &str {
+        return "discord";
+    }
+
+    fn display_name(&self) -> &str {
+        return "Discord";
+    }
+
+    fn oauth_client(&self) -> &OauthClient {
+        return &self.oauth;
+    }
+
+    ///
+    /// identify, and what is needed to check if a user is allowed to log in
+    ///
+    fn scopes(&self) -> Vec<Scope> {
+        let config = &self.config;
+        let mut scopes = vec![Scope::new("identify".to_string())];
+
+        // getting the member from each guild also tells if the user is in it, so guilds is not needed too
+        if !config.allowed_roles.is_empty() {
+            scopes.push(Scope::new("guilds.members.read".to_string()));
+        } else if !config.allowed_guilds.is_empty() {
+            scopes.push(Scope::new("guilds".to_string()));
+        }
+
+        return scopes;
+    }
+
+    async fn user(&self, token: &str) -> Result<User> {
+        let user: DiscordUser = self
+            .client
+            // https://discord.com/developers/docs/resources/user#get-current-user
+            .get(format!("{}/users/@me", self.config.api_url))
+            .bearer_auth(token)
+            .send()
+            .await
+            .context("failed in sending request to target Url")?
+            .error_for_status()
+            .context("failed to get discord user")?
+            .json()
+            .await
+            .context("failed to deserialize response as JSON")?;
+
+        return Ok(User {
+            id: user.id,
+            username: user.username,
+            discriminator: user.discriminator,
+        });
+    }
+
+    ///
+    /// check if the user with `token` is in one of the allowed guilds, and has one of the allowed roles
+    /// if any are set. always true if no guilds are set
+    ///
+    async fn is_allowed(&self, token: &str) -> Result<bool> {
+        let (config, client) = (&self.config, &self.client);
+        if config.allowed_guilds.is_empty() {
+            return Ok(true);
+        }
+
+        if config.allowed_roles.is_empty() {
+            // guilds are listed in pages, each one after the last guild of the page before it
+            let mut after: Option<String> = None;
+            loop {
+                let mut request = client
+                    .get(format!("{}/users/@me/guilds", config.api_url))
+                    .query(&[("limit", GUILD_PAGE_SIZE.to_string())]);
+                if let Some(after) = &after {
+                    request = request.query(&[("after", after)]);
+                }
+
+                let guilds: Vec<PartialGuild> = request
+                    .bearer_auth(token)
+                    .send()
+                    .await
+                    .context("failed to get guilds of user")?
+                    .error_for_status()
+                    .context("failed to get guilds of user")?
+                    .json()
+                    .await
+                    .context("failed to deserialize guilds")?;
+
+                if guilds.iter().any(|g| config.allowed_guilds.contains(&g.id)) {
+                    return Ok(true);
+                }
+                if guilds.len() < GUILD_PAGE_SIZE {
+                    return Ok(false);
+                }
+
+                after = guilds.last().map(|g| g.id.clone());
+            }
+        }
+
+        for guild in &config.allowed_guilds {
+            let response = client
+                .get(format!(
+                    "{}/users/@me/guilds/{guild}/member",
+                    config.api_url
+                ))
+                .bearer_auth(token)
+                .send()
+                .await
+                .with_context(|| format!("failed to get member of guild {guild}"))?;
+
+            // not in this guild
+            if response.status() == StatusCode::NOT_FOUND {
+                continue;
+            }
+
+            let member: GuildMember = response
+                .error_for_status()
+                .with_context(|| format!("failed to get member of guild {guild}"))?
+                .json()
+                .await
+                .context("failed to deserialize guild member")?;
+
+            if member
+                .roles
+                .iter()
+                .any(|r| config.allowed_roles.contains(r))
+            {
+                return Ok(true);
+            }
+        }
+
+        return Ok(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        Json, Router,
+        extract::{Path, Query},
+        response::IntoResponse,
+        routing::get,
+    };
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::test_util;
+
+    /// the token the stand-in API accepts
+    const TOKEN: &str = "token";
+
+    ///
+    /// a stand-in for the discord API, where the user is in `guilds` with the roles in `members`. the
+    /// `after` of each guilds request is kept in `pages`
+    ///
+    async fn discord(
+        guilds: Vec<String>,
+        members: HashMap<String, Vec<String>>,
+        pages: Arc<Mutex<Vec<Option<String>>>>,
+    ) -> String {
+        let router = Router::new()
+            .route(
+                "/users/@me",
+                get(|| async {
+                    Json(json!({"id": "1234", "username": "someone", "discriminator": "0"}))
+                }),
+            )
+            .route(
+                "/users/@me/guilds",
+                get(
+                    move |headers: http::HeaderMap,
+                          Query(query): Query<HashMap<String, String>>| async move {
+                        if !authorized(&headers) {
+                            return StatusCode::UNAUTHORIZED.into_response();
+                        }
+
+                        let limit: usize = query["limit"].parse().unwrap();
+                        let after = query.get("after").cloned();
+                        pages.lock().unwrap().push(after.clone());
+
+                        let page: Vec<Value> = guilds
+                            .iter()
+                            .skip_while(|g| after.as_ref().is_some_and(|a| *g <= a))
+                            .take(limit)
+                            .map(|g| json!({"id": g, "name": "guild"}))
+                            .collect();
+                        return Json(page).into_response();
+                    },
+                ),
+            )
+            .route(
+                "/users/@me/guilds/{guild}/member",
+                get(
+                    move |headers: http::HeaderMap, Path(guild): Path<String>| async move {
+                        if !authorized(&headers) {
+                            return StatusCode::UNAUTHORIZED.into_response();
+                        }
+
+                        return match members.get(&guild) {
+                            Some(roles) => Json(json!({"roles": roles})).into_response(),
+                            None => (StatusCode::NOT_FOUND, Json(json!({"code": 10004})))
+                                .into_response(),
+                        };
+                    },
+                ),
+            );
+
+        return test_util::serve(router).await;
+    }
+
+    fn authorized(headers: &http::HeaderMap) -> bool {
+        return headers
+            .get(http::header::AUTHORIZATION)
+            .is_some_and(|v| v == &format!("Bearer {TOKEN}"));
+    }
+
+    fn provider(
+        api_url: String,
+        allowed_guilds: &[&str],
+        allowed_roles: &[&str],
+    ) -> DiscordProvider {
+        let config = DiscordConfig {
+            api_url,
+            allowed_guilds: allowed_guilds.iter().map(|g| g.to_string()).collect(),
+            allowed_roles: allowed_roles.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        };
+
+        return DiscordProvider::new(
+            &config,
+            "id".to_string(),
+            "secret".to_string(),
+            "http://localhost/auth/discord/callback".to_string(),
+        )
+        .unwrap();
+    }
+
+    /// guild IDs, zero padded so they sort in the order discord returns them
+    fn guild_ids(count: usize) -> Vec<String> {
+        return (1..=count).map(|i| format!("{i:06}")).collect();
+    }
+
+    fn pages() -> Arc<Mutex<Vec<Option<String>>>> {
+        return Arc::new(Mutex::new(vec![]));
+    }
+
+    #[tokio::test]
+    async fn user() {
+        let api = discord(vec![], HashMap::new(), pages()).await;
+
+        let user = provider(api, &[], &[]).user(TOKEN).await.unwrap();
+        assert_eq!(user.id, "1234");
+        assert_eq!(user.username, "someone");
+    }
+
+    #[tokio::test]
+    async fn no_allowed_guilds() {
+        // nothing is asked of discord, so an unreachable API is fine
+        let provider = provider("http://127.0.0.1:1".to_string(), &[], &[]);
+        assert!(provider.is_allowed(TOKEN).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn in_allowed_guild() {
+        let api = discord(guild_ids(3), HashMap::new(), pages()).await;
+
+        let provider = provider(api, &["000002"], &[]);
+        assert!(provider.is_allowed(TOKEN).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn in_no_allowed_guild() {
+        let pages = pages();
+        let api = discord(guild_ids(3), HashMap::new(), pages.clone()).await;
+
+        let provider = provider(api, &["000009", "000010"], &[]);
+        assert!(!provider.is_allowed(TOKEN).await.unwrap());
+        assert_eq!(*pages.lock().unwrap(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn allowed_guild_on_a_later_page() {
+        let pages = pages();
+        let api = discord(guild_ids(450), HashMap::new(), pages.clone()).await;
+
+        let provider = provider(api, &["000430"], &[]);
+        assert!(provider.is_allowed(TOKEN).await.unwrap());
+        assert_eq!(
+            *pages.lock().unwrap(),
+            vec![None, Some("000200".to_string()), Some("000400".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn in_no_allowed_guild_on_any_page() {
+        let pages = pages();
+        let api = discord(guild_ids(400), HashMap::new(), pages.clone()).await;
+
+        let provider = provider(api, &["999999"], &[]);
+        assert!(!provider.is_allowed(TOKEN).await.unwrap());
+
+        // a full last page means there might be more, so one more empty page is asked for
+        assert_eq!(pages.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn in_guild_with_allowed_role() {
+        let members = HashMap::from([("000001".to_string(), vec!["50".to_string()])]);
+        let api = discord(vec![], members, pages()).await;
+
+        let provider = provider(api, &["000001"], &["40", "50"]);
+        assert!(provider.is_allowed(TOKEN).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn in_guild_without_allowed_role() {
+        let members = HashMap::from([("000001".to_string(), vec!["60".to_string()])]);
+        let api = discord(vec![], members, pages()).await;
+
+        let provider = provider(api, &["000001"], &["40", "50"]);
+        assert!(!provider.is_allowed(TOKEN).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn not_a_member() {
+        let api = discord(vec![], HashMap::new(), pages()).await;
+
+        let provider = provider(api, &["000001"], &["50"]);
+        assert!(!provider.is_allowed(TOKEN).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn member_of_a_later_guild() {
+        let members = HashMap::from([("000002".to_string(), vec!["50".to_string()])]);
+        let api = discord(vec![], members, pages()).await;
+
+        let provider = provider(api, &["000001", "000002"], &["50"]);
+        assert!(provider.is_allowed(TOKEN).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn api_errors() {
+        let api = discord(guild_ids(1), HashMap::new(), pages()).await;
+
+        let guilds = provider(api.clone(), &["000001"], &[]);
+        assert!(guilds.is_allowed("wrong").await.is_err());
+
+        let roles = provider(api, &["000001"], &["50"]);
+        assert!(roles.is_allowed("wrong").await.is_err());
+    }
+}
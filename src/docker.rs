@@ -1,239 +1,971 @@
-use std::{collections::HashMap, error::Error, time::Duration};
-
-use bollard::{
-    query_parameters::{
-        CreateContainerOptionsBuilder, InspectContainerOptions, ListContainersOptions,
-        ListContainersOptionsBuilder, ListImagesOptions, ListImagesOptionsBuilder,
-        RemoveContainerOptions, StartContainerOptions, StopContainerOptions,
-    },
-    secret::{ContainerCreateBody, ContainerInspectResponse, HostConfig, PortBinding},
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
 };
-use rand::Rng;
 
-use crate::instance::SquittalInstance;
+use anyhow::{Context, Result, anyhow, bail};
+use axum::body::Body;
+use bollard::secret::{
+    ContainerCreateBody, ContainerInspectResponse, HealthConfig, HealthStatusEnum, HostConfig,
+    PortBinding, RestartPolicy, RestartPolicyNameEnum,
+};
+use http::Request;
+use rand::Rng;
 
-pub async fn get_instances() -> Result<Vec<SquittalInstance>, bollard::errors::Error> {
-    let docker = bollard::Docker::connect_with_socket_defaults()?;
+use crate::{
+    app_state::AppState,
+    config::{self, Config, RemoveAction, RouteMode},
+    db::{InstanceEventKind, Snapshot, unix_time},
+    events::{InstanceUpdate, UpdateKind},
+    exports,
+    instance::{InstanceStatus, SquittalInstance},
+    instance_index, mssql, snapshots,
+};
 
-    let container_filter: ListContainersOptions = ListContainersOptionsBuilder::new()
-        .filters(&HashMap::from([
-            ("ancestor", vec!["squittal"]),
-            ("label", vec!["ink_tag=true"]),
-        ]))
-        .build();
+pub async fn get_instances(state: &AppState) -> Result<Vec<SquittalInstance>> {
+    let result = state
+        .backend
+        .list_containers(&state.config.docker.image)
+        .await?;
 
-    let result = docker.list_containers(Some(container_filter)).await?;
-    //println!("{:?}", result);
+    // extensions are kept in the DB, since labels cannot be changed once a container is made
+    let expires_at = match state.db.expires_at_by_name() {
+        Ok(map) => map,
+        Err(e) => {
+            tracing::error!("failed to get instance expirations from the DB: {}", e);
+            HashMap::new()
+        }
+    };
 
     let mut results: Vec<SquittalInstance> = vec![];
     for ele in result {
-        results.push(SquittalInstance::from(ele));
+        let id = ele.id.clone().unwrap_or_default();
+        let mut instance = match SquittalInstance::from_summary(ele, &state.config) {
+            Ok(instance) => instance,
+            Err(e) => {
+                tracing::warn!("skipping container {} that ink cannot use: {:#}", id, e);
+                continue;
+            }
+        };
+        if let Some(t) = expires_at.get(instance_name(&instance.name)) {
+            instance.expires_at = *t;
+        }
+
+        results.push(instance);
     }
 
     return Ok(results);
 }
 
 ///
-/// get the instances created by a specific user
+/// start creating an instance owned by `owner_id`, with its data volume restored from `snapshot` if given.
+/// used both when a user creates an instance and when a queued user gets a slot. the instance is returned
+/// at once in the provisioning state, and a background task creates the container, moving the instance to
+/// starting, then ready or failed. fails only if the instance cannot be given a name
 ///
-pub async fn get_instance_by_owner(
-    owner: &str,
-) -> Result<Vec<SquittalInstance>, bollard::errors::Error> {
-    let docker = bollard::Docker::connect_with_socket_defaults()?;
-
-    let container_filter: ListContainersOptions = ListContainersOptionsBuilder::new()
-        .filters(&HashMap::from([
-            ("ancestor", vec!["squittal"]),
-            ("label", vec![&format!("created_by={}", owner.to_string())]),
-        ]))
-        .build();
+pub fn provision_instance(
+    state: &AppState,
+    owner_id: &str,
+    username: &str,
+    snapshot: Option<Snapshot>,
+) -> Result<SquittalInstance> {
+    let name = generate_container_name(&state.config)?;
+    let created_on = SystemTime::now();
+    let expires_at = created_on + state.config.instances.ttl();
+
+    let instance = SquittalInstance {
+        name: format!("/{}", container_name(&name)),
+        created_by: owner_id.to_string(),
+        created_on,
+        expires_at,
+        port: 0,
+        status: InstanceStatus::Provisioning,
+        ip: None,
+        failure: None,
+        warning: None,
+        limits: state.config.limits.instance_limits(),
+    };
 
-    let result = docker.list_containers(Some(container_filter)).await?;
-    //println!("{:?}", result);
+    state.index.clear_failed(owner_id);
+    state.index.insert_pending(&name, instance.clone());
+    state
+        .events
+        .publish(InstanceUpdate::new(UpdateKind::Creating, &name, owner_id));
+
+    let task_state = state.clone();
+    let owner_id = owner_id.to_string();
+    let username = username.to_string();
+    tokio::spawn(async move {
+        finish_provisioning(
+            &task_state,
+            &name,
+            &owner_id,
+            &username,
+            created_on,
+            expires_at,
+            snapshot,
+        )
+        .await;
+    });
+
+    return Ok(instance);
+}
 
-    let mut results: Vec<SquittalInstance> = vec![];
-    for ele in result {
-        results.push(SquittalInstance::from(ele));
-    }
+///
+/// where ink can reach a started container
+///
+struct ContainerAddress {
+    /// port published on the host, or 0 if ports are not published
+    port: u16,
 
-    return Ok(results);
+    /// IP of the container on the docker network
+    ip: Option<String>,
 }
 
-pub async fn get_instance_by_name(
+///
+/// create the container of an instance from [`provision_instance`], and record it in the DB
+///
+async fn finish_provisioning(
+    state: &AppState,
     name: &str,
-) -> Result<Vec<SquittalInstance>, bollard::errors::Error> {
-    let docker = bollard::Docker::connect_with_socket_defaults()?;
+    owner_id: &str,
+    username: &str,
+    created_on: SystemTime,
+    expires_at: SystemTime,
+    snapshot: Option<Snapshot>,
+) {
+    let db = &state.db;
+
+    if let Err(e) = db.upsert_owner(owner_id, username) {
+        tracing::error!("failed to record owner {}: {}", owner_id, e);
+    }
 
-    let container_filter: ListContainersOptions = ListContainersOptionsBuilder::new()
-        .filters(&HashMap::from([
-            ("ancestor", vec!["squittal"]),
-            ("name", vec![&format!("squittal-{name}").to_string()]),
-        ]))
-        .build();
+    let address = match create_container(state, name, owner_id, expires_at, snapshot).await {
+        Ok(address) => address,
+        Err(e) => {
+            let err = format!("cannot create new instance: {}", e);
+            tracing::error!(
+                "failed to create instance {} for {}: {}",
+                name,
+                owner_id,
+                err
+            );
+
+            mark_failed(state, name, owner_id, &err);
+            if let Err(e) =
+                db.record_event(None, Some(owner_id), InstanceEventKind::Failed, Some(&err))
+            {
+                tracing::error!("failed to record failed creation for {}: {}", owner_id, e);
+            }
+            return;
+        }
+    };
 
-    let result = docker.list_containers(Some(container_filter)).await?;
-    //println!("{:?}", result);
+    let port = address.port;
+    if let Err(e) = db.insert_instance(name, owner_id, port, created_on, expires_at) {
+        tracing::error!("failed to record instance {}: {}", name, e);
+    }
 
-    let mut results: Vec<SquittalInstance> = vec![];
-    for ele in result {
-        results.push(SquittalInstance::from(ele));
+    // still pending, so nothing is routed to it until squittal is ready
+    state.index.update_pending(name, |i| {
+        i.port = port;
+        i.ip = address.ip;
+    });
+
+    if let Err(e) = wait_until_ready(state, name).await {
+        let err = format!("instance never became ready: {}", e);
+        tracing::error!("instance {} of {} {}", name, owner_id, err);
+
+        mark_failed(state, name, owner_id, &err);
+        if let Err(e) = db.record_event(
+            Some(name),
+            Some(owner_id),
+            InstanceEventKind::Failed,
+            Some(&err),
+        ) {
+            tracing::error!("failed to record failed start of {}: {}", name, e);
+        }
+        if let Err(e) = remove_container(state, &container_name(name), "never became ready").await {
+            tracing::error!(
+                "failed to remove instance {} that never started: {}",
+                name,
+                e
+            );
+        }
+        return;
     }
 
-    return Ok(results);
+    // ready, so the container in the index takes over from here. if it is already gone, the instance was
+    // cancelled while being created, such as when the owner was banned
+    if state.index.remove_pending(name).is_none() {
+        tracing::info!(
+            "instance {} was cancelled while being created, removing it",
+            name
+        );
+        if let Err(e) = remove_container(
+            state,
+            &container_name(name),
+            "cancelled while being created",
+        )
+        .await
+        {
+            tracing::error!("failed to remove cancelled instance {}: {}", name, e);
+        }
+        return;
+    }
+
+    state
+        .events
+        .publish(InstanceUpdate::new(UpdateKind::Healthy, name, owner_id));
+    tracing::info!(
+        "created instance {} for {}/{} on port {}",
+        name,
+        owner_id,
+        username,
+        port
+    );
 }
 
 ///
-/// create a new container with a discord ID set as the owner (which is stored in a label under "created_by")
+/// mark an instance that is being created as failed. it is kept until the owner dismisses it or creates
+/// another, so they can see what went wrong
 ///
-pub async fn create_container(owner: &str) -> Result<(String, u16), Box<dyn Error>> {
-    let docker = bollard::Docker::connect_with_socket_defaults()?;
+fn mark_failed(state: &AppState, name: &str, owner_id: &str, err: &str) {
+    state.index.update_pending(name, |i| {
+        i.status = InstanceStatus::Failed;
+        i.failure = Some(err.to_string());
+    });
+    state
+        .events
+        .publish(InstanceUpdate::new(UpdateKind::Failed, name, owner_id).with_detail(err));
+}
 
-    let squittal_image_filter: ListImagesOptions = ListImagesOptionsBuilder::new()
-        .filters(&HashMap::from([("reference", vec!["squittal"])]))
-        .build();
-    match docker.list_images(Some(squittal_image_filter)).await {
-        Ok(image) => {
-            if image.len() != 1 {
-                panic!("missing 'squittal' image! is it built?");
-            }
-            println!("found squittal image: {:?}", image[0]);
+///
+/// wait until squittal in a newly started instance answers on the ready path without a server error, and
+/// docker reports the container as healthy if it has a health check. fails if the container stops, is
+/// unhealthy, or is not ready within the ready timeout. returns early if the instance is cancelled while
+/// waiting, which the caller finds out when removing it from the pending instances
+///
+async fn wait_until_ready(state: &AppState, instance_name: &str) -> Result<()> {
+    let config = &state.config.instances;
+    let Some(timeout) = config.ready_timeout() else {
+        return Ok(());
+    };
+
+    let container_name = container_name(instance_name);
+    let started = tokio::time::Instant::now();
+
+    loop {
+        if !state.index.is_pending(instance_name) {
+            return Ok(());
+        }
+
+        let inspect = state.backend.inspect_container(&container_name).await?;
+        let container = inspect.state.unwrap_or_default();
+        if container.running != Some(true) {
+            bail!("container stopped while starting");
+        }
+
+        let health = container.health.and_then(|h| h.status);
+        if health == Some(HealthStatusEnum::UNHEALTHY) {
+            bail!("docker reports the container as unhealthy");
+        }
+        let healthy = matches!(
+            health,
+            None | Some(HealthStatusEnum::EMPTY)
+                | Some(HealthStatusEnum::NONE)
+                | Some(HealthStatusEnum::HEALTHY)
+        );
+
+        let address = state
+            .index
+            .get(instance_name)
+            .and_then(|i| i.address(&state.config.docker));
+        if healthy
+            && let Some(address) = address
+            && is_answering(
+                state,
+                &format!("http://{}{}", address, config.ready_path),
+                config.ready_interval(),
+            )
+            .await
+        {
+            tracing::debug!(
+                "instance {} ready after {:?}",
+                instance_name,
+                started.elapsed()
+            );
+            return Ok(());
         }
+
+        if started.elapsed() >= timeout {
+            bail!("not ready after {}s", timeout.as_secs());
+        }
+
+        tokio::time::sleep(config.ready_interval()).await;
+    }
+}
+
+///
+/// if `uri` answers within `timeout` without a server error
+///
+async fn is_answering(state: &AppState, uri: &str, timeout: Duration) -> bool {
+    let request = match Request::get(uri).body(Body::empty()) {
+        Ok(request) => request,
         Err(e) => {
-            return Err(Box::new(e));
+            tracing::error!("invalid ready check uri {}: {}", uri, e);
+            return false;
+        }
+    };
+
+    return match tokio::time::timeout(timeout, state.client.request(request)).await {
+        Ok(Ok(response)) => !response.status().is_server_error(),
+        Ok(Err(e)) => {
+            tracing::trace!("ready check of {} failed: {}", uri, e);
+            false
+        }
+        Err(_) => {
+            tracing::trace!("ready check of {} timed out", uri);
+            false
         }
+    };
+}
+
+///
+/// create a new container for the instance `instance_name`, with a discord ID set as the owner (which is
+/// stored in a label under "created_by"), that expires at `expires_at` unless extended. the data volume
+/// is restored from `snapshot` before the container is started. returns where ink can reach the container
+///
+async fn create_container(
+    state: &AppState,
+    instance_name: &str,
+    owner: &str,
+    expires_at: SystemTime,
+    snapshot: Option<Snapshot>,
+) -> Result<ContainerAddress> {
+    let config = &state.config;
+    let image = config.docker.image.as_str();
+
+    if !state.backend.image_exists(image).await? {
+        bail!("missing '{image}' image! is it built?");
     }
 
-    let instance_name = generate_container_name();
-    let container_name: String = format!("squittal-{}", instance_name).to_string();
+    let container_name: String = container_name(instance_name);
     tracing::debug!("container name: {container_name}");
 
-    let builder: CreateContainerOptionsBuilder =
-        CreateContainerOptionsBuilder::new().name(&container_name);
+    let mut labels = HashMap::from([
+        ("created_by".to_string(), owner.to_string()),
+        ("ink_tag".to_string(), "true".to_string()),
+        ("expires_at".to_string(), unix_time(expires_at).to_string()),
+        ("ink_network".to_string(), config.docker.network.clone()),
+    ]);
 
-    let config = ContainerCreateBody {
-        image: Some("squittal".to_string()),
+    let limits = &config.limits;
+    labels.extend(limits.instance_limits().labels());
+
+    let volume = match config.docker.data_path.is_empty() {
+        true => None,
+        false => Some(volume_name(instance_name)),
+    };
+
+    let port_key = config.docker.port_key();
+    let mut body = ContainerCreateBody {
+        image: Some(image.to_string()),
         host_config: Some(HostConfig {
-            port_bindings: Some(HashMap::from([(
-                "8080/tcp".to_string(),
-                Some(vec![PortBinding {
-                    host_ip: Some("0.0.0.0".to_string()),
-                    host_port: None, // let Docker pick the port to use
-                }]),
-            )])),
-            network_mode: Some("ink".into()),
+            // otherwise ink reaches the container over the docker network, and nothing is published
+            port_bindings: (config.docker.route == RouteMode::Port).then(|| {
+                HashMap::from([(
+                    port_key.clone(),
+                    Some(vec![PortBinding {
+                        // only ink should reach the instance, so it cannot be used without the proxy
+                        host_ip: Some("127.0.0.1".to_string()),
+                        host_port: None, // let Docker pick the port to use
+                    }]),
+                )])
+            }),
+            network_mode: Some(config.docker.network.clone()),
+            binds: volume
+                .as_ref()
+                .map(|v| vec![format!("{v}:{}", config.docker.data_path)]),
+            // docker takes 0 as no limit for each of these
+            memory: Some(limits.memory_mb as i64 * 1024 * 1024),
+            nano_cpus: Some((limits.cpus * 1_000_000_000.0) as i64),
+            pids_limit: Some(limits.pids),
+            readonly_rootfs: Some(limits.read_only),
+            tmpfs: Some(limits.tmpfs_mounts()),
+            cap_drop: Some(limits.cap_drop.clone()),
+            security_opt: limits
+                .no_new_privileges
+                .then(|| vec!["no-new-privileges:true".to_string()]),
+            restart_policy: Some(RestartPolicy {
+                name: Some(match limits.restart_policy {
+                    config::RestartPolicy::No => RestartPolicyNameEnum::NO,
+                    config::RestartPolicy::OnFailure => RestartPolicyNameEnum::ON_FAILURE,
+                    config::RestartPolicy::UnlessStopped => RestartPolicyNameEnum::UNLESS_STOPPED,
+                    config::RestartPolicy::Always => RestartPolicyNameEnum::ALWAYS,
+                }),
+                ..Default::default()
+            }),
             ..Default::default()
         }),
-        exposed_ports: Some(HashMap::from([("8080/tcp".to_string(), HashMap::from([]))])),
-        labels: Some(HashMap::from([
-            ("created_by".to_string(), owner.to_string()),
-            ("ink_tag".to_string(), "true".to_string()),
-        ])),
+        exposed_ports: Some(HashMap::from([(port_key.clone(), HashMap::from([]))])),
+        labels: Some(labels),
+        // otherwise the healthcheck of the image is used, if it has one
+        healthcheck: match config.docker.healthcheck.is_empty() {
+            true => None,
+            false => Some(HealthConfig {
+                test: Some(config.docker.healthcheck.clone()),
+                interval: Some(
+                    Duration::from_secs(config.docker.healthcheck_interval_secs).as_nanos() as i64,
+                ),
+                ..Default::default()
+            }),
+        },
         ..Default::default()
     };
 
-    tracing::trace!("container options: {:?}", config);
+    // everything made from here on is cleaned up if a later step fails
+    let started = async {
+        // a database of its own, so instances cannot see each other's data
+        if let Some(databases) = &state.databases {
+            let login = databases
+                .create(instance_name)
+                .await
+                .context("failed to create database")?;
+
+            // mounted instead of put in an env var, which anyone who can inspect the container can see
+            let file = mssql::write_connection_file(&config.mssql, instance_name, &login)
+                .await
+                .context("failed to write connection string")?;
+            body.env = Some(vec![format!(
+                "{}_FILE={}",
+                config.mssql.connection_env, config.mssql.connection_file
+            )]);
+            if let Some(host_config) = body.host_config.as_mut() {
+                host_config.binds.get_or_insert_with(Vec::new).push(format!(
+                    "{}:{}:ro",
+                    file.display(),
+                    config.mssql.connection_file
+                ));
+            }
+            if let Some(labels) = body.labels.as_mut() {
+                labels.insert("ink_database".to_string(), login.database);
+            }
+        }
+
+        tracing::trace!("container options: {:?}", body);
+
+        if let Some(volume) = &volume {
+            let labels = HashMap::from([
+                ("created_by".to_string(), owner.to_string()),
+                ("ink_tag".to_string(), "true".to_string()),
+            ]);
+            state
+                .backend
+                .create_volume(volume, labels)
+                .await
+                .context("failed to create volume")?;
+        }
 
-    let container = docker
-        .create_container(Some(builder.build()), config)
-        .await?;
-    tracing::debug!("created container {}", container.id);
+        let container_id = state
+            .backend
+            .create_container(&container_name, body)
+            .await?;
+        tracing::debug!("created container {}", container_id);
 
-    docker
-        .start_container(&container_name, None::<StartContainerOptions>)
-        .await?;
+        if let Some(snapshot) = snapshot {
+            snapshots::restore(state, &container_name, &snapshot)
+                .await
+                .with_context(|| format!("failed to restore snapshot {}", snapshot.id))?;
+        }
+
+        return state.backend.start_container(&container_name).await;
+    }
+    .await;
+
+    if let Err(e) = started {
+        // clean up whatever was made before the failure. the container, volume or database may not exist,
+        // or may only be partly made
+        let _ = state.backend.remove_container(&container_name).await;
+        if let Some(volume) = &volume {
+            let _ = state.backend.remove_volume(volume).await;
+        }
+        remove_database(state, instance_name, false).await;
+        return Err(e);
+    }
     tracing::debug!("sucessfully started container {}", &container_name);
+    state
+        .index
+        .update_pending(instance_name, |i| i.status = InstanceStatus::Starting);
+    state.events.publish(InstanceUpdate::new(
+        UpdateKind::Started,
+        instance_name,
+        owner,
+    ));
 
     for i in 1..=5 {
         tracing::debug!(
-            "attempting to get container port for {}, try {}",
+            "attempting to get container address for {}, try {}",
             &container_name,
             i
         );
 
-        let inspect = docker
-            .inspect_container(&container_name, None::<InspectContainerOptions>)
-            .await?;
+        let inspect = match state.backend.inspect_container(&container_name).await {
+            Ok(inspect) => inspect,
+            Err(e) => {
+                tracing::error!(
+                    "failed to inspect container {}, killing container: {}",
+                    &container_name,
+                    e
+                );
+                kill_container(state, &container_name, "failed to inspect container").await;
+                return Err(e.context("failed to inspect container"));
+            }
+        };
+        let address = ContainerAddress {
+            ip: get_container_ip(&inspect, &config.docker.network),
+            port: get_container_port(inspect, &port_key).unwrap_or(0),
+        };
+
+        let reachable = match config.docker.route {
+            RouteMode::Port => address.port != 0,
+            RouteMode::Ip => address.ip.is_some(),
+            // known before the container starts, so the readiness check is what finds out if
+            // the container can be reached by it
+            RouteMode::Name => true,
+        };
+        if reachable {
+            tracing::debug!("got container address for {} on try {}", &container_name, i);
+            let mut update = InstanceUpdate::new(UpdateKind::PortAssigned, instance_name, owner);
+            if address.port != 0 {
+                update = update.with_port(address.port);
+            }
+            state.events.publish(update);
+
+            if let Err(e) = instance_index::refresh(state).await {
+                tracing::error!(
+                    "failed to refresh index after creating {}: {}",
+                    &container_name,
+                    e
+                );
+            }
 
-        let port = get_container_port(inspect);
-        if port.is_some() {
-            tracing::debug!("got container port for {} on try {}", &container_name, i);
-            return Ok((instance_name, port.unwrap()));
+            return Ok(address);
         }
 
-        tracing::warn!("failed to get port of container on try {}", i);
+        tracing::warn!("failed to get address of container on try {}", i);
         tokio::time::sleep(Duration::from_secs(1)).await;
     }
 
     tracing::error!(
-        "failed to get port of container {} after 5 tries, killing container",
+        "failed to get address of container {} after 5 tries, killing container",
         &container_name
     );
-    match remove_container(&container_name).await {
-        Ok(_) => {}
-        Err(err) => {
-            tracing::error!("failed to kill container: {}", err);
+    kill_container(state, &container_name, "failed to get address of container").await;
+
+    return Err(anyhow!("failed to get address of container"));
+}
+
+///
+/// remove a container that was started but cannot be used, along with its volume and database
+///
+async fn kill_container(state: &AppState, container_name: &str, reason: &str) {
+    if let Err(e) = remove_container(state, container_name, reason).await {
+        tracing::error!("failed to kill container {}: {}", container_name, e);
+    }
+}
+
+///
+/// remove a container, stopping the docker container and removing it. `reason` is kept in the DB
+///
+pub async fn remove_container(state: &AppState, name: &str, reason: &str) -> Result<()> {
+    let instance = instance_name(name);
+    let db = &state.db;
+    let indexed = state.index.get(instance);
+    let owner = indexed
+        .as_ref()
+        .map(|i| i.created_by.clone())
+        .unwrap_or_default();
+
+    // squittal has to still be running to export from it
+    if let Some(indexed) = &indexed
+        && indexed.status == InstanceStatus::Ready
+    {
+        exports::export_instance(state, indexed).await;
+    }
+
+    let result = stop_and_remove(state, name).await;
+    match &result {
+        Ok(_) => {
+            let archive = state.config.mssql.on_remove == RemoveAction::Archive;
+            remove_database(state, instance, archive).await;
+            if !state.config.docker.data_path.is_empty()
+                && let Err(e) = state.backend.remove_volume(&volume_name(instance)).await
+            {
+                tracing::error!("failed to remove volume of {}: {}", instance, e);
+            }
+
+            state.index.remove(instance);
+            state.activity.forget(instance);
+            state.events.publish(
+                InstanceUpdate::new(UpdateKind::Removed, instance, &owner).with_detail(reason),
+            );
         }
+        Err(e) => state.events.publish(
+            InstanceUpdate::new(UpdateKind::Failed, instance, &owner)
+                .with_detail(&format!("failed to remove ({reason}): {e}")),
+        ),
+    }
+
+    let db_result = match &result {
+        Ok(_) => db.mark_removed(instance, reason),
+        Err(e) => db.record_event(
+            Some(instance),
+            None,
+            InstanceEventKind::Failed,
+            Some(&format!("failed to remove ({reason}): {e}")),
+        ),
+    };
+
+    if let Err(e) = db_result {
+        tracing::error!("failed to record removal of {}: {}", instance, e);
     }
 
-    return Err("failed to get port of container".into());
+    return result;
 }
 
 ///
-/// remove a container, stopping the docker container and removing it
+/// drop the database of an instance and remove its connection string, if it has one, backing the database
+/// up first if `archive` is set. a failure is logged and kept in the DB, but does not stop the instance
+/// from being removed
 ///
-pub async fn remove_container(name: &str) -> Result<(), bollard::errors::Error> {
-    let docker = bollard::Docker::connect_with_socket_defaults()?;
+async fn remove_database(state: &AppState, instance: &str, archive: bool) {
+    let Some(databases) = &state.databases else {
+        return;
+    };
+
+    if let Err(e) = mssql::remove_connection_file(&state.config.mssql, instance).await {
+        tracing::error!("failed to remove connection string of {}: {}", instance, e);
+    }
 
+    if let Err(e) = databases.remove(instance, archive).await {
+        tracing::error!("failed to remove database of {}: {}", instance, e);
+        if let Err(e) = state.db.record_event(
+            Some(instance),
+            None,
+            InstanceEventKind::Failed,
+            Some(&format!("failed to remove database: {e}")),
+        ) {
+            tracing::error!("failed to record database removal of {}: {}", instance, e);
+        }
+    }
+}
+
+async fn stop_and_remove(state: &AppState, name: &str) -> Result<()> {
     tracing::info!("stopping container {}", name);
-    docker
-        .stop_container(name, None::<StopContainerOptions>)
+    state
+        .backend
+        .stop_container(name, state.config.instances.stop_grace())
         .await?;
 
     tracing::info!("removing container {}", name);
-    docker
-        .remove_container(name, None::<RemoveContainerOptions>)
-        .await?;
+    state.backend.remove_container(name).await?;
 
     return Ok(());
 }
 
+///
+/// get the container name of an instance
+///
+pub fn container_name(instance_name: &str) -> String {
+    return format!("squittal-{instance_name}");
+}
+
+///
+/// get the name of the data volume of an instance
+///
+pub fn volume_name(instance_name: &str) -> String {
+    return format!("{}-data", container_name(instance_name));
+}
+
+///
+/// get the instance name (what is used as the subdomain) from a container name,
+/// which may have the leading / that docker puts on names
+///
+pub fn instance_name(container_name: &str) -> &str {
+    let name = container_name.strip_prefix("/").unwrap_or(container_name);
+    return name.strip_prefix("squittal-").unwrap_or(name);
+}
+
 /**
  * generate a random container name based on the word lists
  */
-fn generate_container_name() -> String {
-    let first_words: Vec<String> = std::fs::read_to_string("first_word_list.txt")
-        .unwrap()
-        .lines()
-        .map(String::from)
-        .collect();
+fn generate_container_name(config: &Config) -> Result<String> {
+    let first_word = random_word(&config.instances.first_word_list)?;
+    let second_word = random_word(&config.instances.second_word_list)?;
+
+    return Ok(format!("{first_word}-{second_word}"));
+}
 
-    let second_words: Vec<String> = std::fs::read_to_string("second_word_list.txt")
-        .unwrap()
+///
+/// pick a random word from the word list at `path`. the lists are checked when ink starts, but are read
+/// each time, so they can still be changed or removed while ink is running
+///
+fn random_word(path: &str) -> Result<String> {
+    let words = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read word list {path}"))?;
+    let words: Vec<&str> = words
         .lines()
-        .map(String::from)
+        .map(str::trim)
+        .filter(|w| !w.is_empty())
         .collect();
 
-    let first_word = &first_words[rand::rng().random_range(0..first_words.len())];
-    let second_word = &second_words[rand::rng().random_range(0..second_words.len())];
+    if words.is_empty() {
+        bail!("word list {path} is empty");
+    }
 
-    return format!("{first_word}-{second_word}");
+    return Ok(words[rand::rng().random_range(0..words.len())].to_string());
 }
 
 ///
-/// get the host port a container is listening to 8080/tcp, which is the port used by the squittal image
+/// get the IP of the container on the docker network `network`
 ///
-fn get_container_port(inspect: ContainerInspectResponse) -> Option<u16> {
+fn get_container_ip(inspect: &ContainerInspectResponse, network: &str) -> Option<String> {
+    return inspect
+        .network_settings
+        .as_ref()?
+        .networks
+        .as_ref()?
+        .get(network)?
+        .ip_address
+        .clone()
+        .filter(|ip| !ip.is_empty());
+}
+
+///
+/// get the host port bound to `port_key` (such as 8080/tcp, which is the port used by the squittal image)
+///
+fn get_container_port(inspect: ContainerInspectResponse, port_key: &str) -> Option<u16> {
     let port = inspect
         .network_settings?
         .ports?
-        .get("8080/tcp")?
+        .get(port_key)?
         .as_ref()?
         .first()?
         .clone()
         .host_port?;
 
-    match port.parse() {
-        Ok(n) => Some(n),
-        Err(_) => None,
+    return port.parse().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fake_backend::{FakeBackend, FakeFailure},
+        fake_mssql::FakeMssql,
+        test_util,
+    };
+
+    ///
+    /// state where instances have a second to become ready
+    ///
+    async fn state(config: Option<Config>) -> (AppState, std::sync::Arc<FakeBackend>) {
+        let mut config = config.unwrap_or_default();
+        config.instances.ready_timeout_secs = 1;
+        config.instances.ready_interval_secs = 1;
+        return test_util::state_with_backend(config).await;
+    }
+
+    ///
+    /// start creating an instance for `owner`, and wait until creating it has failed
+    ///
+    async fn failed_instance(state: &AppState, owner: &str) -> SquittalInstance {
+        let instance = provision_instance(state, owner, "user", None).unwrap();
+        let name = instance_name(&instance.name).to_string();
+
+        let wait = async {
+            loop {
+                if let Some(instance) = state.index.get(&name)
+                    && instance.status == InstanceStatus::Failed
+                {
+                    return instance;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        return tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .expect("instance did not fail in time");
+    }
+
+    ///
+    /// check a failed instance is kept for its owner to see, without counting towards the max
+    /// instances, and that nothing of it is left in docker
+    ///
+    async fn assert_cleaned_up(
+        state: &AppState,
+        backend: &FakeBackend,
+        instance: &SquittalInstance,
+        failure: &str,
+    ) {
+        let reason = instance.failure.as_deref().unwrap_or_default();
+        assert!(reason.contains(failure), "failed with {reason}");
+        assert!(state.index.active_by_owner(&instance.created_by).is_empty());
+        assert_eq!(state.index.len(), 0);
+
+        // removing the container happens after the instance is marked failed
+        let removed = async {
+            while !backend.container_names().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), removed)
+            .await
+            .expect("container was not removed");
+    }
+
+    #[tokio::test]
+    async fn create_fails() {
+        let (state, backend) = state(None).await;
+        backend.fail(FakeFailure::Create);
+
+        let instance = failed_instance(&state, "1").await;
+        assert_cleaned_up(&state, &backend, &instance, "cannot create new instance").await;
+    }
+
+    #[tokio::test]
+    async fn start_fails() {
+        let (state, backend) = state(None).await;
+        backend.fail(FakeFailure::Start);
+
+        let instance = failed_instance(&state, "1").await;
+        assert_cleaned_up(&state, &backend, &instance, "cannot create new instance").await;
+    }
+
+    #[tokio::test]
+    async fn inspect_fails() {
+        let (state, backend) = state(None).await;
+        backend.fail(FakeFailure::Inspect);
+
+        let instance = failed_instance(&state, "1").await;
+        assert_cleaned_up(&state, &backend, &instance, "failed to inspect container").await;
+    }
+
+    #[tokio::test]
+    async fn never_ready() {
+        let (state, backend) = state(None).await;
+        backend.fail(FakeFailure::Answer);
+
+        let instance = failed_instance(&state, "1").await;
+        assert_cleaned_up(&state, &backend, &instance, "never became ready").await;
+    }
+
+    #[tokio::test]
+    async fn name_route_is_checked() {
+        let mut config = Config::default();
+        config.docker.route = RouteMode::Name;
+        let (state, backend) = state(Some(config)).await;
+
+        // names of fake containers do not resolve, so they are never ready
+        let instance = failed_instance(&state, "1").await;
+        assert_cleaned_up(&state, &backend, &instance, "never became ready").await;
+    }
+
+    ///
+    /// state where each instance gets a fake database, with connection strings written to `secrets`
+    ///
+    async fn state_with_databases(
+        secrets: &test_util::TempDir,
+    ) -> (
+        AppState,
+        std::sync::Arc<FakeBackend>,
+        std::sync::Arc<FakeMssql>,
+    ) {
+        let mut config = Config::default();
+        config.mssql.backend = config::MssqlBackendKind::Fake;
+        config.mssql.secrets_dir = secrets.join("secrets");
+        let (mut state, backend) = state(Some(config)).await;
+
+        let databases = std::sync::Arc::new(FakeMssql::new());
+        state.databases = Some(databases.clone());
+        return (state, backend, databases);
+    }
+
+    #[tokio::test]
+    async fn database_per_instance() {
+        let secrets = test_util::TempDir::new();
+        let (state, _, databases) = state_with_databases(&secrets).await;
+        let mssql = &state.config.mssql;
+
+        let name = test_util::running_instance(&state, "1").await;
+        assert!(databases.exists(&name));
+
+        // the connection string is mounted from a file, and only its path is in the env
+        let inspect = state
+            .backend
+            .inspect_container(&container_name(&name))
+            .await
+            .unwrap();
+        let env = inspect.config.unwrap().env.unwrap();
+        assert_eq!(
+            env,
+            vec![format!(
+                "{}_FILE={}",
+                mssql.connection_env, mssql.connection_file
+            )]
+        );
+
+        let file = std::fs::canonicalize(secrets.path().join("secrets").join(&name)).unwrap();
+        let binds = inspect.host_config.unwrap().binds.unwrap();
+        assert!(binds.contains(&format!("{}:{}:ro", file.display(), mssql.connection_file)));
+
+        let connection = std::fs::read_to_string(&file).unwrap();
+        assert!(connection.contains(&format!("Database={}", mssql::database_name(&name))));
+        assert!(connection.contains("Password=fake"));
+
+        remove_container(&state, &container_name(&name), "test")
+            .await
+            .unwrap();
+        assert!(!databases.exists(&name));
+        assert!(!file.exists());
+    }
+
+    #[tokio::test]
+    async fn database_removed_when_creation_fails() {
+        let secrets = test_util::TempDir::new();
+        let (state, backend, databases) = state_with_databases(&secrets).await;
+        backend.fail(FakeFailure::Start);
+
+        let instance = failed_instance(&state, "1").await;
+        assert_cleaned_up(&state, &backend, &instance, "cannot create new instance").await;
+
+        let name = instance_name(&instance.name);
+        assert!(!databases.exists(name));
+        assert!(!secrets.path().join("secrets").join(name).exists());
+    }
+
+    #[tokio::test]
+    async fn database_removed_when_never_ready() {
+        let secrets = test_util::TempDir::new();
+        let (state, backend, databases) = state_with_databases(&secrets).await;
+        backend.fail(FakeFailure::Answer);
+
+        let instance = failed_instance(&state, "1").await;
+        assert_cleaned_up(&state, &backend, &instance, "never became ready").await;
+
+        let name = instance_name(&instance.name);
+        assert!(!databases.exists(name));
+        assert!(!secrets.path().join("secrets").join(name).exists());
+    }
+
+    #[tokio::test]
+    async fn ready() {
+        let (state, backend) = state(None).await;
+
+        let name = test_util::running_instance(&state, "1").await;
+        let instance = state.index.get(&name).unwrap();
+        assert_eq!(instance.status, InstanceStatus::Ready);
+        assert_eq!(backend.container_names(), vec![container_name(&name)]);
     }
 }
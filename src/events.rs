@@ -0,0 +1,386 @@
+use std::{convert::Infallible, time::SystemTime};
+
+use axum::{
+    extract::State,
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures_util::stream;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use utoipa::ToSchema;
+
+use crate::{
+    User,
+    api_error::{ApiError, ErrorBody},
+    app_state::AppState,
+    roles::{self, Role},
+};
+
+/// how many updates can pile up for a slow subscriber before it starts missing them
+const CHANNEL_SIZE: usize = 256;
+
+///
+/// something that happened to an instance. the SSE event name is the same as `kind`
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateKind {
+    /// ink is creating the container
+    Creating,
+
+    /// the container was started
+    Started,
+
+    /// the container has a port, and can be reached through ink
+    PortAssigned,
+
+    /// squittal answered its ready check, and passed the docker health check if it has one. the
+    /// instance can be used now
+    Healthy,
+
+    /// the instance crossed one of the warning thresholds before it expires
+    Expiring,
+
+    /// the instance was removed, such as by the owner or because it expired
+    Removed,
+
+    /// creating or removing the instance failed
+    Failed,
+}
+
+impl UpdateKind {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            UpdateKind::Creating => "creating",
+            UpdateKind::Started => "started",
+            UpdateKind::PortAssigned => "port_assigned",
+            UpdateKind::Healthy => "healthy",
+            UpdateKind::Expiring => "expiring",
+            UpdateKind::Removed => "removed",
+            UpdateKind::Failed => "failed",
+        };
+    }
+}
+
+///
+/// an update sent to everyone listening on /api/v1/events. updates about instances owned by someone else
+/// have the instance name, owner, port and detail removed
+///
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct InstanceUpdate {
+    pub kind: UpdateKind,
+
+    /// name of the instance, empty if creating it failed before it had one
+    pub instance: String,
+
+    /// ID of the user who owns the instance, empty if it is owned by someone else
+    pub owner: String,
+
+    #[schema(value_type = crate::openapi::Timestamp)]
+    pub at: SystemTime,
+
+    /// set when the port is assigned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// set when the instance is expiring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<crate::openapi::Timestamp>)]
+    pub expires_at: Option<SystemTime>,
+
+    /// more about what happened, such as why the instance was removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl InstanceUpdate {
+    pub fn new(kind: UpdateKind, instance: &str, owner: &str) -> Self {
+        return InstanceUpdate {
+            kind,
+            instance: instance.to_string(),
+            owner: owner.to_string(),
+            at: SystemTime::now(),
+            port: None,
+            expires_at: None,
+            detail: None,
+        };
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        return self;
+    }
+
+    pub fn with_expires_at(mut self, expires_at: SystemTime) -> Self {
+        self.expires_at = Some(expires_at);
+        return self;
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        return self;
+    }
+
+    ///
+    /// this update as seen by someone who does not own the instance
+    ///
+    fn redacted(mut self) -> Self {
+        self.instance = "".to_string();
+        self.owner = "".to_string();
+        self.port = None;
+        self.detail = None;
+        return self;
+    }
+}
+
+///
+/// sends instance updates to everyone listening. updates sent while nobody is listening are dropped
+///
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<InstanceUpdate>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_SIZE);
+        return EventBus { sender };
+    }
+
+    pub fn publish(&self, update: InstanceUpdate) {
+        tracing::debug!(
+            "instance {} of {}: {}",
+            &update.instance,
+            &update.owner,
+            update.kind.as_str()
+        );
+
+        // an error only means nothing is listening
+        let _ = self.sender.send(update);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<InstanceUpdate> {
+        return self.sender.subscribe();
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        return EventBus::new();
+    }
+}
+
+///
+/// stream of updates to instances, as server-sent events. updates about the user's own instances have all
+/// the details, others are redacted unless the user is an admin. if the stream falls behind, a `lagged`
+/// event is sent, and the client should reload what it has. the stream is closed once the user is banned
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/events",
+    tag = "instances",
+    responses(
+        (status = 200, description = "stream of server-sent events, each with an update as the data", content_type = "text/event-stream", body = InstanceUpdate),
+        (status = 401, description = "not logged in", body = ErrorBody),
+    )
+)]
+pub async fn stream_events(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let receiver = state.events.subscribe();
+
+    let events = stream::unfold(receiver, move |mut receiver| {
+        let state = state.clone();
+        let user_id = user.id.clone();
+        async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(update) => {
+                        // checked for each update, so a user who is banned or made an admin after
+                        // subscribing gets what their role allows now
+                        let role = match roles::role_of(&state.config, &state.db, &user_id) {
+                            Ok(role) => role,
+                            Err(e) => {
+                                tracing::error!("failed to get role of {}: {}", user_id, e);
+                                return None;
+                            }
+                        };
+
+                        let update = match role {
+                            Role::Banned => {
+                                tracing::info!("closing event stream of banned user {}", user_id);
+                                return None;
+                            }
+                            Role::Admin => update,
+                            Role::User if update.owner == user_id => update,
+                            Role::User => update.redacted(),
+                        };
+
+                        Event::default()
+                            .event(update.kind.as_str())
+                            .json_data(&update)
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        Ok(Event::default().event("lagged").data(missed.to_string()))
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+
+                match event {
+                    Ok(event) => return Some((Ok::<Event, Infallible>(event), receiver)),
+                    Err(e) => tracing::error!("failed to serialize instance update: {}", e),
+                }
+            }
+        }
+    });
+
+    return Ok(Sse::new(events).keep_alive(KeepAlive::default()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::BodyDataStream;
+    use futures_util::StreamExt;
+
+    use crate::config::Config;
+    use crate::test_util;
+
+    use super::*;
+
+    async fn subscribe(state: &AppState, user: &str) -> BodyDataStream {
+        let response = stream_events(State(state.clone()), test_util::user(user))
+            .await
+            .unwrap()
+            .into_response();
+        return response.into_body().into_data_stream();
+    }
+
+    ///
+    /// the name and data of the next event on `events`, or None once the stream is closed
+    ///
+    async fn next_event(events: &mut BodyDataStream) -> Option<(String, String)> {
+        let mut text = String::new();
+        while !text.ends_with("\n\n") {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), events.next())
+                .await
+                .unwrap()?
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        let field = |name: &str| {
+            return text
+                .lines()
+                .find_map(|l| l.strip_prefix(name))
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+        };
+        return Some((field("event:"), field("data:")));
+    }
+
+    fn update(owner: &str) -> InstanceUpdate {
+        return InstanceUpdate::new(UpdateKind::PortAssigned, "abc-def", owner).with_port(1234);
+    }
+
+    #[tokio::test]
+    async fn redacted_for_others() {
+        let state = test_util::state(Config::default()).await;
+        let mut owner = subscribe(&state, "1").await;
+        let mut other = subscribe(&state, "2").await;
+
+        state
+            .events
+            .publish(update("1").with_detail("removed by owner"));
+
+        let (kind, data) = next_event(&mut owner).await.unwrap();
+        assert_eq!(kind, "port_assigned");
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["instance"], "abc-def");
+        assert_eq!(data["owner"], "1");
+        assert_eq!(data["port"], 1234);
+        assert_eq!(data["detail"], "removed by owner");
+
+        // others only learn that something happened to an instance
+        let (kind, data) = next_event(&mut other).await.unwrap();
+        assert_eq!(kind, "port_assigned");
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["kind"], "port_assigned");
+        assert_eq!(data["instance"], "");
+        assert_eq!(data["owner"], "");
+        assert!(data.get("port").is_none());
+        assert!(data.get("detail").is_none());
+    }
+
+    #[tokio::test]
+    async fn not_redacted_for_admins() {
+        let state = test_util::state(Config::default()).await;
+        state.db.set_role("9", Role::Admin, None, "9").unwrap();
+        let mut events = subscribe(&state, "9").await;
+
+        state.events.publish(update("1"));
+        let (_, data) = next_event(&mut events).await.unwrap();
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["instance"], "abc-def");
+        assert_eq!(data["owner"], "1");
+        assert_eq!(data["port"], 1234);
+    }
+
+    #[tokio::test]
+    async fn lagged() {
+        let state = test_util::state(Config::default()).await;
+        let mut events = subscribe(&state, "1").await;
+
+        // nothing is read until all of these are sent, so the oldest are dropped
+        for _ in 0..CHANNEL_SIZE + 10 {
+            state.events.publish(update("1"));
+        }
+
+        let (kind, data) = next_event(&mut events).await.unwrap();
+        assert_eq!(kind, "lagged");
+        assert_eq!(data, "10");
+
+        // the updates that were kept still follow
+        let (kind, _) = next_event(&mut events).await.unwrap();
+        assert_eq!(kind, "port_assigned");
+    }
+
+    #[tokio::test]
+    async fn closed_when_banned() {
+        let state = test_util::state(Config::default()).await;
+        let mut events = subscribe(&state, "2").await;
+
+        state.events.publish(update("2"));
+        let (kind, _) = next_event(&mut events).await.unwrap();
+        assert_eq!(kind, "port_assigned");
+
+        state.db.set_role("2", Role::Banned, None, "1").unwrap();
+        state.events.publish(update("2"));
+        assert!(next_event(&mut events).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn role_checked_for_each_update() {
+        let state = test_util::state(Config::default()).await;
+        let mut events = subscribe(&state, "2").await;
+
+        state.events.publish(update("1"));
+        let (_, data) = next_event(&mut events).await.unwrap();
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["instance"], "");
+
+        // made an admin after subscribing, so later updates are not redacted
+        state.db.set_role("2", Role::Admin, None, "1").unwrap();
+        state.events.publish(update("1"));
+        let (_, data) = next_event(&mut events).await.unwrap();
+        let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["instance"], "abc-def");
+        assert_eq!(data["port"], 1234);
+    }
+}
@@ -0,0 +1,535 @@
+use std::{
+    path::{Path as FsPath, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    response::IntoResponse,
+};
+use futures_util::StreamExt;
+use http::{Request, StatusCode, header};
+use rand::Rng;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    User,
+    api_error::{ApiError, ErrorBody},
+    app_state::AppState,
+    db::{Export, unix_time},
+    docker,
+    instance::SquittalInstance,
+};
+
+/// largest endpoint response or file tar that is saved. anything bigger is not exported at all
+const MAX_EXPORT_SIZE: usize = 64 * 1024 * 1024;
+
+///
+/// where an export was saved from
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportKind {
+    /// the response of an endpoint on squittal
+    Endpoint,
+
+    /// a tar of a file or directory in the container
+    File,
+}
+
+impl ExportKind {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            ExportKind::Endpoint => "endpoint",
+            ExportKind::File => "file",
+        };
+    }
+
+    pub fn parse(kind: &str) -> Result<ExportKind> {
+        return match kind {
+            "endpoint" => Ok(ExportKind::Endpoint),
+            "file" => Ok(ExportKind::File),
+            _ => Err(anyhow!("unknown export kind '{kind}'")),
+        };
+    }
+}
+
+///
+/// save the configured endpoints and files of an instance, before it is removed. failures are logged,
+/// and never stop the instance from being removed
+///
+pub async fn export_instance(state: &AppState, instance: &SquittalInstance) {
+    let config = &state.config.exports;
+    if !config.enabled() {
+        return;
+    }
+
+    let name = docker::instance_name(&instance.name);
+    let owner = &instance.created_by;
+    tracing::info!("exporting from {} before it is removed", name);
+
+    let address = instance.address(&state.config.docker);
+    for endpoint in &config.endpoints {
+        let result = match &address {
+            Some(address) => {
+                match tokio::time::timeout(config.timeout(), fetch(state, address, endpoint)).await
+                {
+                    Ok(result) => result,
+                    Err(_) => Err(anyhow!("timed out")),
+                }
+            }
+            None => Err(anyhow!("instance has no address")),
+        };
+
+        let saved = match result {
+            Ok(body) => save(state, owner, name, ExportKind::Endpoint, endpoint, &body).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            tracing::error!("failed to export {} from {}: {}", endpoint, name, e);
+        }
+    }
+
+    let container_name = docker::container_name(name);
+    for path in &config.files {
+        let result =
+            match tokio::time::timeout(config.timeout(), download(state, &container_name, path))
+                .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("timed out")),
+            };
+
+        let saved = match result {
+            Ok(tar) => save(state, owner, name, ExportKind::File, path, &tar).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            tracing::error!("failed to export {} from {}: {}", path, name, e);
+        }
+    }
+
+    if let Err(e) = prune(state, owner).await {
+        tracing::error!("failed to delete old exports of {}: {}", owner, e);
+    }
+}
+
+///
+/// get the body of `endpoint` on the instance at `address`
+///
+async fn fetch(state: &AppState, address: &str, endpoint: &str) -> Result<Vec<u8>> {
+    let request = Request::get(format!("http://{address}{endpoint}")).body(Body::empty())?;
+    let response = state.client.request(request).await?;
+    if !response.status().is_success() {
+        bail!("got status {}", response.status());
+    }
+
+    let body = axum::body::to_bytes(Body::new(response.into_body()), MAX_EXPORT_SIZE).await?;
+    return Ok(body.to_vec());
+}
+
+///
+/// get `path` in the container as a tar, stopping once it is bigger than [`MAX_EXPORT_SIZE`]
+///
+async fn download(state: &AppState, container_name: &str, path: &str) -> Result<Vec<u8>> {
+    let mut tar = state.backend.download_archive(container_name, path);
+    let mut data = Vec::new();
+    while let Some(chunk) = tar.next().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > MAX_EXPORT_SIZE {
+            bail!("larger than {} bytes", MAX_EXPORT_SIZE);
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    return Ok(data);
+}
+
+///
+/// write an export to the directory of its owner, and record it in the DB
+///
+async fn save(
+    state: &AppState,
+    owner_id: &str,
+    instance: &str,
+    kind: ExportKind,
+    source: &str,
+    data: &[u8],
+) -> Result<()> {
+    // owner IDs can have characters such as : in them
+    let owner_dir: String = owner_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let file = format!(
+        "{owner_dir}/{instance}-{}-{:08x}",
+        unix_time(SystemTime::now()),
+        rand::rng().random::<u32>()
+    );
+
+    let path = file_path(state, &file);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context("failed to create export directory")?;
+    }
+    tokio::fs::write(&path, data)
+        .await
+        .context("failed to save export")?;
+
+    if let Err(e) =
+        state
+            .db
+            .insert_export(owner_id, instance, kind, source, &file, data.len() as i64)
+    {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(e);
+    }
+
+    tracing::info!(
+        "exported {} from {} for {} ({} bytes)",
+        source,
+        instance,
+        owner_id,
+        data.len()
+    );
+    return Ok(());
+}
+
+///
+/// delete the oldest exports of a user until they are at the max they can keep
+///
+async fn prune(state: &AppState, owner_id: &str) -> Result<()> {
+    let exports = state.db.exports_by_owner(owner_id)?;
+    let extra = exports
+        .len()
+        .saturating_sub(state.config.exports.max_per_user);
+
+    for export in exports.into_iter().take(extra) {
+        remove(state, &export, owner_id).await?;
+    }
+
+    return Ok(());
+}
+
+async fn remove(state: &AppState, export: &Export, owner_id: &str) -> Result<()> {
+    state.db.delete_export(export.id, owner_id)?;
+    if let Err(e) = tokio::fs::remove_file(file_path(state, &export.file)).await {
+        tracing::error!("failed to delete export file {}: {}", export.file, e);
+    }
+
+    return Ok(());
+}
+
+fn file_path(state: &AppState, file: &str) -> PathBuf {
+    return FsPath::new(&state.config.exports.dir).join(file);
+}
+
+///
+/// name an export is downloaded as, such as {instance}-results.json
+///
+fn download_name(export: &Export) -> String {
+    let source = export.source.split('?').next().unwrap_or_default();
+    let base = source
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|s| !s.is_empty())
+        .unwrap_or("export");
+
+    return match export.kind {
+        ExportKind::Endpoint => format!("{}-{}", export.instance, base),
+        ExportKind::File => format!("{}-{}.tar", export.instance, base),
+    };
+}
+
+///
+/// list what was saved from the user's instances before they were removed
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/exports",
+    tag = "exports",
+    responses(
+        (status = 200, description = "the exports of the user, oldest first", body = Vec<Export>),
+        (status = 401, description = "not logged in", body = ErrorBody),
+    )
+)]
+pub async fn list_exports(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    return Ok(Json(state.db.exports_by_owner(&user.id)?));
+}
+
+///
+/// download an export of the user
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/exports/{id}/download",
+    tag = "exports",
+    params(("id" = i64, Path, description = "ID of the export")),
+    responses(
+        (status = 200, description = "what was exported, as it was saved. files are a tar", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 404, description = "the export does not exist", body = ErrorBody),
+    )
+)]
+pub async fn download_export(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(export) = state.db.get_export(id, &user.id)? else {
+        return Err(ApiError::NotFound(format!("export {id} does not exist")));
+    };
+
+    let data = tokio::fs::read(file_path(&state, &export.file))
+        .await
+        .context("failed to read export")?;
+
+    let content_type = match export.kind {
+        ExportKind::Endpoint => "application/octet-stream",
+        ExportKind::File => "application/x-tar",
+    };
+
+    return Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", download_name(&export)),
+            ),
+        ],
+        data,
+    ));
+}
+
+///
+/// delete an export of the user
+///
+#[utoipa::path(
+    delete,
+    path = "/api/v1/exports/{id}",
+    tag = "exports",
+    params(("id" = i64, Path, description = "ID of the export")),
+    responses(
+        (status = 204, description = "the export was deleted"),
+        (status = 404, description = "the export does not exist", body = ErrorBody),
+    )
+)]
+pub async fn delete_export(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(export) = state.db.get_export(id, &user.id)? else {
+        return Err(ApiError::NotFound(format!("export {id} does not exist")));
+    };
+
+    remove(&state, &export, &user.id).await?;
+
+    tracing::info!("{}/{} deleted export {}", &user.id, &user.username, id);
+    return Ok(StatusCode::NO_CONTENT);
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+
+    use crate::config::Config;
+    use crate::test_util::{self, TempDir};
+
+    use super::*;
+
+    fn config(dir: &TempDir, endpoints: &[&str], files: &[&str]) -> Config {
+        let mut config = Config::default();
+        config.exports.dir = dir.join("exports");
+        config.exports.endpoints = endpoints.iter().map(|e| e.to_string()).collect();
+        config.exports.files = files.iter().map(|f| f.to_string()).collect();
+        return config;
+    }
+
+    async fn state(dir: &TempDir, endpoints: &[&str], files: &[&str]) -> AppState {
+        return test_util::state(config(dir, endpoints, files)).await;
+    }
+
+    ///
+    /// make `data` what is downloaded from any path in the instance `name`
+    ///
+    async fn fill(state: &AppState, name: &str, data: Vec<u8>) {
+        let tar = futures_util::stream::iter([Ok(Bytes::from(data))]).boxed();
+        state
+            .backend
+            .upload_archive(&docker::container_name(name), "/", tar)
+            .await
+            .unwrap();
+    }
+
+    async fn export(state: &AppState, name: &str) {
+        let instance = state.index.get(name).unwrap();
+        export_instance(state, &instance).await;
+    }
+
+    fn saved(state: &AppState, export: &Export) -> Vec<u8> {
+        return std::fs::read(file_path(state, &export.file)).unwrap();
+    }
+
+    fn export_of(kind: ExportKind, source: &str) -> Export {
+        return Export {
+            id: 1,
+            instance: "abc-def".to_string(),
+            kind,
+            source: source.to_string(),
+            size: 0,
+            created_on: SystemTime::now(),
+            file: String::new(),
+        };
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            download_name(&export_of(ExportKind::Endpoint, "/api/results.json?all=1")),
+            "abc-def-results.json"
+        );
+        assert_eq!(
+            download_name(&export_of(ExportKind::File, "/app/results/")),
+            "abc-def-results.tar"
+        );
+        assert_eq!(
+            download_name(&export_of(ExportKind::Endpoint, "/")),
+            "abc-def-export"
+        );
+    }
+
+    #[tokio::test]
+    async fn endpoints_and_files() {
+        let dir = TempDir::new();
+        let state = state(&dir, &["/results"], &["/app/results"]).await;
+        let name = test_util::running_instance(&state, "1").await;
+        fill(&state, &name, vec![7; 1500]).await;
+
+        export(&state, &name).await;
+
+        let exports = state.db.exports_by_owner("1").unwrap();
+        assert_eq!(exports.len(), 2);
+
+        assert_eq!(exports[0].kind, ExportKind::Endpoint);
+        assert_eq!(exports[0].source, "/results");
+        assert_eq!(exports[0].instance, name);
+        let page = format!("fake squittal instance {}", docker::container_name(&name));
+        assert_eq!(saved(&state, &exports[0]), page.as_bytes());
+        assert_eq!(exports[0].size, page.len() as i64);
+
+        assert_eq!(exports[1].kind, ExportKind::File);
+        assert_eq!(exports[1].source, "/app/results");
+        assert_eq!(saved(&state, &exports[1]), vec![7; 1500]);
+        assert_eq!(exports[1].size, 1500);
+
+        // kept under the owner, and only they can get them
+        assert!(exports[1].file.starts_with("1/"));
+        assert!(state.db.exports_by_owner("2").unwrap().is_empty());
+
+        let result = download_export(
+            State(state.clone()),
+            Path(exports[1].id),
+            test_util::user("2"),
+        )
+        .await;
+        assert!(matches!(result.err(), Some(ApiError::NotFound(_))));
+
+        let response = download_export(
+            State(state.clone()),
+            Path(exports[1].id),
+            test_util::user("1"),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-tar"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, vec![7; 1500]);
+    }
+
+    #[tokio::test]
+    async fn too_large() {
+        let dir = TempDir::new();
+        let state = state(&dir, &["/results"], &["/app/results"]).await;
+        let name = test_util::running_instance(&state, "1").await;
+        fill(&state, &name, vec![0; MAX_EXPORT_SIZE + 1]).await;
+
+        export(&state, &name).await;
+
+        // the file is not saved, but that does not stop the endpoint from being saved
+        let exports = state.db.exports_by_owner("1").unwrap();
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].kind, ExportKind::Endpoint);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("exports/1"))
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn oldest_pruned() {
+        let dir = TempDir::new();
+        let mut config = config(&dir, &["/a", "/b", "/c"], &[]);
+        config.exports.max_per_user = 2;
+        let state = test_util::state(config).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        export(&state, &name).await;
+
+        let sources: Vec<_> = state
+            .db
+            .exports_by_owner("1")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.source)
+            .collect();
+        assert_eq!(sources, ["/b", "/c"]);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("exports/1"))
+                .unwrap()
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn owner_directory() {
+        let dir = TempDir::new();
+        let state = state(&dir, &["/results"], &[]).await;
+        let name = test_util::running_instance(&state, "github:1").await;
+
+        export(&state, &name).await;
+
+        let exports = state.db.exports_by_owner("github:1").unwrap();
+        assert_eq!(exports.len(), 1);
+        assert!(exports[0].file.starts_with("github_1/"));
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let dir = TempDir::new();
+        let state = state(&dir, &[], &[]).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        export(&state, &name).await;
+
+        assert!(state.db.exports_by_owner("1").unwrap().is_empty());
+        assert!(!dir.path().join("exports").exists());
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU16, AtomicU32, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use bollard::secret::{
+    ContainerConfig, ContainerCreateBody, ContainerInspectResponse, ContainerState,
+    ContainerStateStatusEnum, ContainerSummary, ContainerSummaryNetworkSettings,
+    ContainerSummaryStateEnum, EndpointSettings, Health, HealthStatusEnum, NetworkSettings, Port,
+    PortBinding, PortTypeEnum,
+};
+use futures_util::{
+    StreamExt, TryStreamExt,
+    stream::{self, BoxStream},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+
+use crate::{
+    backend::{ContainerBackend, ContainerEvent},
+    db::unix_time,
+};
+
+/// next host port handed out to fake containers. ports and addresses are shared by every fake backend,
+/// as they are all on the same host
+static NEXT_FAKE_PORT: AtomicU16 = AtomicU16::new(40000);
+
+/// the fake docker network is on loopback, so each container can listen on the container port at an
+/// address of its own. the first container gets the address after this one
+const FAKE_NETWORK: Ipv4Addr = Ipv4Addr::new(127, 1, 0, 0);
+
+/// next ID given to a fake container, which is also where its address on the fake network is
+static NEXT_FAKE_ID: AtomicU32 = AtomicU32::new(1);
+
+/// size of the chunks an archive is downloaded in, so that a download is more than one chunk
+const ARCHIVE_CHUNK_SIZE: usize = 512;
+
+/// a tar with nothing in it, which is two blocks of zeros
+const EMPTY_TAR: [u8; 1024] = [0; 1024];
+
+///
+/// backend that keeps containers in memory instead of running them. a running fake container answers
+/// every HTTP request with a short page, and passes its health check if it has one, so everything in ink
+/// works without a docker daemon. it listens on its published port, or if it has none, on the container
+/// port at its IP on the fake network. container names do not resolve, so the "name" route does not work
+///
+pub struct FakeBackend {
+    images: Vec<String>,
+    inner: Mutex<FakeState>,
+    events: broadcast::Sender<ContainerEvent>,
+
+    /// what this backend has been made to fail at
+    failures: Mutex<HashSet<FakeFailure>>,
+}
+
+///
+/// something a fake backend can be made to fail at, to test how ink copes with a broken docker daemon
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FakeFailure {
+    Create,
+    Start,
+    Inspect,
+
+    /// containers start, but never answer requests
+    Answer,
+
+    /// downloading an archive fails after its first chunk
+    Download,
+}
+
+struct FakeState {
+    containers: HashMap<String, FakeContainer>,
+    volumes: HashSet<String>,
+}
+
+struct FakeContainer {
+    id: String,
+    body: ContainerCreateBody,
+    created: SystemTime,
+    running: bool,
+    paused: bool,
+
+    /// host port the container port is published on, given out when the container is first started
+    port: Option<u16>,
+
+    /// address of the container on the fake network
+    ip: Ipv4Addr,
+
+    /// answers requests on `port` while the container is running
+    server: Option<JoinHandle<()>>,
+
+    /// the last tar uploaded to the container, which is what is downloaded from it
+    archive: Option<Vec<u8>>,
+}
+
+impl FakeBackend {
+    ///
+    /// create a fake backend where `images` exist
+    ///
+    pub fn new(images: Vec<String>) -> Self {
+        return FakeBackend {
+            images,
+            inner: Mutex::new(FakeState {
+                containers: HashMap::new(),
+                volumes: HashSet::new(),
+            }),
+            events: broadcast::channel(64).0,
+            failures: Mutex::new(HashSet::new()),
+        };
+    }
+
+    ///
+    /// make every later `failure` happen
+    ///
+    #[cfg(test)]
+    pub fn fail(&self, failure: FakeFailure) {
+        self.failures.lock().unwrap().insert(failure);
+    }
+
+    ///
+    /// names of every container, running or not
+    ///
+    #[cfg(test)]
+    pub fn container_names(&self) -> Vec<String> {
+        return self
+            .inner
+            .lock()
+            .unwrap()
+            .containers
+            .keys()
+            .cloned()
+            .collect();
+    }
+
+    ///
+    /// the tar that would be downloaded from a container, which is the last one uploaded to it
+    ///
+    #[cfg(test)]
+    pub fn archive(&self, name: &str) -> Option<Vec<u8>> {
+        return self
+            .inner
+            .lock()
+            .unwrap()
+            .containers
+            .get(name)
+            .and_then(|c| c.archive.clone());
+    }
+
+    fn failing(&self, failure: FakeFailure) -> bool {
+        return self.failures.lock().unwrap().contains(&failure);
+    }
+
+    fn check(&self, failure: FakeFailure) -> Result<()> {
+        if self.failing(failure) {
+            return Err(anyhow!("fake backend made to fail at {failure:?}"));
+        }
+        return Ok(());
+    }
+
+    fn set_paused(&self, name: &str, paused: bool) -> Result<()> {
+        let mut state = self.inner.lock().unwrap();
+        let container = state
+            .containers
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("no such container: {name}"))?;
+
+        if !container.running {
+            return Err(anyhow!("container {name} is not running"));
+        }
+        if container.paused == paused {
+            return Err(anyhow!(
+                "container {name} is already {}",
+                match paused {
+                    true => "paused",
+                    false => "unpaused",
+                }
+            ));
+        }
+
+        container.paused = paused;
+        return Ok(());
+    }
+
+    fn send_event(&self, name: &str, action: &str) {
+        // an error only means nothing is listening
+        let _ = self.events.send(ContainerEvent {
+            name: name.to_string(),
+            action: action.to_string(),
+        });
+    }
+}
+
+///
+/// the port number of a port key, such as 8080 for 8080/tcp
+///
+fn container_port(port_key: &str) -> u16 {
+    return port_key
+        .split('/')
+        .next()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(0);
+}
+
+///
+/// answer every request on `listener` with a page saying which container it is
+///
+async fn serve(listener: TcpListener, name: String) {
+    let page = format!("fake squittal instance {name}");
+    let response = format!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        page.len(),
+        page
+    );
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::warn!("fake container {} failed to accept: {}", name, e);
+                continue;
+            }
+        };
+
+        let response = response.clone();
+        tokio::spawn(async move {
+            // the request itself does not matter, only that it was sent
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+impl FakeContainer {
+    /// if a health check was set when the container was created
+    fn has_healthcheck(&self) -> bool {
+        return self
+            .body
+            .healthcheck
+            .as_ref()
+            .and_then(|h| h.test.as_ref())
+            .and_then(|t| t.first())
+            .is_some_and(|kind| kind != "NONE");
+    }
+
+    fn stop_server(&mut self) {
+        if let Some(server) = self.server.take() {
+            server.abort();
+        }
+    }
+
+    /// the container port squittal is exposed on, such as 8080/tcp
+    fn port_key(&self) -> Option<&String> {
+        return self.body.exposed_ports.as_ref()?.keys().next();
+    }
+
+    /// if the container port is published on the host
+    fn publishes_port(&self) -> bool {
+        return self
+            .body
+            .host_config
+            .as_ref()
+            .and_then(|h| h.port_bindings.as_ref())
+            .is_some_and(|bindings| !bindings.is_empty());
+    }
+
+    fn networks(&self) -> HashMap<String, EndpointSettings> {
+        let network = self
+            .body
+            .host_config
+            .as_ref()
+            .and_then(|h| h.network_mode.clone())
+            .unwrap_or_else(|| "bridge".to_string());
+
+        return HashMap::from([(
+            network,
+            EndpointSettings {
+                ip_address: Some(self.ip.to_string()),
+                ..Default::default()
+            },
+        )]);
+    }
+
+    fn summary(&self, name: &str) -> ContainerSummary {
+        let ports = match (self.port_key(), self.port) {
+            (Some(key), Some(port)) => vec![Port {
+                ip: Some("127.0.0.1".to_string()),
+                private_port: container_port(key),
+                public_port: Some(port),
+                typ: Some(PortTypeEnum::TCP),
+            }],
+            _ => vec![],
+        };
+
+        return ContainerSummary {
+            id: Some(self.id.clone()),
+            names: Some(vec![format!("/{name}")]),
+            image: self.body.image.clone(),
+            created: Some(unix_time(self.created)),
+            ports: Some(ports),
+            labels: self.body.labels.clone(),
+            network_settings: Some(ContainerSummaryNetworkSettings {
+                networks: Some(self.networks()),
+            }),
+            state: Some(match self.running {
+                true => ContainerSummaryStateEnum::RUNNING,
+                false => ContainerSummaryStateEnum::EXITED,
+            }),
+            ..Default::default()
+        };
+    }
+}
+
+#[async_trait]
+impl ContainerBackend for FakeBackend {
+    async fn list_containers(&self, image: &str) -> Result<Vec<ContainerSummary>> {
+        let state = self.inner.lock().unwrap();
+
+        return Ok(state
+            .containers
+            .iter()
+            .filter(|(_, c)| c.running && c.body.image.as_deref() == Some(image))
+            .filter(|(_, c)| {
+                c.body
+                    .labels
+                    .as_ref()
+                    .and_then(|l| l.get("ink_tag"))
+                    .is_some_and(|v| v == "true")
+            })
+            .map(|(name, c)| c.summary(name))
+            .collect());
+    }
+
+    async fn image_exists(&self, image: &str) -> Result<bool> {
+        return Ok(self.images.iter().any(|i| i == image));
+    }
+
+    async fn create_container(&self, name: &str, body: ContainerCreateBody) -> Result<String> {
+        self.check(FakeFailure::Create)?;
+        let image = body.image.clone().unwrap_or_default();
+        if !self.images.contains(&image) {
+            return Err(anyhow!("no such image: {image}"));
+        }
+
+        let mut state = self.inner.lock().unwrap();
+        if state.containers.contains_key(name) {
+            return Err(anyhow!("container name {name} is already in use"));
+        }
+
+        let next_id = NEXT_FAKE_ID.fetch_add(1, Ordering::Relaxed);
+        let id = format!("fake{next_id:060}");
+        let ip = Ipv4Addr::from(u32::from(FAKE_NETWORK) + next_id);
+
+        state.containers.insert(
+            name.to_string(),
+            FakeContainer {
+                id: id.clone(),
+                body,
+                created: SystemTime::now(),
+                running: false,
+                paused: false,
+                port: None,
+                ip,
+                server: None,
+                archive: None,
+            },
+        );
+
+        return Ok(id);
+    }
+
+    async fn start_container(&self, name: &str) -> Result<()> {
+        self.check(FakeFailure::Start)?;
+        let mut state = self.inner.lock().unwrap();
+        let container = state
+            .containers
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("no such container: {name}"))?;
+
+        let (ip, port) = match container.publishes_port() {
+            true => (
+                Ipv4Addr::LOCALHOST,
+                *container
+                    .port
+                    .get_or_insert_with(|| NEXT_FAKE_PORT.fetch_add(1, Ordering::Relaxed)),
+            ),
+            false => (
+                container.ip,
+                container.port_key().map(|k| container_port(k)).unwrap_or(0),
+            ),
+        };
+        if container.server.is_none() && !self.failing(FakeFailure::Answer) {
+            let listener = std::net::TcpListener::bind((ip, port))
+                .and_then(|l| l.set_nonblocking(true).map(|_| l))
+                .and_then(TcpListener::from_std)
+                .map_err(|e| anyhow!("fake container cannot listen on {ip}:{port}: {e}"))?;
+            container.server = Some(tokio::spawn(serve(listener, name.to_string())));
+        }
+        container.running = true;
+        drop(state);
+
+        self.send_event(name, "start");
+        return Ok(());
+    }
+
+    async fn inspect_container(&self, name: &str) -> Result<ContainerInspectResponse> {
+        self.check(FakeFailure::Inspect)?;
+        let state = self.inner.lock().unwrap();
+        let container = state
+            .containers
+            .get(name)
+            .ok_or_else(|| anyhow!("no such container: {name}"))?;
+
+        let ports = match (container.port_key(), container.port) {
+            (Some(key), Some(port)) => HashMap::from([(
+                key.clone(),
+                Some(vec![PortBinding {
+                    host_ip: Some("127.0.0.1".to_string()),
+                    host_port: Some(port.to_string()),
+                }]),
+            )]),
+            _ => HashMap::new(),
+        };
+
+        return Ok(ContainerInspectResponse {
+            id: Some(container.id.clone()),
+            name: Some(format!("/{name}")),
+            image: container.body.image.clone(),
+            config: Some(ContainerConfig {
+                image: container.body.image.clone(),
+                env: container.body.env.clone(),
+                labels: container.body.labels.clone(),
+                ..Default::default()
+            }),
+            host_config: container.body.host_config.clone(),
+            state: Some(ContainerState {
+                status: Some(match (container.running, container.paused) {
+                    (true, true) => ContainerStateStatusEnum::PAUSED,
+                    (true, false) => ContainerStateStatusEnum::RUNNING,
+                    (false, _) => ContainerStateStatusEnum::EXITED,
+                }),
+                running: Some(container.running),
+                paused: Some(container.paused),
+                health: match container.has_healthcheck() && container.running {
+                    true => Some(Health {
+                        status: Some(HealthStatusEnum::HEALTHY),
+                        ..Default::default()
+                    }),
+                    false => None,
+                },
+                ..Default::default()
+            }),
+            network_settings: Some(NetworkSettings {
+                ports: Some(ports),
+                networks: Some(container.networks()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+
+    async fn stop_container(&self, name: &str, _grace: Duration) -> Result<()> {
+        let mut state = self.inner.lock().unwrap();
+        let container = state
+            .containers
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("no such container: {name}"))?;
+
+        container.running = false;
+        container.paused = false;
+        container.stop_server();
+        drop(state);
+
+        self.send_event(name, "stop");
+        return Ok(());
+    }
+
+    async fn remove_container(&self, name: &str) -> Result<()> {
+        let mut state = self.inner.lock().unwrap();
+        match state.containers.remove(name) {
+            Some(mut container) => container.stop_server(),
+            None => return Err(anyhow!("no such container: {name}")),
+        }
+        drop(state);
+
+        self.send_event(name, "destroy");
+        return Ok(());
+    }
+
+    async fn create_volume(&self, name: &str, _labels: HashMap<String, String>) -> Result<()> {
+        self.inner.lock().unwrap().volumes.insert(name.to_string());
+        return Ok(());
+    }
+
+    async fn remove_volume(&self, name: &str) -> Result<()> {
+        if !self.inner.lock().unwrap().volumes.remove(name) {
+            return Err(anyhow!("no such volume: {name}"));
+        }
+
+        return Ok(());
+    }
+
+    async fn pause_container(&self, name: &str) -> Result<()> {
+        self.set_paused(name, true)?;
+        self.send_event(name, "pause");
+        return Ok(());
+    }
+
+    async fn unpause_container(&self, name: &str) -> Result<()> {
+        self.set_paused(name, false)?;
+        self.send_event(name, "unpause");
+        return Ok(());
+    }
+
+    fn download_archive(&self, container: &str, _path: &str) -> BoxStream<'static, Result<Bytes>> {
+        let state = self.inner.lock().unwrap();
+        let Some(container) = state.containers.get(container) else {
+            return stream::once(futures_util::future::ready(Err(anyhow!(
+                "no such container: {container}"
+            ))))
+            .boxed();
+        };
+
+        let tar = container
+            .archive
+            .clone()
+            .unwrap_or_else(|| EMPTY_TAR.to_vec());
+        let mut chunks: Vec<Result<Bytes>> = tar
+            .chunks(ARCHIVE_CHUNK_SIZE)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        if self.failing(FakeFailure::Download) {
+            chunks.truncate(1);
+            chunks.push(Err(anyhow!(
+                "fake backend made to fail at {:?}",
+                FakeFailure::Download
+            )));
+        }
+
+        return stream::iter(chunks).boxed();
+    }
+
+    async fn upload_archive(
+        &self,
+        container: &str,
+        _path: &str,
+        tar: BoxStream<'static, Result<Bytes>>,
+    ) -> Result<()> {
+        let chunks: Vec<Bytes> = tar.try_collect().await?;
+
+        let mut state = self.inner.lock().unwrap();
+        let container = state
+            .containers
+            .get_mut(container)
+            .ok_or_else(|| anyhow!("no such container: {container}"))?;
+
+        container.archive = Some(chunks.concat());
+        return Ok(());
+    }
+
+    fn container_events(&self) -> BoxStream<'static, Result<ContainerEvent>> {
+        return futures_util::stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((Ok(event), rx)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed();
+    }
+}
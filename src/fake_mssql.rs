@@ -0,0 +1,66 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+
+use crate::mssql::{DatabaseLogin, InstanceDatabases, database_name};
+
+///
+/// databases kept in memory instead of on an MSSQL server. creating one that already exists fails,
+/// the same as on a real server, so leaked databases show up when working on ink without MSSQL
+///
+#[derive(Default)]
+pub struct FakeMssql {
+    databases: Mutex<HashMap<String, DatabaseLogin>>,
+}
+
+impl FakeMssql {
+    pub fn new() -> Self {
+        return FakeMssql::default();
+    }
+
+    ///
+    /// if `instance` has a database
+    ///
+    #[cfg(test)]
+    pub fn exists(&self, instance: &str) -> bool {
+        return self
+            .databases
+            .lock()
+            .unwrap()
+            .contains_key(&database_name(instance));
+    }
+}
+
+#[async_trait]
+impl InstanceDatabases for FakeMssql {
+    async fn create(&self, instance: &str) -> Result<DatabaseLogin> {
+        let name = database_name(instance);
+        let mut databases = self.databases.lock().unwrap();
+        if databases.contains_key(&name) {
+            return Err(anyhow!("database {name} already exists"));
+        }
+
+        let login = DatabaseLogin {
+            database: name.clone(),
+            login: name.clone(),
+            password: "fake".to_string(),
+        };
+        databases.insert(name, login.clone());
+
+        return Ok(login);
+    }
+
+    async fn remove(&self, instance: &str, archive: bool) -> Result<()> {
+        let name = database_name(instance);
+        if self.databases.lock().unwrap().remove(&name).is_some() {
+            tracing::info!(
+                "{} fake database {}",
+                if archive { "archived" } else { "dropped" },
+                name
+            );
+        }
+
+        return Ok(());
+    }
+}
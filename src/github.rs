@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use oauth2::{AuthType, Scope};
+use serde::Deserialize;
+
+use crate::{
+    User,
+    config::GithubConfig,
+    identity::{self, IdentityProvider, OauthClient},
+};
+
+///
+/// log in with a github account
+///
+pub struct GithubProvider {
+    config: GithubConfig,
+    oauth: OauthClient,
+    client: reqwest::Client,
+}
+
+// https://docs.github.com/en/rest/users/users#get-the-authenticated-user
+#[derive(Debug, Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+}
+
+impl GithubProvider {
+    pub fn new(
+        config: &GithubConfig,
+        client_id: String,
+        client_secret: String,
+        redirect_url: String,
+    ) -> Result<Self> {
+        let oauth = identity::oauth_client(
+            client_id,
+            client_secret,
+            format!("{}/authorize", config.oauth_url),
+            format!("{}/access_token", config.oauth_url),
+            redirect_url,
+        )?
+        .set_auth_type(AuthType::RequestBody);
+
+        return Ok(GithubProvider {
+            config: config.clone(),
+            oauth,
+            client: reqwest::Client::new(),
+        });
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for GithubProvider {
+    fn id(&self) -> &str {
+        return "github";
+    }
+
+    fn display_name(&self) -> &str {
+        return "GitHub";
+    }
+
+    fn oauth_client(&self) -> &OauthClient {
+        return &self.oauth;
+    }
+
+    ///
+    /// no scopes are needed to read the public profile
+    ///
+    fn scopes(&self) -> Vec<Scope> {
+        return vec![];
+    }
+
+    async fn user(&self, token: &str) -> Result<User> {
+        let user: GithubUser = self
+            .client
+            .get(format!("{}/user", self.config.api_url))
+            .bearer_auth(token)
+            // github rejects requests without a user agent
+            .header("User-Agent", "ink")
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .await
+            .context("failed to get github user")?
+            .error_for_status()
+            .context("failed to get github user")?
+            .json()
+            .await
+            .context("failed to deserialize github user")?;
+
+        return Ok(User {
+            id: format!("github:{}", user.id),
+            username: user.login,
+            discriminator: "0".to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, ACCESS_TOKEN};
+
+    async fn provider() -> GithubProvider {
+        let github = test_util::github().await;
+        let config = GithubConfig {
+            api_url: format!("{github}/api"),
+            oauth_url: format!("{github}/login/oauth"),
+            ..Default::default()
+        };
+
+        return GithubProvider::new(
+            &config,
+            "id".to_string(),
+            "secret".to_string(),
+            "http://localhost/auth/github/callback".to_string(),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn user() {
+        let user = provider().await.user(ACCESS_TOKEN).await.unwrap();
+        assert_eq!(user.id, "github:1234");
+        assert_eq!(user.username, "someone");
+    }
+
+    #[tokio::test]
+    async fn user_with_bad_token() {
+        assert!(provider().await.user("wrong").await.is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{env, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use oauth2::{
+    AuthUrl, Client, ClientId, ClientSecret, ExtraTokenFields, RedirectUrl, Scope,
+    StandardRevocableToken, StandardTokenResponse, TokenUrl,
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenType,
+    },
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{User, config::Config, discord::DiscordProvider, github::GithubProvider, oidc};
+
+///
+/// fields of a token response other than the oauth ones. OpenID Connect providers send an ID token
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenFields {
+    pub id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+pub type OauthTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+
+///
+/// oauth client that keeps the ID token of token responses
+///
+pub type OauthClient = Client<
+    BasicErrorResponse,
+    OauthTokenResponse,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+///
+/// somewhere users can log in from with oauth, such as discord
+///
+#[async_trait]
+pub trait IdentityProvider: Send + Sync {
+    ///
+    /// short name of the provider, used in the login URL (/auth/{id})
+    ///
+    fn id(&self) -> &str;
+
+    ///
+    /// name shown on the login button
+    ///
+    fn display_name(&self) -> &str;
+
+    fn oauth_client(&self) -> &OauthClient;
+
+    ///
+    /// the oauth scopes requested when logging in
+    ///
+    fn scopes(&self) -> Vec<Scope>;
+
+    ///
+    /// whether a nonce is sent when logging in, which comes back in the ID token
+    ///
+    fn uses_nonce(&self) -> bool {
+        return false;
+    }
+
+    ///
+    /// check the ID token sent with the access token, if the provider sends one. `nonce` is the one
+    /// sent when the login started
+    ///
+    fn check_id_token(&self, _id_token: Option<&str>, _nonce: Option<&str>) -> Result<()> {
+        return Ok(());
+    }
+
+    ///
+    /// get the user an access token belongs to. IDs from providers other than discord are prefixed
+    /// with the provider, so users from different providers never share an ID
+    ///
+    async fn user(&self, token: &str) -> Result<User>;
+
+    ///
+    /// check if the user an access token belongs to is allowed to log in
+    ///
+    async fn is_allowed(&self, _token: &str) -> Result<bool> {
+        return Ok(true);
+    }
+}
+
+///
+/// the identity providers turned on in the config
+///
+#[derive(Clone)]
+pub struct IdentityProviders {
+    providers: Arc<Vec<Arc<dyn IdentityProvider>>>,
+}
+
+///
+/// a provider as shown to users picking how to log in
+///
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ProviderInfo {
+    id: String,
+    name: String,
+}
+
+impl IdentityProviders {
+    ///
+    /// set up every provider in the config. OIDC providers are discovered from their issuer,
+    /// so this fails if one cannot be reached
+    ///
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let mut providers: Vec<Arc<dyn IdentityProvider>> = vec![];
+
+        let discord = &config.discord;
+        let discord_id = discord
+            .client_id
+            .clone()
+            .or_else(|| env::var("DISCORD_CLIENT_ID").ok());
+        let discord_secret = discord
+            .client_secret
+            .clone()
+            .or_else(|| env::var("DISCORD_SECRET").ok());
+        if let (Some(id), Some(secret)) = (discord_id, discord_secret) {
+            // DISCORD_CALLBACK is kept so existing discord apps that use /auth/callback keep working
+            let redirect_url =
+                env::var("DISCORD_CALLBACK").unwrap_or_else(|_| callback_url(config, "discord"));
+            providers.push(Arc::new(DiscordProvider::new(
+                discord,
+                id,
+                secret,
+                redirect_url,
+            )?));
+        }
+
+        if let (Some(id), Some(secret)) = (&config.github.client_id, &config.github.client_secret) {
+            providers.push(Arc::new(GithubProvider::new(
+                &config.github,
+                id.clone(),
+                secret.clone(),
+                callback_url(config, "github"),
+            )?));
+        }
+
+        let mut oidc_names: Vec<&String> = config.oidc.keys().collect();
+        oidc_names.sort();
+        for name in oidc_names {
+            let provider =
+                oidc::OidcProvider::discover(name, &config.oidc[name], callback_url(config, name))
+                    .await
+                    .with_context(|| format!("failed to set up oidc provider {name}"))?;
+            providers.push(Arc::new(provider));
+        }
+
+        if providers.is_empty() {
+            bail!(
+                "no identity providers are set up. set DISCORD_CLIENT_ID and DISCORD_SECRET, or configure github or oidc"
+            );
+        }
+
+        for provider in &providers {
+            tracing::info!("users can log in with {}", provider.id());
+        }
+
+        return Ok(IdentityProviders {
+            providers: Arc::new(providers),
+        });
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<dyn IdentityProvider>> {
+        return self.providers.iter().find(|p| p.id() == id).cloned();
+    }
+
+    pub fn list(&self) -> Vec<ProviderInfo> {
+        return self
+            .providers
+            .iter()
+            .map(|p| ProviderInfo {
+                id: p.id().to_string(),
+                name: p.display_name().to_string(),
+            })
+            .collect();
+    }
+}
+
+///
+/// URL the provider `id` sends users back to after they log in
+///
+fn callback_url(config: &Config, id: &str) -> String {
+    return format!("{}/auth/{id}/callback", config.public_url);
+}
+
+///
+/// build the oauth client of a provider
+///
+pub fn oauth_client(
+    client_id: String,
+    client_secret: String,
+    auth_url: String,
+    token_url: String,
+    redirect_url: String,
+) -> Result<OauthClient> {
+    return Ok(OauthClient::new(
+        ClientId::new(client_id),
+        Some(ClientSecret::new(client_secret)),
+        AuthUrl::new(auth_url).context("failed to create new authorization server URL")?,
+        Some(TokenUrl::new(token_url).context("failed to create new token endpoint URL")?),
+    )
+    .set_redirect_uri(
+        RedirectUrl::new(redirect_url).context("failed to create new redirection URL")?,
+    ));
+}
@@ -1,42 +1,250 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::{Context, Result};
 use bollard::secret::ContainerSummary;
 use serde::Serialize;
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize)]
+use crate::config::{Config, DockerConfig, RouteMode};
+
+///
+/// where an instance is in being created. instances found in docker are always ready
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceStatus {
+    /// ink has accepted the instance, and is creating the container
+    Provisioning,
+
+    /// the container is started, and ink is waiting for it to get a port
+    Starting,
+
+    /// the instance can be used
+    Ready,
+
+    /// creating the instance failed, see the failure for why
+    Failed,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct SquittalInstance {
     pub name: String,
     pub created_by: String,
+    #[schema(value_type = crate::openapi::Timestamp)]
     pub created_on: SystemTime,
+    #[schema(value_type = crate::openapi::Timestamp)]
+    pub expires_at: SystemTime,
+    /// port published on the host, or 0 if the instance has none, such as when ports are not published
     pub port: u16,
+    pub status: InstanceStatus,
+
+    /// IP of the container on the docker network, if it has been given one
+    #[serde(skip)]
+    pub ip: Option<String>,
+
+    /// why creating the instance failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+
+    /// set when the instance is close to expiring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<ExpiryWarning>,
+
+    /// resources the container is limited to
+    pub limits: InstanceLimits,
 }
 
-impl From<ContainerSummary> for SquittalInstance {
-    fn from(summary: ContainerSummary) -> Self {
-        let epoch: i64 = summary.created.expect("failed to get created of container");
+///
+/// resources the container of an instance is limited to. these are kept in labels when the container is
+/// created, since docker does not return them when listing containers. containers made before limits
+/// were added have none
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct InstanceLimits {
+    /// most memory the container can use, in MiB. null if there is no limit
+    pub memory_mb: Option<u64>,
 
-        let owner = summary
-            .labels
-            .expect("missing labels field")
+    /// how many CPUs the container can use. null if there is no limit
+    pub cpus: Option<f64>,
+
+    /// most processes and threads the container can have. null if there is no limit
+    pub pids: Option<i64>,
+
+    /// if the root filesystem of the container is read-only
+    pub read_only: bool,
+}
+
+impl InstanceLimits {
+    ///
+    /// the labels these limits are kept in on the container
+    ///
+    pub fn labels(&self) -> Vec<(String, String)> {
+        let mut labels = vec![("ink_read_only".to_string(), self.read_only.to_string())];
+        if let Some(memory_mb) = self.memory_mb {
+            labels.push(("ink_memory_mb".to_string(), memory_mb.to_string()));
+        }
+        if let Some(cpus) = self.cpus {
+            labels.push(("ink_cpus".to_string(), cpus.to_string()));
+        }
+        if let Some(pids) = self.pids {
+            labels.push(("ink_pids".to_string(), pids.to_string()));
+        }
+
+        return labels;
+    }
+
+    pub fn from_labels(labels: &HashMap<String, String>) -> Self {
+        return InstanceLimits {
+            memory_mb: labels.get("ink_memory_mb").and_then(|v| v.parse().ok()),
+            cpus: labels.get("ink_cpus").and_then(|v| v.parse().ok()),
+            pids: labels.get("ink_pids").and_then(|v| v.parse().ok()),
+            read_only: labels.get("ink_read_only").is_some_and(|v| v == "true"),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ExpiryWarning {
+    /// the warning threshold that was crossed, in seconds before the instance expires
+    pub threshold_secs: u64,
+
+    /// how long until the instance expires, in seconds
+    pub expires_in_secs: u64,
+}
+
+impl SquittalInstance {
+    ///
+    /// get the smallest of the warning `thresholds` (in seconds) this instance is within of expiring, if any
+    ///
+    pub fn expiry_warning(&self, thresholds: &[u64], now: SystemTime) -> Option<ExpiryWarning> {
+        let remaining = self
+            .expires_at
+            .duration_since(now)
+            .unwrap_or_default()
+            .as_secs();
+
+        let threshold = thresholds.iter().filter(|&&t| remaining <= t).min()?;
+
+        return Some(ExpiryWarning {
+            threshold_secs: *threshold,
+            expires_in_secs: remaining,
+        });
+    }
+
+    ///
+    /// host and port ink reaches squittal in this instance at, or none if the container does not have
+    /// one yet
+    ///
+    pub fn address(&self, docker: &DockerConfig) -> Option<String> {
+        return match docker.route {
+            RouteMode::Port => (self.port != 0).then(|| format!("127.0.0.1:{}", self.port)),
+            RouteMode::Ip => self
+                .ip
+                .as_ref()
+                .map(|ip| format!("{ip}:{}", docker.container_port)),
+            RouteMode::Name => Some(format!(
+                "{}:{}",
+                self.name.trim_start_matches('/'),
+                docker.container_port
+            )),
+        };
+    }
+
+    ///
+    /// set the warning for this instance as of now
+    ///
+    pub fn with_warning(mut self, thresholds: &[u64]) -> Self {
+        self.warning = self.expiry_warning(thresholds, SystemTime::now());
+        return self;
+    }
+}
+
+///
+/// the time `secs` seconds after the unix epoch, or none if that is too far out to be a time
+///
+fn from_unix(secs: u64) -> Option<SystemTime> {
+    return UNIX_EPOCH.checked_add(Duration::from_secs(secs));
+}
+
+impl SquittalInstance {
+    ///
+    /// read an instance from a container ink made. containers on the image that ink did not make, such as
+    /// ones made by hand, can be missing what ink puts on its containers, and are an error
+    ///
+    pub fn from_summary(summary: ContainerSummary, config: &Config) -> Result<Self> {
+        let name = summary
+            .names
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .context("container has no name")?;
+
+        let epoch = summary.created.context("container has no created time")?;
+        let created_on = u64::try_from(epoch)
+            .ok()
+            .and_then(from_unix)
+            .with_context(|| format!("container has an invalid created time of {epoch}"))?;
+
+        let labels = summary.labels.context("container has no labels")?;
+
+        let owner = labels
             .get("created_by")
-            .expect("missing created_by label")
+            .context("container has no created_by label")?
             .clone();
 
+        // containers from before expires_at was tracked have no label, and get the default lifetime
+        let expires_at = match labels.get("expires_at") {
+            Some(value) => value
+                .parse::<u64>()
+                .ok()
+                .and_then(from_unix)
+                .with_context(|| {
+                    format!("container has an invalid expires_at label of '{value}'")
+                })?,
+            None => created_on
+                .checked_add(config.instances.ttl())
+                .context("container was created too far in the future")?,
+        };
+
+        // containers are not given a host port when ink reaches them over the docker network. images can
+        // expose other ports too, so only the one squittal listens on is used
         let port = summary
             .ports
-            .expect("missing ports")
+            .unwrap_or_default()
             .iter()
-            .find(|&iter| iter.private_port == 8080)
-            .expect("missing port 8080")
-            .public_port
-            .expect("missing public_port for 8080");
+            .filter(|p| p.private_port == config.docker.container_port)
+            .find_map(|p| p.public_port)
+            .unwrap_or(0);
 
-        return SquittalInstance {
-            name: summary.names.unwrap()[0].clone(),
+        // prefer the network the container was attached to when it was created
+        let networks = summary
+            .network_settings
+            .and_then(|n| n.networks)
+            .unwrap_or_default();
+        let ip = labels
+            .get("ink_network")
+            .and_then(|network| networks.get(network))
+            .into_iter()
+            .chain(networks.values())
+            .find_map(|endpoint| endpoint.ip_address.clone().filter(|ip| !ip.is_empty()));
+
+        let limits = InstanceLimits::from_labels(&labels);
+
+        return Ok(SquittalInstance {
+            name,
             created_by: owner,
-            created_on: UNIX_EPOCH + Duration::from_secs(epoch as u64),
-            port: port,
-        };
+            created_on,
+            expires_at,
+            port,
+            status: InstanceStatus::Ready,
+            ip,
+            failure: None,
+            warning: None,
+            limits,
+        });
     }
 }
 
@@ -46,7 +254,158 @@ impl Clone for SquittalInstance {
             name: self.name.clone(),
             created_by: self.created_by.clone(),
             created_on: self.created_on,
+            expires_at: self.expires_at,
             port: self.port,
+            status: self.status,
+            ip: self.ip.clone(),
+            failure: self.failure.clone(),
+            warning: self.warning.clone(),
+            limits: self.limits.clone(),
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bollard::secret::Port;
+
+    use super::*;
+
+    /// a summary of a container as ink makes them, with the squittal port published on 40000
+    fn summary() -> ContainerSummary {
+        return ContainerSummary {
+            names: Some(vec!["/squittal-brawny-eggnog".to_string()]),
+            created: Some(1000),
+            labels: Some(HashMap::from([
+                ("created_by".to_string(), "1234".to_string()),
+                ("ink_tag".to_string(), "true".to_string()),
+                ("expires_at".to_string(), "2000".to_string()),
+            ])),
+            ports: Some(vec![
+                Port {
+                    private_port: 9090,
+                    public_port: Some(50000),
+                    ..Default::default()
+                },
+                Port {
+                    private_port: 8080,
+                    public_port: Some(40000),
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        };
+    }
+
+    fn parse(summary: ContainerSummary) -> Result<SquittalInstance> {
+        return SquittalInstance::from_summary(summary, &Config::default());
+    }
+
+    fn set_label(summary: &mut ContainerSummary, key: &str, value: &str) {
+        summary
+            .labels
+            .as_mut()
+            .unwrap()
+            .insert(key.to_string(), value.to_string());
+    }
+
+    #[test]
+    fn valid_summary() {
+        let instance = parse(summary()).unwrap();
+
+        assert_eq!(instance.name, "/squittal-brawny-eggnog");
+        assert_eq!(instance.created_by, "1234");
+        assert_eq!(instance.created_on, UNIX_EPOCH + Duration::from_secs(1000));
+        assert_eq!(instance.expires_at, UNIX_EPOCH + Duration::from_secs(2000));
+        assert_eq!(instance.port, 40000);
+        assert_eq!(instance.status, InstanceStatus::Ready);
+    }
+
+    #[test]
+    fn no_names() {
+        let mut summary = summary();
+        summary.names = None;
+        assert!(parse(summary).is_err());
+
+        let mut summary = self::summary();
+        summary.names = Some(vec![]);
+        assert!(parse(summary).is_err());
+    }
+
+    #[test]
+    fn no_created() {
+        let mut summary = summary();
+        summary.created = None;
+        assert!(parse(summary).is_err());
+    }
+
+    #[test]
+    fn negative_created() {
+        // created is an i64, so the only times a SystemTime cannot hold are before the epoch
+        let mut summary = summary();
+        summary.created = Some(-1);
+        assert!(parse(summary).is_err());
+
+        let mut summary = self::summary();
+        summary.created = Some(i64::MIN);
+        assert!(parse(summary).is_err());
+    }
+
+    #[test]
+    fn no_labels() {
+        let mut summary = summary();
+        summary.labels = None;
+        assert!(parse(summary).is_err());
+    }
+
+    #[test]
+    fn no_created_by_label() {
+        let mut summary = summary();
+        summary.labels.as_mut().unwrap().remove("created_by");
+        assert!(parse(summary).is_err());
+    }
+
+    #[test]
+    fn expires_at_not_a_number() {
+        let mut summary = summary();
+        set_label(&mut summary, "expires_at", "tomorrow");
+        assert!(parse(summary).is_err());
+    }
+
+    #[test]
+    fn expires_at_too_large() {
+        let mut summary = summary();
+        set_label(&mut summary, "expires_at", &u64::MAX.to_string());
+        assert!(parse(summary).is_err());
+    }
+
+    #[test]
+    fn no_expires_at_label() {
+        let mut summary = summary();
+        summary.labels.as_mut().unwrap().remove("expires_at");
+
+        let instance = parse(summary).unwrap();
+        assert_eq!(
+            instance.expires_at,
+            instance.created_on + Config::default().instances.ttl()
+        );
+    }
+
+    #[test]
+    fn no_ports() {
+        let mut summary = summary();
+        summary.ports = None;
+        assert_eq!(parse(summary).unwrap().port, 0);
+    }
+
+    #[test]
+    fn only_other_ports_published() {
+        let mut summary = summary();
+        summary.ports = Some(vec![Port {
+            private_port: 9090,
+            public_port: Some(50000),
+            ..Default::default()
+        }]);
+        assert_eq!(parse(summary).unwrap().port, 0);
+    }
+}
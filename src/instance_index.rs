@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures_util::StreamExt;
+
+use crate::{
+    app_state::AppState,
+    docker,
+    instance::{InstanceStatus, SquittalInstance},
+};
+
+/// container event actions that change what instances are running
+static REFRESH_ACTIONS: [&str; 6] = ["create", "start", "die", "stop", "destroy", "rename"];
+
+///
+/// in-memory copy of the running instances, keyed by instance name (the subdomain). kept current from
+/// the container events stream and when ink creates or removes an instance, so that looking up an
+/// instance does not need a round trip to docker
+///
+#[derive(Clone, Default)]
+pub struct InstanceIndex {
+    instances: Arc<RwLock<HashMap<String, SquittalInstance>>>,
+
+    /// instances ink is still creating, or failed to create. these are not in docker (or not ready yet),
+    /// so they are kept apart from `instances` and are not touched by a refresh. they take the place of a
+    /// container with the same name until they are ready
+    pending: Arc<RwLock<HashMap<String, SquittalInstance>>>,
+}
+
+impl InstanceIndex {
+    pub fn new() -> Self {
+        return InstanceIndex::default();
+    }
+
+    pub fn get(&self, name: &str) -> Option<SquittalInstance> {
+        if let Some(instance) = self.pending.read().unwrap().get(name) {
+            return Some(instance.clone());
+        }
+
+        return self.instances.read().unwrap().get(name).cloned();
+    }
+
+    ///
+    /// every instance, including those still being created and those that failed to be
+    ///
+    pub fn all(&self) -> Vec<SquittalInstance> {
+        let mut all = self.running();
+        all.extend(self.pending.read().unwrap().values().cloned());
+        return all;
+    }
+
+    ///
+    /// the instances that are running in docker, and not still being created
+    ///
+    pub fn running(&self) -> Vec<SquittalInstance> {
+        let pending = self.pending.read().unwrap();
+        return self
+            .instances
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| !pending.contains_key(*name))
+            .map(|(_, i)| i.clone())
+            .collect();
+    }
+
+    pub fn by_owner(&self, owner: &str) -> Vec<SquittalInstance> {
+        return self
+            .all()
+            .into_iter()
+            .filter(|i| i.created_by == owner)
+            .collect();
+    }
+
+    ///
+    /// instances of `owner` that are running or being created, leaving out those that failed
+    ///
+    pub fn active_by_owner(&self, owner: &str) -> Vec<SquittalInstance> {
+        return self
+            .by_owner(owner)
+            .into_iter()
+            .filter(|i| i.status != InstanceStatus::Failed)
+            .collect();
+    }
+
+    ///
+    /// how many instances are running or being created, which is what counts towards the max instances
+    ///
+    pub fn len(&self) -> usize {
+        return self
+            .all()
+            .iter()
+            .filter(|i| i.status != InstanceStatus::Failed)
+            .count();
+    }
+
+    ///
+    /// track an instance that is being created, until it is ready
+    ///
+    pub fn insert_pending(&self, name: &str, instance: SquittalInstance) {
+        self.pending
+            .write()
+            .unwrap()
+            .insert(name.to_string(), instance);
+    }
+
+    ///
+    /// if `name` is still being created, or failed to be
+    ///
+    pub fn is_pending(&self, name: &str) -> bool {
+        return self.pending.read().unwrap().contains_key(name);
+    }
+
+    ///
+    /// change an instance that is being created in place, returning false if there is no such instance
+    ///
+    pub fn update_pending(&self, name: &str, f: impl FnOnce(&mut SquittalInstance)) -> bool {
+        return match self.pending.write().unwrap().get_mut(name) {
+            Some(instance) => {
+                f(instance);
+                true
+            }
+            None => false,
+        };
+    }
+
+    ///
+    /// forget the instances of `owner` that failed to be created, such as when they create another
+    ///
+    pub fn clear_failed(&self, owner: &str) {
+        self.pending
+            .write()
+            .unwrap()
+            .retain(|_, i| i.created_by != owner || i.status != InstanceStatus::Failed);
+    }
+
+    ///
+    /// stop tracking an instance that was being created, either because it is ready and in docker,
+    /// or because it failed and the owner has dismissed it
+    ///
+    pub fn remove_pending(&self, name: &str) -> Option<SquittalInstance> {
+        return self.pending.write().unwrap().remove(name);
+    }
+
+    ///
+    /// change the instance named `name` in place, returning false if there is no such instance
+    ///
+    pub fn update(&self, name: &str, f: impl FnOnce(&mut SquittalInstance)) -> bool {
+        return match self.instances.write().unwrap().get_mut(name) {
+            Some(instance) => {
+                f(instance);
+                true
+            }
+            None => false,
+        };
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.instances.write().unwrap().remove(name);
+    }
+
+    ///
+    /// replace everything in the index with `instances`
+    ///
+    pub fn replace(&self, instances: Vec<SquittalInstance>) {
+        let map: HashMap<String, SquittalInstance> = instances
+            .into_iter()
+            .map(|i| (docker::instance_name(&i.name).to_string(), i))
+            .collect();
+
+        *self.instances.write().unwrap() = map;
+    }
+}
+
+///
+/// reload the index from the container backend
+///
+pub async fn refresh(state: &AppState) -> Result<()> {
+    let instances = docker::get_instances(state).await?;
+    state.index.replace(instances);
+
+    return Ok(());
+}
+
+///
+/// background task that keeps the index current from the container events stream,
+/// reconnecting if the stream is lost
+///
+pub async fn watch_instances(state: AppState) {
+    loop {
+        let mut events = state.backend.container_events();
+
+        // load after subscribing, so nothing that happens in between is missed
+        if let Err(e) = refresh(&state).await {
+            tracing::error!("failed to load instances into the index: {}", e);
+        }
+
+        while let Some(event) = events.next().await {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::error!("error in container events stream: {}", e);
+                    break;
+                }
+            };
+
+            if !REFRESH_ACTIONS.contains(&event.action.as_str()) {
+                continue;
+            }
+
+            tracing::debug!(
+                "container {} {}, refreshing index",
+                event.name,
+                event.action
+            );
+            if let Err(e) = refresh(&state).await {
+                tracing::error!("failed to refresh instance index: {}", e);
+            }
+        }
+
+        tracing::warn!("container events stream ended, reconnecting");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
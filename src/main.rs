@@ -1,34 +1,68 @@
 use anyhow::{Context, Result, anyhow};
-use async_session::{MemoryStore, Session, SessionStore};
+use async_session::{Session, SessionStore};
 use axum::{
     RequestPartsExt, Router,
-    extract::{FromRef, FromRequestParts, OptionalFromRequestParts, Query, State},
+    extract::{FromRef, FromRequestParts, OptionalFromRequestParts, Path, Query, State},
     http::{HeaderMap, header::SET_COOKIE},
     middleware,
-    response::{IntoResponse, Redirect, Response},
-    routing::get,
+    response::{IntoResponse, Redirect},
+    routing::{any, get},
 };
 use axum_extra::{TypedHeader, headers, typed_header::TypedHeaderRejectionReason};
 use http::{StatusCode, header, request::Parts};
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
-use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, RedirectUrl, Scope,
-    TokenResponse, TokenUrl, basic::BasicClient, reqwest::async_http_client,
-};
+use oauth2::{AuthorizationCode, CsrfToken, TokenResponse, reqwest::async_http_client};
 use serde::{Deserialize, Serialize};
-use std::{convert::Infallible, env, time::Duration};
+use std::{sync::Arc, time::Duration};
 use tower::ServiceBuilder;
 use tower_http::{services::ServeDir, trace::TraceLayer};
+use utoipa::OpenApi;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{api_error::ApiError, session_store::SqliteSessionStore};
 
+mod activity;
+mod admin;
 mod api;
+mod api_error;
 mod app_state;
+mod backend;
+mod cleanup;
+mod config;
+mod db;
+mod discord;
 mod docker;
+mod events;
+mod exports;
+mod fake_backend;
+mod fake_mssql;
+mod github;
+mod identity;
 mod instance;
+mod instance_index;
+mod mssql;
+mod notify;
+mod oidc;
+mod openapi;
 mod proxy;
+mod roles;
+mod session_store;
+mod snapshots;
+#[cfg(test)]
+mod test_util;
+mod tokens;
+mod waitlist;
 mod websocket_proxy;
 
 static COOKIE_NAME: &str = "INK_SESSION";
 static CSRF_TOKEN: &str = "csrf_token";
+static LOGIN_PROVIDER: &str = "login_provider";
+static LOGIN_NONCE: &str = "login_nonce";
+
+/// how long a login lasts before the user has to auth with discord again
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+/// how long the CSRF session lives while the user is off on the discord auth page
+const CSRF_SESSION_TTL: Duration = Duration::from_secs(60 * 10);
 
 // large parts of the oauth code is from
 // https://github.com/tokio-rs/axum/blob/main/examples/oauth/src/main.rs
@@ -51,27 +85,72 @@ async fn main() {
 
     tracing::info!("starting ink");
 
-    // `MemoryStore` is just used as an example. Don't use this in production.
-    let store = MemoryStore::new();
-    let oauth_client = oauth_client().unwrap();
+    let config = match config::Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            tracing::error!("failed to load config: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    tracing::debug!("loaded config: {:?}", config);
+
+    let store = session_store::SqliteSessionStore::open(&config.database).unwrap();
+    let db = db::Database::open(&config.database).unwrap();
+    let backend: Arc<dyn backend::ContainerBackend> = match config.docker.backend {
+        config::BackendKind::Docker => Arc::new(backend::DockerBackend::connect().unwrap()),
+        config::BackendKind::Fake => {
+            tracing::warn!("using the fake container backend, no instances will actually run");
+            Arc::new(fake_backend::FakeBackend::new(vec![
+                config.docker.image.clone(),
+            ]))
+        }
+    };
+    let databases: Option<Arc<dyn mssql::InstanceDatabases>> = match config.mssql.backend {
+        config::MssqlBackendKind::Shared => None,
+        config::MssqlBackendKind::Server => Some(Arc::new(mssql::MssqlServer::new(&config.mssql))),
+        config::MssqlBackendKind::Fake => {
+            tracing::warn!("using fake MSSQL databases, instances will not get a real database");
+            Some(Arc::new(fake_mssql::FakeMssql::new()))
+        }
+    };
+
+    let identity = match identity::IdentityProviders::from_config(&config).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            tracing::error!("failed to set up identity providers: {:#}", e);
+            std::process::exit(1);
+        }
+    };
     let client: proxy::Client =
         hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
             .build(HttpConnector::new());
 
     let app_state = app_state::AppState {
-        store,
-        oauth_client,
+        store: store.clone(),
+        identity,
         client,
+        db,
+        config: config.clone(),
+        backend,
+        databases,
+        index: instance_index::InstanceIndex::new(),
+        notifier: notify::Notifier::new(&config.notifications),
+        activity: activity::ActivityTracker::new(),
+        waitlist: waitlist::Waitlist::new(),
+        events: events::EventBus::new(),
     };
 
+    let cleanup_state = app_state.clone();
+    let index_state = app_state.clone();
+
+    let (api_router, _) = api();
+
     let app = Router::new()
         .fallback_service(ServeDir::new("www").append_index_html_on_directories(true))
-        .route("/auth/discord", get(discord_auth))
-        .route("/auth/callback", get(login_authorized))
-        .route("/api/create", get(api::create_instance))
-        .route("/api/list", get(api::list_instances))
-        .route("/api/whoami", get(api::whoami))
-        .route("/api/mine", get(api::get_instance))
+        .route("/auth/{provider}", get(provider_auth))
+        .route("/auth/{provider}/callback", get(login_authorized))
+        .route("/auth/callback", get(discord_callback))
+        .merge(api_router)
         .route("/logout", get(logout))
         .layer(
             ServiceBuilder::new()
@@ -83,60 +162,126 @@ async fn main() {
         )
         .with_state(app_state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(&config.bind_address)
+        .await
+        .unwrap();
+    tracing::info!("listening on {}", &config.bind_address);
 
     let background = tokio::task::spawn(async move {
-        instance_cleanup().await;
+        cleanup::instance_cleanup(cleanup_state).await;
     });
 
+    let index_background = tokio::task::spawn(instance_index::watch_instances(index_state));
+
+    let session_background = tokio::task::spawn(session_store::session_cleanup(
+        store,
+        Duration::from_secs(60 * 10),
+    ));
+
     axum::serve(listener, app).await.unwrap();
     background.abort();
+    index_background.abort();
+    session_background.abort();
 }
 
-fn oauth_client() -> Result<BasicClient, AppError> {
-    let client_id = env::var("DISCORD_CLIENT_ID").context("Missing DISCORD_CLIENT_ID!")?;
-    let client_secret = env::var("DISCORD_SECRET").context("Missing DISCORD_SECRET!")?;
-    let redirect_url =
-        env::var("DISCORD_CALLBACK").unwrap_or("http://localhost:8000/auth/callback".to_string());
-    let auth_url = "https://discord.com/api/oauth2/authorize?response_type=code".to_string();
-    let token_url = "https://discord.com/api/oauth2/token".to_string();
-
-    return Ok(BasicClient::new(
-        ClientId::new(client_id),
-        Some(ClientSecret::new(client_secret)),
-        AuthUrl::new(auth_url).context("failed to create new authorization server URL")?,
-        Some(TokenUrl::new(token_url).context("failed to create new token endpoint URL")?),
-    )
-    .set_redirect_uri(
-        RedirectUrl::new(redirect_url).context("failed to create new redirection URL")?,
-    ));
+///
+/// routes of /api, and the OpenAPI document of them. the document is served at /api/v1/openapi.json
+///
+fn api() -> (Router<app_state::AppState>, utoipa::openapi::OpenApi) {
+    let (router, doc) = OpenApiRouter::with_openapi(openapi::ApiDoc::openapi())
+        .routes(routes!(api::list_providers))
+        .routes(routes!(api::whoami))
+        .routes(routes!(api::list_instances, api::create_instance))
+        .routes(routes!(api::get_instance))
+        .routes(routes!(api::remove_instance))
+        .routes(routes!(api::extend_instance))
+        .routes(routes!(events::stream_events))
+        .routes(routes!(snapshots::list_snapshots))
+        .routes(routes!(snapshots::create_snapshot))
+        .routes(routes!(snapshots::download_snapshot))
+        .routes(routes!(snapshots::delete_snapshot))
+        .routes(routes!(exports::list_exports))
+        .routes(routes!(exports::download_export))
+        .routes(routes!(exports::delete_export))
+        .routes(routes!(
+            api::get_queue_position,
+            api::join_queue,
+            api::leave_queue
+        ))
+        .routes(routes!(tokens::list_tokens, tokens::create_token))
+        .routes(routes!(tokens::revoke_token))
+        .routes(routes!(admin::list_instances))
+        .routes(routes!(admin::remove_instance))
+        .routes(routes!(admin::extend_instance))
+        .routes(routes!(admin::list_roles))
+        .routes(routes!(admin::set_role))
+        .split_for_parts();
+    let spec = doc
+        .to_pretty_json()
+        .expect("failed to serialize OpenAPI document");
+
+    let router = router
+        .route(
+            "/api/v1/openapi.json",
+            get(|| async move { ([(header::CONTENT_TYPE, "application/json")], spec) }),
+        )
+        .route("/api/{*path}", any(api::unknown_endpoint));
+
+    return (router, doc);
 }
 
-// The user data we'll get back from Discord.
-// https://discord.com/developers/docs/resources/user#user-object-user-structure
-#[derive(Debug, Serialize, Deserialize)]
-struct User {
+// The user data we'll get back from an identity provider. IDs from providers other than
+// discord are prefixed with the provider (such as github:1234)
+#[derive(Debug, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct User {
     id: String,
     username: String,
     discriminator: String,
 }
 
-async fn discord_auth(
-    State(client): State<BasicClient>,
-    State(store): State<MemoryStore>,
+async fn provider_auth(
+    Path(provider): Path<String>,
+    State(state): State<app_state::AppState>,
 ) -> Result<impl IntoResponse, AppError> {
-    let (auth_url, csrf_token) = client
+    let store = &state.store;
+    let Some(provider) = state.identity.get(&provider) else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            format!("unknown login provider {provider}"),
+        )
+            .into_response());
+    };
+
+    let mut request = provider
+        .oauth_client()
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("identify".to_string()))
-        .url();
+        .add_scopes(provider.scopes());
+
+    // comes back in the ID token, so a token from another login cannot be used in this one
+    let nonce = provider
+        .uses_nonce()
+        .then(|| CsrfToken::new_random().secret().clone());
+    if let Some(nonce) = &nonce {
+        request = request.add_extra_param("nonce", nonce.clone());
+    }
+    let (auth_url, csrf_token) = request.url();
 
-    // Create session to store csrf_token
+    // Create session to store csrf_token, and which provider it is for
     let mut session = Session::new();
+    session.expire_in(CSRF_SESSION_TTL);
     session
         .insert(CSRF_TOKEN, &csrf_token)
         .context("failed in inserting CSRF token into session")?;
+    session
+        .insert(LOGIN_PROVIDER, provider.id())
+        .context("failed in inserting provider into session")?;
+    if let Some(nonce) = &nonce {
+        session
+            .insert(LOGIN_NONCE, nonce)
+            .context("failed in inserting nonce into session")?;
+    }
 
-    // Store the session in MemoryStore and retrieve the session cookie
+    // Store the session and retrieve the session cookie
     let cookie = store
         .store_session(session)
         .await
@@ -151,11 +296,11 @@ async fn discord_auth(
         cookie.parse().context("failed to parse cookie")?,
     );
 
-    return Ok((headers, Redirect::to(auth_url.as_ref())));
+    return Ok((headers, Redirect::to(auth_url.as_ref())).into_response());
 }
 
 async fn logout(
-    State(store): State<MemoryStore>,
+    State(store): State<SqliteSessionStore>,
     TypedHeader(cookies): TypedHeader<headers::Cookie>,
 ) -> Result<impl IntoResponse, AppError> {
     let cookie = cookies
@@ -187,11 +332,16 @@ struct AuthRequest {
     state: String,
 }
 
+///
+/// check the login being finished is the one started in this browser, returning the nonce it sent
+/// if any
+///
 async fn csrf_token_validation_workflow(
     auth_request: &AuthRequest,
+    provider: &str,
     cookies: &headers::Cookie,
-    store: &MemoryStore,
-) -> Result<(), AppError> {
+    store: &SqliteSessionStore,
+) -> Result<Option<String>, AppError> {
     // Extract the cookie from the request
     let cookie = cookies
         .get(COOKIE_NAME)
@@ -213,6 +363,10 @@ async fn csrf_token_validation_workflow(
         .get::<CsrfToken>(CSRF_TOKEN)
         .context("CSRF token not found in session")?
         .to_owned();
+    let stored_provider = session
+        .get::<String>(LOGIN_PROVIDER)
+        .context("login provider not found in session")?;
+    let nonce = session.get::<String>(LOGIN_NONCE);
 
     // Cleanup the CSRF token session
     store
@@ -225,39 +379,77 @@ async fn csrf_token_validation_workflow(
         return Err(anyhow!("CSRF token mismatch").into());
     }
 
-    return Ok(());
+    // don't let a login started with one provider be finished with another
+    if stored_provider != provider {
+        return Err(
+            anyhow!("login started with {stored_provider}, but finished with {provider}").into(),
+        );
+    }
+
+    return Ok(nonce);
+}
+
+///
+/// callback discord apps made before there were other providers send users to
+///
+async fn discord_callback(
+    query: Query<AuthRequest>,
+    state: State<app_state::AppState>,
+    cookies: TypedHeader<headers::Cookie>,
+) -> Result<impl IntoResponse, AppError> {
+    return login_authorized(Path("discord".to_string()), query, state, cookies).await;
 }
 
 async fn login_authorized(
+    Path(provider): Path<String>,
     Query(query): Query<AuthRequest>,
-    State(store): State<MemoryStore>,
-    State(oauth_client): State<BasicClient>,
+    State(state): State<app_state::AppState>,
     TypedHeader(cookies): TypedHeader<headers::Cookie>,
 ) -> Result<impl IntoResponse, AppError> {
-    csrf_token_validation_workflow(&query, &cookies, &store).await?;
+    let store = &state.store;
+    let Some(provider) = state.identity.get(&provider) else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            format!("unknown login provider {provider}"),
+        )
+            .into_response());
+    };
+
+    let nonce = csrf_token_validation_workflow(&query, provider.id(), &cookies, store).await?;
 
     // Get an auth token
-    let token = oauth_client
+    let token = provider
+        .oauth_client()
         .exchange_code(AuthorizationCode::new(query.code.clone()))
         .request_async(async_http_client)
         .await
         .context("failed in sending request request to authorization server")?;
+    provider
+        .check_id_token(token.extra_fields().id_token.as_deref(), nonce.as_deref())
+        .context("rejected ID token")?;
+    let token = token.access_token().secret();
 
-    // Fetch user data from discord
-    let client = reqwest::Client::new();
-    let user_data: User = client
-        // https://discord.com/developers/docs/resources/user#get-current-user
-        .get("https://discordapp.com/api/users/@me")
-        .bearer_auth(token.access_token().secret())
-        .send()
-        .await
-        .context("failed in sending request to target Url")?
-        .json::<User>()
+    // Fetch user data from the provider
+    let user_data: User = provider.user(token).await?;
+
+    // such as only letting in members of some discord guilds
+    if !provider
+        .is_allowed(token)
         .await
-        .context("failed to deserialize response as JSON")?;
+        .context("failed to check if user is allowed to log in")?
+    {
+        tracing::info!(
+            "rejecting {} login from {}/{}, not allowed by the provider",
+            provider.id(),
+            &user_data.id,
+            &user_data.username
+        );
+        return Ok(Redirect::to("/denied.html").into_response());
+    }
 
     // Create a new session filled with user data
     let mut session = Session::new();
+    session.expire_in(SESSION_TTL);
     session
         .insert("user", &user_data)
         .context("failed in inserting serialized value into session")?;
@@ -269,8 +461,11 @@ async fn login_authorized(
         .context("failed to store session")?
         .context("unexpected error retrieving cookie value")?;
 
-    // Build the cookie
-    let cookie = format!("{COOKIE_NAME}={cookie}; SameSite=Lax; HttpOnly; Secure; Path=/");
+    // Build the cookie, keeping it around in the browser as long as the session lives
+    let cookie = format!(
+        "{COOKIE_NAME}={cookie}; SameSite=Lax; HttpOnly; Secure; Path=/; Max-Age={}",
+        SESSION_TTL.as_secs()
+    );
 
     // Set cookie
     let mut headers = HeaderMap::new();
@@ -279,59 +474,130 @@ async fn login_authorized(
         cookie.parse().context("failed to parse cookie")?,
     );
 
-    return Ok((headers, Redirect::to("/")));
+    return Ok((headers, Redirect::to("/")).into_response());
+}
+
+impl<S> FromRequestParts<S> for User
+where
+    SqliteSessionStore: FromRef<S>,
+    db::Database: FromRef<S>,
+    Arc<config::Config>: FromRef<S>,
+    S: Send + Sync,
+{
+    // API requests that are not logged in get a 401, which the frontend uses to show the login buttons
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = Arc::<config::Config>::from_ref(state);
+        let db = db::Database::from_ref(state);
+
+        // scripts use an API token instead of the session cookie. other kinds of Authorization
+        // header, such as basic auth from a proxy in front of ink, are left alone
+        let user = match bearer_token(&parts.headers) {
+            Some(token) => user_from_token(parts, &token, &db)?,
+            None => user_from_session(parts, state).await?,
+        };
+
+        // checked on every request, so bans apply to users who are already logged in
+        match roles::role_of(&config, &db, &user.id) {
+            Ok(roles::Role::Banned) => return Err(ApiError::Banned),
+            Ok(_) => {}
+            Err(e) => {
+                return Err(ApiError::Internal(
+                    e.context(format!("failed to get role of {}", user.id)),
+                ));
+            }
+        }
+
+        return Ok(user);
+    }
 }
 
-struct AuthRedirect;
+///
+/// the token of an `Authorization: Bearer` header, if the request has one
+///
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
 
-impl IntoResponse for AuthRedirect {
-    fn into_response(self) -> Response {
-        return Redirect::temporary("/auth/discord").into_response();
+    // the scheme is case insensitive (https://www.rfc-editor.org/rfc/rfc9110#section-11.1)
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return None;
     }
+    return Some(token.trim().to_string());
 }
 
-impl<S> FromRequestParts<S> for User
+///
+/// get the user from an API token, making sure the token can be used for the request
+///
+fn user_from_token(parts: &Parts, token: &str, db: &db::Database) -> Result<User, ApiError> {
+    let owner = match db.use_token(&tokens::hash(token)) {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return Err(ApiError::InvalidToken),
+        Err(e) => return Err(ApiError::Internal(e.context("failed to look up API token"))),
+    };
+
+    match tokens::TokenScope::required_for(&parts.method, parts.uri.path()) {
+        None => {
+            return Err(ApiError::MissingScope(
+                "API tokens cannot be used for this".to_string(),
+            ));
+        }
+        Some(scope) if !owner.scopes.contains(&scope) => {
+            return Err(ApiError::MissingScope(format!(
+                "API token is missing the {scope} scope"
+            )));
+        }
+        Some(_) => {}
+    }
+
+    return Ok(User {
+        id: owner.owner_id,
+        username: owner.username,
+        discriminator: "0".to_string(),
+    });
+}
+
+///
+/// get the user from the session cookie set when they logged in
+///
+async fn user_from_session<S>(parts: &mut Parts, state: &S) -> Result<User, ApiError>
 where
-    MemoryStore: FromRef<S>,
+    SqliteSessionStore: FromRef<S>,
     S: Send + Sync,
 {
-    // If anything goes wrong or no session is found, redirect to the auth page
-    type Rejection = AuthRedirect;
+    let store = SqliteSessionStore::from_ref(state);
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let store = MemoryStore::from_ref(state);
+    let cookies = parts
+        .extract::<TypedHeader<headers::Cookie>>()
+        .await
+        .map_err(|e| match e.reason() {
+            TypedHeaderRejectionReason::Missing => ApiError::NotLoggedIn,
+            _ => ApiError::BadRequest(format!("invalid cookie header: {e}")),
+        })?;
 
-        let cookies = parts
-            .extract::<TypedHeader<headers::Cookie>>()
-            .await
-            .map_err(|e| match *e.name() {
-                header::COOKIE => match e.reason() {
-                    TypedHeaderRejectionReason::Missing => AuthRedirect,
-                    _ => panic!("unexpected error getting Cookie header(s): {e}"),
-                },
-                _ => panic!("unexpected error getting cookies: {e}"),
-            })?;
-
-        let session_cookie = cookies.get(COOKIE_NAME).ok_or(AuthRedirect)?;
-
-        let session = store
-            .load_session(session_cookie.to_string())
-            .await
-            .unwrap()
-            .ok_or(AuthRedirect)?;
+    let session_cookie = cookies.get(COOKIE_NAME).ok_or(ApiError::NotLoggedIn)?;
 
-        let user = session.get::<User>("user").ok_or(AuthRedirect)?;
+    let session = store
+        .load_session(session_cookie.to_string())
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to load session: {e}"))?
+        .ok_or(ApiError::NotLoggedIn)?;
 
-        return Ok(user);
-    }
+    let user = session.get::<User>("user").ok_or(ApiError::NotLoggedIn)?;
+
+    return Ok(user);
 }
 
 impl<S> OptionalFromRequestParts<S> for User
 where
-    MemoryStore: FromRef<S>,
+    SqliteSessionStore: FromRef<S>,
+    db::Database: FromRef<S>,
+    Arc<config::Config>: FromRef<S>,
     S: Send + Sync,
 {
-    type Rejection = Infallible;
+    // not being logged in is fine, but being banned is not
+    type Rejection = ApiError;
 
     async fn from_request_parts(
         parts: &mut Parts,
@@ -339,7 +605,8 @@ where
     ) -> Result<Option<Self>, Self::Rejection> {
         match <User as FromRequestParts<S>>::from_request_parts(parts, state).await {
             Ok(res) => Ok(Some(res)),
-            Err(AuthRedirect) => Ok(None),
+            Err(ApiError::NotLoggedIn) => Ok(None),
+            Err(e) => Err(e),
         }
     }
 }
@@ -369,43 +636,320 @@ where
     }
 }
 
-/// background thread that cleans up old squittal instances
-async fn instance_cleanup() {
-    loop {
-        let instances = docker::get_instances().await;
-        if instances.is_err() {
-            eprintln!("failed to perform cleanup: {}", instances.unwrap_err());
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            continue;
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{body::Body, response::Response};
+    use http::{Method, Request};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const METHODS: [Method; 5] = [
+        Method::GET,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+    ];
+
+    ///
+    /// the methods of each path in the OpenAPI document, with path parameters filled in
+    ///
+    fn documented(doc: &utoipa::openapi::OpenApi) -> Vec<(String, Vec<Method>)> {
+        return doc
+            .paths
+            .paths
+            .iter()
+            .map(|(path, item)| {
+                let path = path
+                    .split('/')
+                    .map(|part| if part.starts_with('{') { "1" } else { part })
+                    .collect::<Vec<_>>()
+                    .join("/");
+
+                let methods = [
+                    (Method::GET, &item.get),
+                    (Method::POST, &item.post),
+                    (Method::PUT, &item.put),
+                    (Method::PATCH, &item.patch),
+                    (Method::DELETE, &item.delete),
+                ]
+                .into_iter()
+                .filter(|(_, op)| op.is_some())
+                .map(|(method, _)| method)
+                .collect();
+
+                return (path, methods);
+            })
+            .collect();
+    }
+
+    ///
+    /// send a request to the API, returning the status and body
+    ///
+    async fn send(router: &Router, method: Method, path: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        return (status, String::from_utf8_lossy(&body).to_string());
+    }
+
+    #[tokio::test]
+    async fn documented_endpoints_are_routed() {
+        let (router, doc) = api();
+        let router = router.with_state(test_util::state(config::Config::default()).await);
+
+        let documented = documented(&doc);
+        assert!(!documented.is_empty());
+
+        for (path, methods) in documented {
+            assert!(path.starts_with("/api/"), "{path} is not under /api");
+
+            for method in methods {
+                let (status, body) = send(&router, method.clone(), &path).await;
+                assert_ne!(
+                    status,
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{method} {path} is documented but not routed"
+                );
+                assert!(
+                    !body.contains("is not an API endpoint"),
+                    "{method} {path} is documented but not routed"
+                );
+            }
         }
+    }
 
-        let instances = instances.unwrap();
-
-        let now: std::time::SystemTime = std::time::SystemTime::now();
-        for instance in instances {
-            let diff: Duration = now
-                .duration_since(instance.created_on)
-                .expect("failed to diff time");
-
-            let name: String = if instance.name.starts_with("/") {
-                instance.name[1..].to_string()
-            } else {
-                instance.name
-            };
-
-            if diff >= Duration::from_secs(60 * 60 * 4) {
-                println!("killing {}, diff={:?}", name, diff);
-                let result = docker::remove_container(&name).await;
-                if result.is_err() {
-                    eprintln!(
-                        "failed to remove instance {}: {}",
-                        name,
-                        result.unwrap_err()
-                    );
-                }
+    #[tokio::test]
+    async fn routed_methods_are_documented() {
+        let (router, doc) = api();
+        let router = router.with_state(test_util::state(config::Config::default()).await);
+
+        for (path, methods) in documented(&doc) {
+            for method in METHODS.iter().filter(|m| !methods.contains(m)) {
+                let (status, _) = send(&router, method.clone(), &path).await;
+                assert_eq!(
+                    status,
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{method} {path} is routed but not documented"
+                );
             }
         }
+    }
+
+    #[tokio::test]
+    async fn undocumented_paths_are_not_routed() {
+        let (router, doc) = api();
+        let router = router.with_state(test_util::state(config::Config::default()).await);
+
+        // the document itself is the only API route that is not in it
+        assert!(!doc.paths.paths.contains_key("/api/v1/openapi.json"));
+        let (status, _) = send(&router, Method::GET, "/api/v1/openapi.json").await;
+        assert_eq!(status, StatusCode::OK);
+
+        for method in METHODS {
+            let (status, body) = send(&router, method.clone(), "/api/v1/nothing").await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{method} /api/v1/nothing");
+            assert!(body.contains("is not an API endpoint"));
+        }
+    }
+
+    ///
+    /// the login routes, where users log in with `issuer` (as corp) or `github`
+    ///
+    async fn login_router(
+        issuer: &test_util::Issuer,
+        github: &str,
+    ) -> (Router, app_state::AppState) {
+        let mut config = config::Config::default();
+        config.oidc.insert("corp".to_string(), issuer.config());
+        config.github.api_url = format!("{github}/api");
+        config.github.oauth_url = format!("{github}/login/oauth");
+        let state = test_util::state(config).await;
+
+        let router = Router::new()
+            .route("/auth/{provider}", get(provider_auth))
+            .route("/auth/{provider}/callback", get(login_authorized))
+            .with_state(state.clone());
+        return (router, state);
+    }
+
+    ///
+    /// start logging in with `provider`, returning the query of the URL the user is sent to, and the
+    /// cookie of the login
+    ///
+    async fn start_login(router: &Router, provider: &str) -> (HashMap<String, String>, String) {
+        let request = Request::get(format!("/auth/{provider}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let location = response.headers()[header::LOCATION].to_str().unwrap();
+        let query = url::Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+
+        return (query, session_cookie(&response).unwrap());
+    }
+
+    async fn finish_login(
+        router: &Router,
+        provider: &str,
+        code: &str,
+        csrf_state: &str,
+        cookie: &str,
+    ) -> Response {
+        let request = Request::get(format!(
+            "/auth/{provider}/callback?code={code}&state={csrf_state}"
+        ))
+        .header(header::COOKIE, cookie)
+        .body(Body::empty())
+        .unwrap();
+        return router.clone().oneshot(request).await.unwrap();
+    }
+
+    fn session_cookie(response: &Response) -> Option<String> {
+        let cookie = response.headers().get(SET_COOKIE)?.to_str().unwrap();
+        return Some(cookie.split(';').next().unwrap().to_string());
+    }
+
+    ///
+    /// the user a finished login is logged in as, if any
+    ///
+    async fn logged_in(state: &app_state::AppState, response: &Response) -> Option<User> {
+        if response.headers().get(header::LOCATION)? != "/" {
+            return None;
+        }
+
+        let cookie = session_cookie(response)?;
+        let session = state
+            .store
+            .load_session(cookie.trim_start_matches("INK_SESSION=").to_string())
+            .await
+            .unwrap()?;
+        return session.get::<User>("user");
+    }
+
+    #[tokio::test]
+    async fn oidc_login() {
+        let (issuer, github) = (test_util::issuer().await, test_util::github().await);
+        let (router, state) = login_router(&issuer, &github).await;
+
+        let (query, cookie) = start_login(&router, "corp").await;
+        assert_eq!(query["client_id"], "ink");
+        assert_eq!(query["scope"], "openid profile");
+        issuer.claims.lock().unwrap()["nonce"] = json!(query["nonce"]);
+
+        let response = finish_login(
+            &router,
+            "corp",
+            test_util::LOGIN_CODE,
+            &query["state"],
+            &cookie,
+        )
+        .await;
+        let user = logged_in(&state, &response).await.unwrap();
+        assert_eq!(user.id, "corp:1234");
+        assert_eq!(user.username, "someone");
+    }
+
+    #[tokio::test]
+    async fn oidc_login_with_other_nonce() {
+        let (issuer, github) = (test_util::issuer().await, test_util::github().await);
+        let (router, state) = login_router(&issuer, &github).await;
+
+        // such as an ID token from an earlier login
+        let (first, _) = start_login(&router, "corp").await;
+        issuer.claims.lock().unwrap()["nonce"] = json!(first["nonce"]);
+
+        let (query, cookie) = start_login(&router, "corp").await;
+        assert_ne!(query["nonce"], first["nonce"]);
+
+        let response = finish_login(
+            &router,
+            "corp",
+            test_util::LOGIN_CODE,
+            &query["state"],
+            &cookie,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(logged_in(&state, &response).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn github_login() {
+        let (issuer, github) = (test_util::issuer().await, test_util::github().await);
+        let (router, state) = login_router(&issuer, &github).await;
+
+        let (query, cookie) = start_login(&router, "github").await;
+        assert!(!query.contains_key("nonce"));
+
+        let response = finish_login(
+            &router,
+            "github",
+            test_util::LOGIN_CODE,
+            &query["state"],
+            &cookie,
+        )
+        .await;
+        let user = logged_in(&state, &response).await.unwrap();
+        assert_eq!(user.id, "github:1234");
+        assert_eq!(user.username, "someone");
+    }
+
+    #[tokio::test]
+    async fn login_rejections() {
+        let (issuer, github) = (test_util::issuer().await, test_util::github().await);
+        let (router, state) = login_router(&issuer, &github).await;
+
+        for provider in ["corp", "github"] {
+            // the provider does not give out a token for the code
+            let (query, cookie) = start_login(&router, provider).await;
+            issuer.claims.lock().unwrap()["nonce"] = json!(query.get("nonce"));
+            let response = finish_login(&router, provider, "wrong", &query["state"], &cookie).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert!(logged_in(&state, &response).await.is_none());
+
+            // the login was not started in this browser
+            let (query, cookie) = start_login(&router, provider).await;
+            issuer.claims.lock().unwrap()["nonce"] = json!(query.get("nonce"));
+            let response =
+                finish_login(&router, provider, test_util::LOGIN_CODE, "wrong", &cookie).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert!(logged_in(&state, &response).await.is_none());
+        }
 
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        // a login started with github cannot be finished with another provider
+        let (query, cookie) = start_login(&router, "github").await;
+        let response = finish_login(
+            &router,
+            "corp",
+            test_util::LOGIN_CODE,
+            &query["state"],
+            &cookie,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(logged_in(&state, &response).await.is_none());
+
+        // unknown providers are not found
+        let request = Request::get("/auth/missing").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 }
@@ -0,0 +1,257 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rand::{Rng, distr::Alphanumeric};
+use tiberius::{AuthMethod, Client};
+use tokio::net::TcpStream;
+use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
+
+use crate::{config::MssqlConfig, db::unix_time};
+
+///
+/// the database and login made for an instance
+///
+#[derive(Debug, Clone)]
+pub struct DatabaseLogin {
+    pub database: String,
+    pub login: String,
+    pub password: String,
+}
+
+impl DatabaseLogin {
+    ///
+    /// the connection string squittal uses to reach this database
+    ///
+    pub fn connection_string(&self, config: &MssqlConfig) -> String {
+        return format!(
+            "Server={},{};Database={};User Id={};Password={};TrustServerCertificate={}",
+            config.host,
+            config.port,
+            self.database,
+            self.login,
+            self.password,
+            config.trust_server_certificate
+        );
+    }
+}
+
+///
+/// creates and removes the database of each instance. [`MssqlServer`] is the real implementation, and
+/// [`crate::fake_mssql::FakeMssql`] keeps databases in memory for running without an MSSQL server
+///
+#[async_trait]
+pub trait InstanceDatabases: Send + Sync {
+    ///
+    /// create a database for `instance`, with a login that can only use that database
+    ///
+    async fn create(&self, instance: &str) -> Result<DatabaseLogin>;
+
+    ///
+    /// drop the database and login of `instance`, backing the database up first if `archive` is set.
+    /// does nothing if the instance has no database
+    ///
+    async fn remove(&self, instance: &str, archive: bool) -> Result<()>;
+}
+
+///
+/// name of the database and login of an instance. instance names are made from the word lists, but are
+/// still quoted wherever they are used in SQL
+///
+pub fn database_name(instance: &str) -> String {
+    return format!("squittal_{}", instance.replace('-', "_"));
+}
+
+///
+/// where the connection string of an instance is kept on the host
+///
+fn connection_file_path(config: &MssqlConfig, instance: &str) -> PathBuf {
+    return Path::new(&config.secrets_dir).join(instance);
+}
+
+///
+/// write the connection string of `login` to a file for `instance`, returning the absolute path of the
+/// file so docker can mount it into the container
+///
+pub async fn write_connection_file(
+    config: &MssqlConfig,
+    instance: &str,
+    login: &DatabaseLogin,
+) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(&config.secrets_dir)
+        .await
+        .with_context(|| format!("failed to create {}", config.secrets_dir))?;
+
+    // only ink can look in the directory. the file itself is mounted into the container, where
+    // squittal may run as any user
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(0o700);
+        tokio::fs::set_permissions(&config.secrets_dir, permissions)
+            .await
+            .with_context(|| format!("failed to restrict {}", config.secrets_dir))?;
+    }
+
+    let path = connection_file_path(config, instance);
+    tokio::fs::write(&path, login.connection_string(config))
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    return tokio::fs::canonicalize(&path)
+        .await
+        .with_context(|| format!("failed to get absolute path of {}", path.display()));
+}
+
+///
+/// remove the connection string file of `instance`, if it has one
+///
+pub async fn remove_connection_file(config: &MssqlConfig, instance: &str) -> Result<()> {
+    let path = connection_file_path(config, instance);
+    return match tokio::fs::remove_file(&path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            Err(anyhow::Error::new(e).context(format!("failed to remove {}", path.display())))
+        }
+    };
+}
+
+///
+/// databases on an MSSQL server, managed with the admin login from the config
+///
+pub struct MssqlServer {
+    config: MssqlConfig,
+}
+
+impl MssqlServer {
+    pub fn new(config: &MssqlConfig) -> Self {
+        return MssqlServer {
+            config: config.clone(),
+        };
+    }
+
+    async fn connect(&self) -> Result<Client<Compat<TcpStream>>> {
+        let mut config = tiberius::Config::new();
+        config.host(&self.config.admin_host);
+        config.port(self.config.admin_port);
+        config.authentication(AuthMethod::sql_server(
+            &self.config.admin_user,
+            &self.config.admin_password,
+        ));
+        config.application_name("ink");
+        if self.config.trust_server_certificate {
+            config.trust_cert();
+        }
+
+        let tcp = TcpStream::connect(config.get_addr()).await?;
+        tcp.set_nodelay(true)?;
+
+        return Ok(Client::connect(config, tcp.compat_write()).await?);
+    }
+
+    ///
+    /// run each batch in order, stopping at the first that fails
+    ///
+    async fn run(&self, batches: &[String]) -> Result<()> {
+        let mut client = self.connect().await?;
+        for batch in batches {
+            tracing::trace!("running on mssql: {}", batch);
+            client
+                .simple_query(batch.as_str())
+                .await?
+                .into_results()
+                .await?;
+        }
+        client.close().await?;
+
+        return Ok(());
+    }
+}
+
+#[async_trait]
+impl InstanceDatabases for MssqlServer {
+    async fn create(&self, instance: &str) -> Result<DatabaseLogin> {
+        let name = database_name(instance);
+        let password: String = rand::rng()
+            .sample_iter(Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        let ident = quote_ident(&name);
+        self.run(&[
+            format!("CREATE DATABASE {ident}"),
+            // the password is random, so the password policy of the server is not needed
+            format!(
+                "CREATE LOGIN {ident} WITH PASSWORD = {}, DEFAULT_DATABASE = {ident}, CHECK_POLICY = OFF",
+                quote_literal(&password)
+            ),
+            format!(
+                "USE {ident}; CREATE USER {ident} FOR LOGIN {ident}; ALTER ROLE db_owner ADD MEMBER {ident}"
+            ),
+        ])
+        .await?;
+
+        tracing::info!("created database {} for instance {}", name, instance);
+        return Ok(DatabaseLogin {
+            database: name.clone(),
+            login: name,
+            password,
+        });
+    }
+
+    async fn remove(&self, instance: &str, archive: bool) -> Result<()> {
+        let name = database_name(instance);
+        let (ident, literal) = (quote_ident(&name), quote_literal(&name));
+
+        let mut drop_database = format!(
+            "IF DB_ID({literal}) IS NOT NULL BEGIN ALTER DATABASE {ident} SET SINGLE_USER WITH ROLLBACK IMMEDIATE; "
+        );
+        if archive {
+            let path = format!(
+                "{}/{}-{}.bak",
+                self.config.archive_dir.trim_end_matches('/'),
+                name,
+                unix_time(SystemTime::now())
+            );
+            drop_database.push_str(&format!(
+                "BACKUP DATABASE {ident} TO DISK = {} WITH COPY_ONLY; ",
+                quote_literal(&path)
+            ));
+        }
+        drop_database.push_str(&format!("DROP DATABASE {ident}; END"));
+
+        self.run(&[
+            drop_database,
+            format!("IF SUSER_ID({literal}) IS NOT NULL DROP LOGIN {ident}"),
+        ])
+        .await?;
+
+        tracing::info!(
+            "{} database {} of instance {}",
+            if archive { "archived" } else { "dropped" },
+            name,
+            instance
+        );
+        return Ok(());
+    }
+}
+
+///
+/// quote a name for SQL, such as a database or login
+///
+fn quote_ident(name: &str) -> String {
+    return format!("[{}]", name.replace(']', "]]"));
+}
+
+///
+/// quote a string for SQL
+///
+fn quote_literal(value: &str) -> String {
+    return format!("N'{}'", value.replace('\'', "''"));
+}
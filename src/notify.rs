@@ -0,0 +1,133 @@
+use serde::Serialize;
+
+use crate::{
+    config::NotificationConfig,
+    db::unix_time,
+    docker,
+    instance::{ExpiryWarning, SquittalInstance},
+};
+
+///
+/// tells owners their instance is about to expire. if a webhook is configured the warning is POSTed
+/// to it as JSON, otherwise the warning is only logged
+///
+#[derive(Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    webhook_url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExpiryNotification<'a> {
+    event: &'static str,
+    instance: &'a str,
+    owner: &'a str,
+    expires_at: i64,
+    expires_in_secs: u64,
+    threshold_secs: u64,
+}
+
+impl Notifier {
+    pub fn new(config: &NotificationConfig) -> Self {
+        return Notifier {
+            client: reqwest::Client::new(),
+            webhook_url: config.webhook_url.clone(),
+        };
+    }
+
+    pub async fn expiring(&self, instance: SquittalInstance, warning: ExpiryWarning) {
+        let name = docker::instance_name(&instance.name);
+
+        tracing::info!(
+            "instance {} of {} expires in {}s",
+            name,
+            &instance.created_by,
+            warning.expires_in_secs
+        );
+
+        let url = match &self.webhook_url {
+            Some(url) => url,
+            None => return,
+        };
+
+        let body = ExpiryNotification {
+            event: "expiring",
+            instance: name,
+            owner: &instance.created_by,
+            expires_at: unix_time(instance.expires_at),
+            expires_in_secs: warning.expires_in_secs,
+            threshold_secs: warning.threshold_secs,
+        };
+
+        let result = self
+            .client
+            .post(url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+
+        if let Err(e) = result {
+            tracing::error!("failed to send expiry warning for {}: {}", name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::StatusCode;
+
+    use crate::config::Config;
+    use crate::test_util;
+
+    use super::*;
+
+    async fn instance() -> SquittalInstance {
+        let state = test_util::state(Config::default()).await;
+        let name = test_util::running_instance(&state, "1").await;
+        return state.index.get(&name).unwrap();
+    }
+
+    fn warning() -> ExpiryWarning {
+        return ExpiryWarning {
+            threshold_secs: 600,
+            expires_in_secs: 550,
+        };
+    }
+
+    #[tokio::test]
+    async fn posts_to_webhook() {
+        let (url, mut received) = test_util::webhook(StatusCode::OK).await;
+        let notifier = Notifier::new(&NotificationConfig {
+            webhook_url: Some(url),
+        });
+        let instance = instance().await;
+
+        notifier.expiring(instance.clone(), warning()).await;
+
+        let body = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body["event"], "expiring");
+        assert_eq!(body["instance"], docker::instance_name(&instance.name));
+        assert_eq!(body["owner"], "1");
+        assert_eq!(body["expires_at"], unix_time(instance.expires_at));
+        assert_eq!(body["expires_in_secs"], 550);
+        assert_eq!(body["threshold_secs"], 600);
+    }
+
+    #[tokio::test]
+    async fn webhook_fails() {
+        let (url, mut received) = test_util::webhook(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let notifier = Notifier::new(&NotificationConfig {
+            webhook_url: Some(url),
+        });
+
+        // only logged, the same as any other failure to send it
+        notifier.expiring(instance().await, warning()).await;
+        assert!(received.recv().await.is_some());
+    }
+}
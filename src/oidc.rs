@@ -0,0 +1,400 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use oauth2::Scope;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    User,
+    config::OidcConfig,
+    identity::{self, IdentityProvider, OauthClient},
+};
+
+///
+/// log in with any OpenID Connect provider, such as keycloak or google
+///
+pub struct OidcProvider {
+    name: String,
+    config: OidcConfig,
+    oauth: OauthClient,
+    issuer: String,
+    userinfo_endpoint: String,
+    client: reqwest::Client,
+}
+
+// https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+impl OidcProvider {
+    ///
+    /// set up the provider named `name`, getting its endpoints from the discovery document of the issuer
+    ///
+    pub async fn discover(name: &str, config: &OidcConfig, redirect_url: String) -> Result<Self> {
+        let client = reqwest::Client::new();
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer_url.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("failed to get {url}"))?
+            .error_for_status()
+            .with_context(|| format!("failed to get {url}"))?
+            .json()
+            .await
+            .context("failed to deserialize discovery document")?;
+
+        tracing::debug!("discovered oidc provider {}: {:?}", name, metadata);
+
+        // https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderConfigurationValidation
+        if metadata.issuer.trim_end_matches('/') != config.issuer_url.trim_end_matches('/') {
+            bail!(
+                "discovery document is for issuer {}, not {}",
+                metadata.issuer,
+                config.issuer_url
+            );
+        }
+
+        return Ok(OidcProvider {
+            name: name.to_string(),
+            config: config.clone(),
+            oauth: identity::oauth_client(
+                config.client_id.clone(),
+                config.client_secret.clone(),
+                metadata.authorization_endpoint,
+                metadata.token_endpoint,
+                redirect_url,
+            )?,
+            issuer: metadata.issuer,
+            userinfo_endpoint: metadata.userinfo_endpoint,
+            client,
+        });
+    }
+}
+
+///
+/// get a claim as a string. some providers use numbers for IDs
+///
+fn claim(claims: &Value, name: &str) -> Option<String> {
+    return match claims.get(name)? {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+}
+
+///
+/// get the claims of an ID token, without checking its signature
+///
+fn id_token_claims(id_token: &str) -> Result<Value> {
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .context("ID token is not a JWT")?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .context("failed to decode ID token")?;
+
+    return serde_json::from_slice(&payload).context("failed to deserialize ID token");
+}
+
+#[async_trait]
+impl IdentityProvider for OidcProvider {
+    fn id(&self) -> &str {
+        return &self.name;
+    }
+
+    fn display_name(&self) -> &str {
+        if self.config.display_name.is_empty() {
+            return &self.name;
+        }
+        return &self.config.display_name;
+    }
+
+    fn oauth_client(&self) -> &OauthClient {
+        return &self.oauth;
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        return self.config.scopes.iter().cloned().map(Scope::new).collect();
+    }
+
+    fn uses_nonce(&self) -> bool {
+        return true;
+    }
+
+    ///
+    /// the token comes straight from the token endpoint of the issuer, so the signature is not
+    /// checked (https://openid.net/specs/openid-connect-core-1_0.html#IDTokenValidation)
+    ///
+    fn check_id_token(&self, id_token: Option<&str>, nonce: Option<&str>) -> Result<()> {
+        let id_token = id_token.context("no ID token was sent with the access token")?;
+        let claims = id_token_claims(id_token)?;
+
+        let issuer = claim(&claims, "iss");
+        if issuer.as_deref() != Some(self.issuer.as_str()) {
+            bail!("ID token is from issuer {:?}, not {}", issuer, self.issuer);
+        }
+
+        // the audience is either one client ID, or a list of them
+        let client_id = &self.config.client_id;
+        let for_client = match claims.get("aud") {
+            Some(Value::String(aud)) => aud == client_id,
+            Some(Value::Array(aud)) => aud.iter().any(|a| a.as_str() == Some(client_id)),
+            _ => false,
+        };
+        if !for_client {
+            bail!("ID token is not for client {client_id}");
+        }
+        if let Some(party) = claim(&claims, "azp")
+            && party != *client_id
+        {
+            bail!("ID token is authorized for {party}, not {client_id}");
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match claims.get("exp").and_then(Value::as_u64) {
+            Some(expires) if expires > now => {}
+            _ => bail!("ID token has expired"),
+        }
+
+        if nonce.is_none() || claim(&claims, "nonce").as_deref() != nonce {
+            bail!("ID token nonce does not match the one sent when logging in");
+        }
+
+        return Ok(());
+    }
+
+    async fn user(&self, token: &str) -> Result<User> {
+        let claims: Value = self
+            .client
+            .get(&self.userinfo_endpoint)
+            .bearer_auth(token)
+            .send()
+            .await
+            .context("failed to get userinfo")?
+            .error_for_status()
+            .context("failed to get userinfo")?
+            .json()
+            .await
+            .context("failed to deserialize userinfo")?;
+
+        let id = claim(&claims, &self.config.id_claim).ok_or_else(|| {
+            anyhow!(
+                "userinfo from {} is missing the {} claim",
+                self.name,
+                self.config.id_claim
+            )
+        })?;
+        let username = claim(&claims, &self.config.username_claim).unwrap_or_else(|| id.clone());
+
+        return Ok(User {
+            id: format!("{}:{id}", self.name),
+            username,
+            discriminator: "0".to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::{self, ACCESS_TOKEN};
+
+    const NONCE: &str = "nonce";
+
+    async fn provider(issuer: &test_util::Issuer) -> OidcProvider {
+        return OidcProvider::discover(
+            "corp",
+            &issuer.config(),
+            "http://localhost/auth/corp/callback".to_string(),
+        )
+        .await
+        .unwrap();
+    }
+
+    ///
+    /// check an ID token with the claims of `issuer`, changed by `change`
+    ///
+    async fn check(issuer: &test_util::Issuer, change: impl FnOnce(&mut Value)) -> Result<()> {
+        let provider = provider(issuer).await;
+
+        let mut claims = issuer.claims.lock().unwrap().clone();
+        claims["nonce"] = json!(NONCE);
+        change(&mut claims);
+
+        return provider.check_id_token(Some(&test_util::jwt(&claims)), Some(NONCE));
+    }
+
+    #[tokio::test]
+    async fn discover() {
+        let issuer = test_util::issuer().await;
+
+        let provider = provider(&issuer).await;
+        assert_eq!(provider.issuer, issuer.url);
+        assert_eq!(
+            provider.userinfo_endpoint,
+            format!("{}/userinfo", issuer.url)
+        );
+        assert_eq!(
+            provider.oauth.auth_url().as_str(),
+            format!("{}/authorize", issuer.url)
+        );
+        assert_eq!(
+            provider.oauth.token_url().unwrap().as_str(),
+            format!("{}/token", issuer.url)
+        );
+    }
+
+    #[tokio::test]
+    async fn discover_with_trailing_slash() {
+        let issuer = test_util::issuer().await;
+        let mut config = issuer.config();
+        config.issuer_url.push('/');
+
+        let provider = OidcProvider::discover("corp", &config, "http://localhost".to_string())
+            .await
+            .unwrap();
+        assert_eq!(provider.issuer, issuer.url);
+    }
+
+    #[tokio::test]
+    async fn discover_other_issuer() {
+        let issuer = test_util::issuer().await;
+        issuer.claims.lock().unwrap()["iss"] = json!("https://id.example.com");
+
+        let result =
+            OidcProvider::discover("corp", &issuer.config(), "http://localhost".to_string()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn discover_missing_document() {
+        let issuer = test_util::issuer().await;
+        let mut config = issuer.config();
+        config.issuer_url = format!("{}/realms/missing", issuer.url);
+
+        let result = OidcProvider::discover("corp", &config, "http://localhost".to_string()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn user() {
+        let issuer = test_util::issuer().await;
+
+        let user = provider(&issuer).await.user(ACCESS_TOKEN).await.unwrap();
+        assert_eq!(user.id, "corp:1234");
+        assert_eq!(user.username, "someone");
+    }
+
+    #[tokio::test]
+    async fn user_with_other_claims() {
+        let issuer = test_util::issuer().await;
+        let mut provider = provider(&issuer).await;
+
+        // numbers are used as strings, and the ID is used for a missing or empty username
+        provider.config.id_claim = "number".to_string();
+        provider.config.username_claim = "empty".to_string();
+        let user = provider.user(ACCESS_TOKEN).await.unwrap();
+        assert_eq!(user.id, "corp:42");
+        assert_eq!(user.username, "42");
+
+        provider.config.id_claim = "missing".to_string();
+        assert!(provider.user(ACCESS_TOKEN).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn user_with_bad_token() {
+        let issuer = test_util::issuer().await;
+
+        assert!(provider(&issuer).await.user("wrong").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn id_token() {
+        let issuer = test_util::issuer().await;
+
+        assert!(check(&issuer, |_| {}).await.is_ok());
+        assert!(
+            check(&issuer, |c| c["aud"] = json!(["other", "ink"]))
+                .await
+                .is_ok()
+        );
+        assert!(check(&issuer, |c| c["azp"] = json!("ink")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn id_token_rejections() {
+        let issuer = test_util::issuer().await;
+
+        let other_issuer = format!("{}/other", issuer.url);
+        assert!(
+            check(&issuer, |c| c["iss"] = json!(other_issuer))
+                .await
+                .is_err()
+        );
+        assert!(check(&issuer, |c| c["aud"] = json!("other")).await.is_err());
+        assert!(
+            check(&issuer, |c| c["aud"] = json!(["other"]))
+                .await
+                .is_err()
+        );
+        assert!(check(&issuer, |c| c["azp"] = json!("other")).await.is_err());
+        assert!(
+            check(&issuer, |c| c["exp"] = json!(test_util::now() - 10))
+                .await
+                .is_err()
+        );
+        assert!(
+            check(&issuer, |c| c["nonce"] = json!("other"))
+                .await
+                .is_err()
+        );
+        assert!(
+            check(&issuer, |c| {
+                c.as_object_mut().unwrap().remove("nonce");
+            })
+            .await
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_id_token() {
+        let issuer = test_util::issuer().await;
+        let provider = provider(&issuer).await;
+
+        assert!(provider.check_id_token(None, Some(NONCE)).is_err());
+        assert!(
+            provider
+                .check_id_token(Some("not a jwt"), Some(NONCE))
+                .is_err()
+        );
+
+        // a login started without a nonce is never finished
+        let mut claims = issuer.claims.lock().unwrap().clone();
+        claims["nonce"] = json!(NONCE);
+        assert!(
+            provider
+                .check_id_token(Some(&test_util::jwt(&claims)), None)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use serde::Serialize;
+use utoipa::{
+    Modify, OpenApi, ToSchema,
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+
+use crate::COOKIE_NAME;
+
+///
+/// the OpenAPI document of /api/v1. the paths are added from the handlers as they are routed in main.rs,
+/// and the tests there check the document and the router have the same routes
+///
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "ink",
+        description = "squittal instances on demand. errors are always sent as {\"error\": {\"code\": \"...\", \"message\": \"...\"}}"
+    ),
+    modifiers(&SecuritySchemes),
+    security(("session" = []), ("token" = [])),
+    tags(
+        (name = "instances", description = "creating and managing your instance"),
+        (name = "queue", description = "waiting for an instance when all are in use"),
+        (name = "snapshots", description = "saving the data volume of your instance, and starting new instances from it"),
+        (name = "exports", description = "results saved from your instances before they were removed"),
+        (name = "tokens", description = "API tokens, which can only be managed when logged in on the site"),
+        (name = "users", description = "who is logged in, and how to log in"),
+        (name = "admin", description = "admin only, managing every instance and user"),
+    )
+)]
+pub struct ApiDoc;
+
+///
+/// how a SystemTime is sent in JSON
+///
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Timestamp {
+    pub secs_since_epoch: u64,
+    pub nanos_since_epoch: u32,
+}
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                COOKIE_NAME,
+                "set when logging in on the site",
+            ))),
+        );
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "API token made on the site. the read scope allows GET requests, write allows the rest, and admin allows /api/v1/admin",
+                    ))
+                    .build(),
+            ),
+        );
+    }
+}
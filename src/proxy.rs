@@ -3,42 +3,63 @@ use axum::{
     extract::{Request, State},
     http::uri::Uri,
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::{Html, IntoResponse, Response},
 };
-use http::StatusCode;
+use http::{StatusCode, header};
 use hyper_util::client::legacy::connect::HttpConnector;
 
-use crate::{app_state, docker, websocket_proxy};
+use crate::{app_state, instance::InstanceStatus, websocket_proxy};
 
 pub type Client = hyper_util::client::legacy::Client<HttpConnector, Body>;
 
+/// shown instead of the instance until it is ready, and reloads itself until then
+static STARTING_PAGE: &str = include_str!("../www/starting.html");
+
+/// shown when an instance cannot be reached, with {message} replaced by why
+static UNAVAILABLE_PAGE: &str = include_str!("../www/unavailable.html");
+
+///
+/// a page saying the instance cannot be reached. `message` is put in the page as is, so it must not come
+/// from the request or from an error
+///
+fn unavailable(status: StatusCode, message: &str) -> Response {
+    return (status, Html(UNAVAILABLE_PAGE.replace("{message}", message))).into_response();
+}
+
 pub async fn proxy_handler(
     State(state): State<app_state::AppState>,
     mut request: Request,
     next: Next,
 ) -> Response {
-    let host = request.headers().get("host");
-
-    if host.is_some() {
-        let host = host.unwrap().to_str().unwrap();
-
-        let parts = host.split(".");
-        let parts = parts.collect::<Vec<&str>>();
-        if parts.len() < 1 {
-            panic!("how is there less than 1 part to the host in '{}'", host);
-        }
-
-        let instance = parts[0];
+    // a host that is not valid text cannot be the subdomain of an instance, so ink handles the request
+    let host = request
+        .headers()
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
 
-        let d = docker::get_instance_by_name(instance).await;
-        if d.is_err() {
-            tracing::error!("error getting docker instance: {}", d.unwrap_err());
-            panic!();
-        }
+    if let Some(host) = host {
+        let instance = host.split('.').next().unwrap_or_default().to_string();
 
-        let d = d.unwrap();
+        if let Some(d) = state.index.get(&instance) {
+            match d.status {
+                InstanceStatus::Ready => {}
+                InstanceStatus::Provisioning | InstanceStatus::Starting => {
+                    return (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        [(header::RETRY_AFTER, "5")],
+                        Html(STARTING_PAGE),
+                    )
+                        .into_response();
+                }
+                InstanceStatus::Failed => {
+                    return unavailable(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "This instance failed to start",
+                    );
+                }
+            }
 
-        if d.len() > 0 {
             let path = request.uri().path();
             let path_query = request
                 .uri()
@@ -46,34 +67,50 @@ pub async fn proxy_handler(
                 .map(|v| v.as_str())
                 .unwrap_or(path);
 
-            if path.starts_with("/DbAdmin")
-                || path.starts_with("/rulesets")
-                || path.starts_with("/TeamBuilder")
+            if state
+                .config
+                .proxy
+                .blocked_paths
+                .iter()
+                .any(|blocked| path.starts_with(blocked.as_str()))
             {
-                return axum::http::Response::builder()
-                    .status(StatusCode::FORBIDDEN)
-                    .body(Body::from("no permission to view this page"))
-                    .unwrap();
+                return (StatusCode::FORBIDDEN, "no permission to view this page").into_response();
             }
 
-            let port = d[0].port;
-            let uri = format!("http://127.0.0.1:{}{}", port, path_query);
+            if let Err(e) = state.db.record_proxied(&instance) {
+                tracing::error!("failed to record proxied event for {}: {}", instance, e);
+            }
+
+            state.activity.touch(&instance);
+
+            let Some(address) = d.address(&state.config.docker) else {
+                return unavailable(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "This instance cannot be reached right now",
+                );
+            };
+            let uri = format!("http://{}{}", address, path_query);
             tracing::debug!("proxying {} to {}", host, uri);
 
-            *request.uri_mut() = Uri::try_from(uri).unwrap();
+            *request.uri_mut() = match Uri::try_from(&uri) {
+                Ok(uri) => uri,
+                Err(e) => {
+                    tracing::error!("cannot proxy {} to invalid uri {}: {}", host, uri, e);
+                    return unavailable(StatusCode::BAD_GATEWAY, "This instance cannot be reached");
+                }
+            };
 
             if websocket_proxy::is_websocket_upgrade(request.headers()) {
                 tracing::trace!("Detected WebSocket upgrade request");
-                match websocket_proxy::handle_websocket(request, &format!("127.0.0.1:{}", port))
-                    .await
-                {
+                let guard = state.activity.connect(&instance);
+                match websocket_proxy::handle_websocket(request, &address, guard).await {
                     Ok(response) => return response,
                     Err(e) => {
                         tracing::error!("Failed to handle WebSocket upgrade: {}", e);
-                        return axum::http::Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::from(format!("WebSocket upgrade failed: {e}")))
-                            .unwrap();
+                        return unavailable(
+                            StatusCode::BAD_GATEWAY,
+                            "Could not connect to this instance",
+                        );
                     }
                 }
             } else {
@@ -82,12 +119,11 @@ pub async fn proxy_handler(
                         return r.into_response();
                     }
                     Err(e) => {
-                        tracing::error!("error proxying http request: {}", e);
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("error proxying http request: {}", e),
-                        )
-                            .into_response();
+                        tracing::error!("error proxying http request to {}: {}", instance, e);
+                        return unavailable(
+                            StatusCode::BAD_GATEWAY,
+                            "Could not get a response from this instance. It may have stopped",
+                        );
                     }
                 }
             }
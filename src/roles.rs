@@ -0,0 +1,92 @@
+use std::{fmt, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use axum::extract::{FromRef, FromRequestParts};
+use http::request::Parts;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{User, api_error::ApiError, config::Config, db::Database};
+
+///
+/// what a user is allowed to do in ink
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// can use the admin API, and manage anyone's instance
+    Admin,
+
+    /// can create and manage their own instance. everyone is a user unless given another role
+    User,
+
+    /// cannot use ink at all
+    Banned,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            Role::Admin => "admin",
+            Role::User => "user",
+            Role::Banned => "banned",
+        };
+    }
+
+    pub fn parse(role: &str) -> Result<Role> {
+        return match role {
+            "admin" => Ok(Role::Admin),
+            "user" => Ok(Role::User),
+            "banned" => Ok(Role::Banned),
+            _ => Err(anyhow!("unknown role '{role}'")),
+        };
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(self.as_str());
+    }
+}
+
+///
+/// get the role of a user. admins in the config are always admins, otherwise the role set
+/// from the admin API is used, and everyone else is a user
+///
+pub fn role_of(config: &Config, db: &Database, id: &str) -> Result<Role> {
+    if config.access.admins.iter().any(|a| a == id) {
+        return Ok(Role::Admin);
+    }
+
+    return Ok(db.get_role(id)?.unwrap_or(Role::User));
+}
+
+///
+/// a logged in user that is an admin. rejects everyone else with a 403
+///
+pub struct Admin(pub User);
+
+impl<S> FromRequestParts<S> for Admin
+where
+    crate::SqliteSessionStore: FromRef<S>,
+    Database: FromRef<S>,
+    Arc<Config>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = User::from_request_parts(parts, state).await?;
+
+        let config = Arc::<Config>::from_ref(state);
+        let db = Database::from_ref(state);
+
+        return match role_of(&config, &db, &user.id) {
+            Ok(Role::Admin) => Ok(Admin(user)),
+            Ok(_) => Err(ApiError::Forbidden("admin only".to_string())),
+            Err(e) => Err(ApiError::Internal(
+                e.context(format!("failed to get role of {}", user.id)),
+            )),
+        };
+    }
+}
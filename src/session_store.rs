@@ -0,0 +1,273 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use async_session::{Result, Session, SessionStore, async_trait, serde_json};
+use sqlite::{ConnectionThreadSafe, State};
+
+use crate::db::unix_time;
+
+///
+/// session store that keeps sessions in a sqlite database, so logins survive ink restarting
+///
+#[derive(Clone)]
+pub struct SqliteSessionStore {
+    conn: Arc<ConnectionThreadSafe>,
+}
+
+impl fmt::Debug for SqliteSessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.debug_struct("SqliteSessionStore").finish_non_exhaustive();
+    }
+}
+
+impl SqliteSessionStore {
+    ///
+    /// open (or create) the sqlite database at `path`, and make sure the session table exists
+    ///
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let mut conn = sqlite::Connection::open_thread_safe(path)
+            .with_context(|| format!("failed to open session database {path}"))?;
+        conn.set_busy_timeout(5000)?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS session (
+                id TEXT PRIMARY KEY NOT NULL,
+                expires INTEGER NULL,
+                session TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS session_expires ON session (expires);",
+        )
+        .context("failed to create session table")?;
+
+        return Ok(SqliteSessionStore {
+            conn: Arc::new(conn),
+        });
+    }
+
+    ///
+    /// delete all sessions that have expired, returning how many were removed
+    ///
+    pub async fn cleanup(&self) -> anyhow::Result<usize> {
+        return self
+            .blocking(|conn| {
+                // counted from what was deleted, as other statements on the connection change change_count
+                let mut stmt = conn.prepare(
+                    "DELETE FROM session WHERE expires IS NOT NULL AND expires <= ? RETURNING id",
+                )?;
+                stmt.bind((1, now()))?;
+
+                let mut removed = 0;
+                while let State::Row = stmt.next()? {
+                    removed += 1;
+                }
+
+                return Ok(removed);
+            })
+            .await;
+    }
+
+    ///
+    /// run `f` on the connection in a blocking task, as sqlite calls block the thread they are made on
+    ///
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&ConnectionThreadSafe) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let conn = self.conn.clone();
+        return tokio::task::spawn_blocking(move || f(&conn)).await?;
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+
+        let json = self
+            .blocking(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT session FROM session WHERE id = ? AND (expires IS NULL OR expires > ?)",
+                )?;
+                stmt.bind((1, id.as_str()))?;
+                stmt.bind((2, now()))?;
+
+                if let State::Done = stmt.next()? {
+                    return Ok(None);
+                }
+
+                return Ok(Some(stmt.read::<String, _>(0)?));
+            })
+            .await?;
+
+        let Some(json) = json else {
+            return Ok(None);
+        };
+        let session: Session = serde_json::from_str(&json)?;
+
+        return Ok(session.validate());
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let json = serde_json::to_string(&session)?;
+        let expires: Option<i64> = session.expiry().map(|e| e.timestamp());
+        let id = session.id().to_string();
+
+        self.blocking(move |conn| {
+            let mut stmt = conn.prepare(
+                "INSERT INTO session (id, expires, session) VALUES (?, ?, ?)
+                    ON CONFLICT(id) DO UPDATE SET expires = excluded.expires, session = excluded.session",
+            )?;
+            stmt.bind((1, id.as_str()))?;
+            stmt.bind((2, expires))?;
+            stmt.bind((3, json.as_str()))?;
+            stmt.next()?;
+
+            return Ok(());
+        })
+        .await?;
+
+        session.reset_data_changed();
+        return Ok(session.into_cookie_value());
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        let id = session.id().to_string();
+
+        return self
+            .blocking(move |conn| {
+                let mut stmt = conn.prepare("DELETE FROM session WHERE id = ?")?;
+                stmt.bind((1, id.as_str()))?;
+                stmt.next()?;
+
+                return Ok(());
+            })
+            .await;
+    }
+
+    async fn clear_store(&self) -> Result {
+        return self
+            .blocking(|conn| {
+                conn.execute("DELETE FROM session")?;
+                return Ok(());
+            })
+            .await;
+    }
+}
+
+///
+/// background task that purges expired sessions from the store every `interval`
+///
+pub async fn session_cleanup(store: SqliteSessionStore, interval: Duration) {
+    loop {
+        match store.cleanup().await {
+            Ok(0) => {}
+            Ok(n) => tracing::debug!("removed {} expired sessions", n),
+            Err(e) => tracing::error!("failed to remove expired sessions: {}", e),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+fn now() -> i64 {
+    return unix_time(SystemTime::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> SqliteSessionStore {
+        return SqliteSessionStore::open(":memory:").unwrap();
+    }
+
+    async fn stored_session(store: &SqliteSessionStore, user: &str) -> String {
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60 * 60));
+        session.insert("user", user).unwrap();
+
+        return store.store_session(session).await.unwrap().unwrap();
+    }
+
+    fn expire_all(store: &SqliteSessionStore) {
+        store
+            .conn
+            .execute("UPDATE session SET expires = 0")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn store_and_load() {
+        let store = store();
+        let cookie = stored_session(&store, "1").await;
+
+        let session = store.load_session(cookie).await.unwrap().unwrap();
+        assert_eq!(session.get::<String>("user").as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn store_updates_existing() {
+        let store = store();
+        let cookie = stored_session(&store, "1").await;
+
+        let mut session = store.load_session(cookie.clone()).await.unwrap().unwrap();
+        session.insert("user", "2").unwrap();
+        store.store_session(session).await.unwrap();
+
+        let session = store.load_session(cookie).await.unwrap().unwrap();
+        assert_eq!(session.get::<String>("user").as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn load_unknown() {
+        let store = store();
+        stored_session(&store, "1").await;
+
+        // a session that was never stored
+        let mut session = Session::new();
+        session.insert("user", "2").unwrap();
+        let cookie = session.into_cookie_value().unwrap();
+        assert!(store.load_session(cookie).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn destroy() {
+        let store = store();
+        let cookie = stored_session(&store, "1").await;
+        let kept = stored_session(&store, "2").await;
+
+        let session = store.load_session(cookie.clone()).await.unwrap().unwrap();
+        store.destroy_session(session).await.unwrap();
+
+        assert!(store.load_session(cookie).await.unwrap().is_none());
+        assert!(store.load_session(kept).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn expired_sessions() {
+        let store = store();
+        let cookie = stored_session(&store, "1").await;
+        expire_all(&store);
+        let kept = stored_session(&store, "2").await;
+
+        assert!(store.load_session(cookie).await.unwrap().is_none());
+        assert_eq!(store.cleanup().await.unwrap(), 1);
+        assert_eq!(store.cleanup().await.unwrap(), 0);
+        assert!(store.load_session(kept).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn sessions_without_expiry_are_kept() {
+        let store = store();
+        let mut session = Session::new();
+        session.insert("user", "1").unwrap();
+        let cookie = store.store_session(session).await.unwrap().unwrap();
+
+        assert_eq!(store.cleanup().await.unwrap(), 0);
+        assert!(store.load_session(cookie).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,546 @@
+use std::path::{Path as FsPath, PathBuf};
+
+use anyhow::{Context, Result};
+use axum::{
+    Json,
+    body::{Body, Bytes},
+    extract::{Path, State},
+    response::IntoResponse,
+};
+use futures_util::{StreamExt, TryStreamExt, stream::BoxStream};
+use http::{StatusCode, header};
+use rand::Rng;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use utoipa::ToSchema;
+
+use crate::{
+    User,
+    api::ensure_ready,
+    api_error::{ApiError, ErrorBody},
+    app_state::AppState,
+    db::Snapshot,
+    docker,
+};
+
+///
+/// path of a snapshot tarball
+///
+fn file_path(state: &AppState, file: &str) -> PathBuf {
+    return FsPath::new(&state.config.snapshots.dir).join(file);
+}
+
+///
+/// directory in the container that a snapshot is extracted into. a snapshot is a tar of the data path,
+/// with the data path itself at the top, so it goes in the directory above
+///
+fn restore_path(data_path: &str) -> &str {
+    return match data_path.trim_end_matches('/').rsplit_once('/') {
+        Some(("", _)) | None => "/",
+        Some((parent, _)) => parent,
+    };
+}
+
+///
+/// get a snapshot of the user to create an instance from, making sure instances have a volume to restore
+/// it into
+///
+pub fn find(state: &AppState, owner_id: &str, id: i64) -> Result<Snapshot, ApiError> {
+    if state.config.docker.data_path.is_empty() {
+        return Err(ApiError::BadRequest(
+            "instances do not have a data volume, so snapshots cannot be used".to_string(),
+        ));
+    }
+
+    return match state.db.get_snapshot(id, owner_id)? {
+        Some(snapshot) => Ok(snapshot),
+        None => Err(ApiError::NotFound(format!("snapshot {id} does not exist"))),
+    };
+}
+
+///
+/// extract a snapshot into the data volume of a container that has been created but not started
+///
+pub async fn restore(state: &AppState, container_name: &str, snapshot: &Snapshot) -> Result<()> {
+    let file = tokio::fs::File::open(file_path(state, &snapshot.file))
+        .await
+        .context("failed to read snapshot")?;
+
+    state
+        .backend
+        .upload_archive(
+            container_name,
+            restore_path(&state.config.docker.data_path),
+            ReaderStream::new(file).map_err(anyhow::Error::from).boxed(),
+        )
+        .await?;
+
+    tracing::info!("restored snapshot {} into {}", snapshot.id, container_name);
+    return Ok(());
+}
+
+///
+/// write a tar streamed from a container to `path` chunk by chunk, returning its size
+///
+async fn write_archive(mut tar: BoxStream<'static, Result<Bytes>>, path: &FsPath) -> Result<u64> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .context("failed to create snapshot file")?;
+
+    let mut size = 0;
+    while let Some(chunk) = tar.next().await {
+        let chunk = chunk.context("failed to copy the data volume")?;
+        file.write_all(&chunk)
+            .await
+            .context("failed to save snapshot")?;
+        size += chunk.len() as u64;
+    }
+    file.flush().await.context("failed to save snapshot")?;
+
+    return Ok(size);
+}
+
+///
+/// copy the data volume of a running container to `path`, returning the size of the tar. the container is
+/// paused while it is copied, so squittal cannot write to the volume part way through and leave files in
+/// the snapshot that do not match each other. nothing is left at `path` if the copy fails
+///
+async fn copy_data_volume(state: AppState, container_name: String, path: PathBuf) -> Result<u64> {
+    state
+        .backend
+        .pause_container(&container_name)
+        .await
+        .context("failed to pause the instance")?;
+
+    let tar = state
+        .backend
+        .download_archive(&container_name, &state.config.docker.data_path);
+    let copied = write_archive(tar, &path).await;
+
+    // the instance is unusable until it is unpaused, so this happens even if the copy failed
+    if let Err(e) = state.backend.unpause_container(&container_name).await {
+        tracing::error!(
+            "failed to unpause {} after snapshotting it: {}",
+            container_name,
+            e
+        );
+    }
+    if copied.is_err() {
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    return copied;
+}
+
+///
+/// list the snapshots of the user
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/snapshots",
+    tag = "snapshots",
+    responses(
+        (status = 200, description = "the snapshots of the user", body = Vec<Snapshot>),
+        (status = 401, description = "not logged in", body = ErrorBody),
+    )
+)]
+pub async fn list_snapshots(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    return Ok(Json(state.db.snapshots_by_owner(&user.id)?));
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateSnapshot {
+    /// what the snapshot is of, such as "league night setup"
+    name: String,
+}
+
+///
+/// snapshot the data volume of an instance owned by the user
+///
+#[utoipa::path(
+    post,
+    path = "/api/v1/instances/{name}/snapshots",
+    tag = "snapshots",
+    params(("name" = String, Path, description = "name of the instance")),
+    request_body = CreateSnapshot,
+    responses(
+        (status = 201, description = "the new snapshot", body = Snapshot),
+        (status = 400, description = "the name is not valid, instances have no data volume, or the user has too many snapshots", body = ErrorBody),
+        (status = 403, description = "the instance is owned by someone else", body = ErrorBody),
+        (status = 404, description = "the instance does not exist", body = ErrorBody),
+        (status = 409, description = "the instance is not ready yet", body = ErrorBody),
+    )
+)]
+pub async fn create_snapshot(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    user: User,
+    Json(body): Json<CreateSnapshot>,
+) -> Result<impl IntoResponse, ApiError> {
+    let config = &state.config;
+    if config.docker.data_path.is_empty() {
+        return Err(ApiError::BadRequest(
+            "instances do not have a data volume, so they cannot be snapshotted".to_string(),
+        ));
+    }
+
+    let label = body.name.trim();
+    if label.is_empty() || label.len() > 100 {
+        return Err(ApiError::BadRequest(
+            "snapshot name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+
+    let Some(instance) = state.index.get(&name) else {
+        return Err(ApiError::NotFound(format!(
+            "instance {name} does not exist"
+        )));
+    };
+    if instance.created_by != user.id {
+        return Err(ApiError::Forbidden(format!(
+            "instance {name} is owned by someone else"
+        )));
+    }
+    ensure_ready(&instance, &name)?;
+
+    if state.db.snapshots_by_owner(&user.id)?.len() >= config.snapshots.max_per_user {
+        return Err(ApiError::BadRequest(format!(
+            "cannot have more than {} snapshots, delete one first",
+            config.snapshots.max_per_user
+        )));
+    }
+
+    let file = format!("{:016x}.tar", rand::rng().random::<u64>());
+    tokio::fs::create_dir_all(&config.snapshots.dir)
+        .await
+        .context("failed to create snapshot directory")?;
+
+    // copied in a task of its own, so the instance is still unpaused if the request is dropped part way
+    let size = tokio::spawn(copy_data_volume(
+        state.clone(),
+        docker::container_name(&name),
+        file_path(&state, &file),
+    ))
+    .await
+    .context("snapshot task failed")??;
+
+    let id = match state
+        .db
+        .insert_snapshot(&user.id, label, &name, &file, size as i64)
+    {
+        Ok(id) => id,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(file_path(&state, &file)).await;
+            return Err(e.into());
+        }
+    };
+
+    tracing::info!(
+        "{}/{} took snapshot {} of {} ({} bytes)",
+        &user.id,
+        &user.username,
+        id,
+        name,
+        size
+    );
+
+    let snapshot = state
+        .db
+        .get_snapshot(id, &user.id)?
+        .context("snapshot is missing after saving it")?;
+    return Ok((StatusCode::CREATED, Json(snapshot)));
+}
+
+///
+/// download a snapshot of the user as a tarball
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/snapshots/{id}/download",
+    tag = "snapshots",
+    params(("id" = i64, Path, description = "ID of the snapshot")),
+    responses(
+        (status = 200, description = "the snapshot", content_type = "application/x-tar", body = Vec<u8>),
+        (status = 404, description = "the snapshot does not exist", body = ErrorBody),
+    )
+)]
+pub async fn download_snapshot(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(snapshot) = state.db.get_snapshot(id, &user.id)? else {
+        return Err(ApiError::NotFound(format!("snapshot {id} does not exist")));
+    };
+
+    let file = tokio::fs::File::open(file_path(&state, &snapshot.file))
+        .await
+        .context("failed to read snapshot")?;
+    let size = file
+        .metadata()
+        .await
+        .context("failed to read snapshot")?
+        .len();
+
+    return Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-tar".to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}-{}.tar\"", snapshot.instance, id),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    ));
+}
+
+///
+/// delete a snapshot of the user
+///
+#[utoipa::path(
+    delete,
+    path = "/api/v1/snapshots/{id}",
+    tag = "snapshots",
+    params(("id" = i64, Path, description = "ID of the snapshot")),
+    responses(
+        (status = 204, description = "the snapshot was deleted"),
+        (status = 404, description = "the snapshot does not exist", body = ErrorBody),
+    )
+)]
+pub async fn delete_snapshot(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(snapshot) = state.db.get_snapshot(id, &user.id)? else {
+        return Err(ApiError::NotFound(format!("snapshot {id} does not exist")));
+    };
+
+    state.db.delete_snapshot(id, &user.id)?;
+    if let Err(e) = tokio::fs::remove_file(file_path(&state, &snapshot.file)).await {
+        tracing::error!("failed to delete snapshot file {}: {}", snapshot.file, e);
+    }
+
+    tracing::info!("{}/{} deleted snapshot {}", &user.id, &user.username, id);
+    return Ok(StatusCode::NO_CONTENT);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::config::Config;
+    use crate::fake_backend::{FakeBackend, FakeFailure};
+    use crate::test_util::{self, TempDir};
+
+    use super::*;
+
+    /// more than one chunk of the fake backend, and not a whole number of them
+    const DATA_SIZE: usize = 1500;
+
+    async fn state(dir: &TempDir) -> (AppState, Arc<FakeBackend>) {
+        let mut config = Config::default();
+        config.docker.data_path = "/data".to_string();
+        config.snapshots.dir = dir.join("snapshots");
+        return test_util::state_with_backend(config).await;
+    }
+
+    fn data() -> Vec<u8> {
+        return (0..DATA_SIZE).map(|i| (i % 251) as u8).collect();
+    }
+
+    ///
+    /// put `data()` in the data volume of the instance `name`
+    ///
+    async fn fill_volume(state: &AppState, name: &str) {
+        let tar = futures_util::stream::iter([Ok(Bytes::from(data()))]).boxed();
+        state
+            .backend
+            .upload_archive(&docker::container_name(name), "/", tar)
+            .await
+            .unwrap();
+    }
+
+    async fn snapshot(state: &AppState, name: &str, owner: &str) -> Result<Snapshot, ApiError> {
+        let response = create_snapshot(
+            State(state.clone()),
+            Path(name.to_string()),
+            test_util::user(owner),
+            Json(CreateSnapshot {
+                name: "league night".to_string(),
+            }),
+        )
+        .await?
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let id = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["id"]
+            .as_i64()
+            .unwrap();
+        return Ok(state.db.get_snapshot(id, owner).unwrap().unwrap());
+    }
+
+    async fn is_paused(state: &AppState, name: &str) -> bool {
+        let container = state
+            .backend
+            .inspect_container(&docker::container_name(name))
+            .await
+            .unwrap();
+        return container.state.unwrap().paused.unwrap();
+    }
+
+    fn files(dir: &TempDir) -> Vec<std::path::PathBuf> {
+        return match std::fs::read_dir(dir.path().join("snapshots")) {
+            Ok(entries) => entries.map(|e| e.unwrap().path()).collect(),
+            Err(_) => Vec::new(),
+        };
+    }
+
+    #[test]
+    fn restore_into_parent() {
+        assert_eq!(restore_path("/data"), "/");
+        assert_eq!(restore_path("/data/"), "/");
+        assert_eq!(restore_path("/app/data"), "/app");
+    }
+
+    #[tokio::test]
+    async fn create_and_restore() {
+        let dir = TempDir::new();
+        let (state, backend) = state(&dir).await;
+        let name = test_util::running_instance(&state, "1").await;
+        fill_volume(&state, &name).await;
+
+        let snapshot = snapshot(&state, &name, "1").await.unwrap();
+        assert_eq!(snapshot.name, "league night");
+        assert_eq!(snapshot.instance, name);
+        assert_eq!(snapshot.size, DATA_SIZE as i64);
+        assert_eq!(
+            std::fs::read(file_path(&state, &snapshot.file)).unwrap(),
+            data()
+        );
+
+        // restored into the instance of someone else, so it has an empty volume to start with
+        let other = test_util::running_instance(&state, "2").await;
+        let other_container = docker::container_name(&other);
+        restore(&state, &other_container, &snapshot).await.unwrap();
+
+        assert_eq!(backend.archive(&other_container), Some(data()));
+    }
+
+    #[tokio::test]
+    async fn paused_while_copied() {
+        let dir = TempDir::new();
+        let (state, _) = state(&dir).await;
+        let name = test_util::running_instance(&state, "1").await;
+        let mut events = state.backend.container_events();
+
+        snapshot(&state, &name, "1").await.unwrap();
+        assert!(!is_paused(&state, &name).await);
+
+        let mut actions = Vec::new();
+        while actions.len() < 2 {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            if event.name == docker::container_name(&name) {
+                actions.push(event.action);
+            }
+        }
+        assert_eq!(actions, ["pause", "unpause"]);
+    }
+
+    #[tokio::test]
+    async fn failed_copy() {
+        let dir = TempDir::new();
+        let (state, backend) = state(&dir).await;
+        let name = test_util::running_instance(&state, "1").await;
+        fill_volume(&state, &name).await;
+        backend.fail(FakeFailure::Download);
+
+        let result = snapshot(&state, &name, "1").await;
+        assert!(matches!(result.err(), Some(ApiError::Internal(_))));
+
+        // the instance is unpaused, and the part of the tar that was copied is not kept
+        assert!(!is_paused(&state, &name).await);
+        assert!(files(&dir).is_empty());
+        assert!(state.db.snapshots_by_owner("1").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_of_someone_else() {
+        let dir = TempDir::new();
+        let (state, _) = state(&dir).await;
+        let name = test_util::running_instance(&state, "1").await;
+
+        let result = snapshot(&state, &name, "2").await;
+        assert!(matches!(result.err(), Some(ApiError::Forbidden(_))));
+        assert!(!is_paused(&state, &name).await);
+        assert!(files(&dir).is_empty());
+    }
+
+    #[tokio::test]
+    async fn download_and_delete() {
+        let dir = TempDir::new();
+        let (state, _) = state(&dir).await;
+        let name = test_util::running_instance(&state, "1").await;
+        fill_volume(&state, &name).await;
+        let snapshot = snapshot(&state, &name, "1").await.unwrap();
+
+        let result = download_snapshot(
+            State(state.clone()),
+            Path(snapshot.id),
+            test_util::user("2"),
+        )
+        .await;
+        assert!(matches!(result.err(), Some(ApiError::NotFound(_))));
+
+        let response = download_snapshot(
+            State(state.clone()),
+            Path(snapshot.id),
+            test_util::user("1"),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_LENGTH],
+            DATA_SIZE.to_string()
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, data());
+
+        let result = delete_snapshot(
+            State(state.clone()),
+            Path(snapshot.id),
+            test_util::user("2"),
+        )
+        .await;
+        assert!(matches!(result.err(), Some(ApiError::NotFound(_))));
+        assert_eq!(files(&dir).len(), 1);
+
+        let response = delete_snapshot(
+            State(state.clone()),
+            Path(snapshot.id),
+            test_util::user("1"),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(files(&dir).is_empty());
+        assert!(state.db.get_snapshot(snapshot.id, "1").unwrap().is_none());
+    }
+}
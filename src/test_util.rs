@@ -0,0 +1,353 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_session::{Session, SessionStore};
+use axum::{
+    Form, Json, Router,
+    extract::State,
+    response::IntoResponse,
+    routing::{get, post},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use http::{HeaderMap, StatusCode, header};
+use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
+use serde_json::{Value, json};
+
+use crate::{
+    User,
+    activity::ActivityTracker,
+    api,
+    app_state::AppState,
+    config::{Config, OidcConfig},
+    db::Database,
+    docker,
+    events::EventBus,
+    fake_backend::FakeBackend,
+    identity::IdentityProviders,
+    instance::{InstanceStatus, SquittalInstance},
+    instance_index::{self, InstanceIndex},
+    notify::Notifier,
+    proxy,
+    session_store::SqliteSessionStore,
+    waitlist::Waitlist,
+};
+
+///
+/// state for tests, using the fake backend and in memory databases
+///
+pub async fn state(config: Config) -> AppState {
+    return state_with_backend(config).await.0;
+}
+
+///
+/// state for tests, along with its fake backend so it can be made to fail
+///
+pub async fn state_with_backend(mut config: Config) -> (AppState, Arc<FakeBackend>) {
+    // ink does not start without a way to log in, and github is set up without calling it
+    config.github.client_id = Some("test".to_string());
+    config.github.client_secret = Some("test".to_string());
+
+    let client: proxy::Client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+        .build(HttpConnector::new());
+
+    let backend = Arc::new(FakeBackend::new(vec![config.docker.image.clone()]));
+    let state = AppState {
+        store: SqliteSessionStore::open(":memory:").unwrap(),
+        identity: IdentityProviders::from_config(&config).await.unwrap(),
+        client,
+        db: Database::open(":memory:").unwrap(),
+        backend: backend.clone(),
+        databases: None,
+        index: InstanceIndex::new(),
+        notifier: Notifier::new(&config.notifications),
+        activity: ActivityTracker::new(),
+        waitlist: Waitlist::new(),
+        events: EventBus::new(),
+        config: Arc::new(config),
+    };
+
+    return (state, backend);
+}
+
+pub fn user(id: &str) -> User {
+    return User {
+        id: id.to_string(),
+        username: format!("user{id}"),
+        discriminator: "0".to_string(),
+    };
+}
+
+///
+/// log `user` in, returning the cookie of their session
+///
+pub async fn session_cookie(state: &AppState, user: &User) -> String {
+    let mut session = Session::new();
+    session.insert("user", user).unwrap();
+    let cookie = state.store.store_session(session).await.unwrap().unwrap();
+
+    return format!("{}={cookie}", crate::COOKIE_NAME);
+}
+
+///
+/// wait until the instance named `name` has finished being created and is in the index, which is kept
+/// current by watch_instances outside of tests
+///
+pub async fn wait_until_running(state: &AppState, name: &str) -> SquittalInstance {
+    let wait = async {
+        loop {
+            instance_index::refresh(state).await.unwrap();
+            if !state.index.is_pending(name)
+                && let Some(instance) = state.index.get(name)
+            {
+                return instance;
+            }
+
+            if let Some(instance) = state.index.get(name) {
+                assert_ne!(
+                    instance.status,
+                    InstanceStatus::Failed,
+                    "instance failed: {:?}",
+                    instance.failure
+                );
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+
+    return tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .expect("instance was not created in time");
+}
+
+///
+/// create an instance for `owner` and wait until it is running, returning its name
+///
+pub async fn running_instance(state: &AppState, owner: &str) -> String {
+    if let Err(e) = api::create_instance(State(state.clone()), user(owner), None).await {
+        panic!("failed to create instance: {e:?}");
+    }
+
+    let instance = state.index.active_by_owner(owner).remove(0);
+    let name = docker::instance_name(&instance.name).to_string();
+    wait_until_running(state, &name).await;
+
+    return name;
+}
+
+///
+/// serve `router` on a free port on localhost, returning its base URL. used as a stand-in for the APIs
+/// ink calls, such as discord
+///
+pub async fn serve(router: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    return format!("http://{address}");
+}
+
+///
+/// a stand-in webhook that answers with `status`, returning its URL and what is POSTed to it
+///
+pub async fn webhook(status: StatusCode) -> (String, tokio::sync::mpsc::UnboundedReceiver<Value>) {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let router = Router::new().route(
+        "/hook",
+        post(move |Json(body): Json<Value>| async move {
+            let _ = sender.send(body);
+            return status;
+        }),
+    );
+
+    return (format!("{}/hook", serve(router).await), receiver);
+}
+
+/// the authorization code the stand-in identity providers give out tokens for
+pub const LOGIN_CODE: &str = "code";
+
+/// the access token the stand-in identity providers give out
+pub const ACCESS_TOKEN: &str = "token";
+
+///
+/// a stand-in OpenID Connect provider, for the client ID "ink". the ID tokens it gives out have
+/// `claims`, which start out as ones that pass every check other than the nonce. its discovery
+/// document names the `iss` claim as the issuer
+///
+pub struct Issuer {
+    pub url: String,
+    pub claims: Arc<Mutex<Value>>,
+}
+
+impl Issuer {
+    pub fn config(&self) -> OidcConfig {
+        return OidcConfig {
+            issuer_url: self.url.clone(),
+            client_id: "ink".to_string(),
+            client_secret: "secret".to_string(),
+            ..Default::default()
+        };
+    }
+}
+
+pub async fn issuer() -> Issuer {
+    let claims = Arc::new(Mutex::new(Value::Null));
+    let (id_token_claims, discovery_claims) = (claims.clone(), claims.clone());
+
+    let router = Router::new()
+        .route(
+            "/.well-known/openid-configuration",
+            get(|headers: HeaderMap| async move {
+                let host = headers[header::HOST].to_str().unwrap();
+                let url = format!("http://{host}");
+                // the issuer of the ID tokens is the one in the document
+                let issuer = discovery_claims.lock().unwrap()["iss"].clone();
+                return Json(json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{url}/authorize"),
+                    "token_endpoint": format!("{url}/token"),
+                    "userinfo_endpoint": format!("{url}/userinfo"),
+                }));
+            }),
+        )
+        .route(
+            "/token",
+            post(
+                move |Form(form): Form<HashMap<String, String>>| async move {
+                    if form.get("code").map(String::as_str) != Some(LOGIN_CODE) {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            Json(json!({"error": "invalid_grant"})),
+                        )
+                            .into_response();
+                    }
+
+                    let claims = id_token_claims.lock().unwrap().clone();
+                    return Json(json!({
+                        "access_token": ACCESS_TOKEN,
+                        "token_type": "Bearer",
+                        "id_token": jwt(&claims),
+                    }))
+                    .into_response();
+                },
+            ),
+        )
+        .route(
+            "/userinfo",
+            get(|headers: HeaderMap| async move {
+                if !has_token(&headers) {
+                    return StatusCode::UNAUTHORIZED.into_response();
+                }
+                return Json(json!({
+                    "sub": "1234",
+                    "preferred_username": "someone",
+                    "number": 42,
+                    "empty": "",
+                }))
+                .into_response();
+            }),
+        );
+
+    let url = serve(router).await;
+    *claims.lock().unwrap() = json!({
+        "iss": url,
+        "aud": "ink",
+        "sub": "1234",
+        "exp": now() + 300,
+    });
+
+    return Issuer { url, claims };
+}
+
+///
+/// a stand-in for github, with oauth under /login/oauth and the API under /api
+///
+pub async fn github() -> String {
+    let router = Router::new()
+        .route(
+            "/login/oauth/access_token",
+            post(|Form(form): Form<HashMap<String, String>>| async move {
+                if form.get("code").map(String::as_str) != Some(LOGIN_CODE) {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"error": "bad_verification_code"})),
+                    )
+                        .into_response();
+                }
+                return Json(json!({
+                    "access_token": ACCESS_TOKEN,
+                    "token_type": "bearer",
+                    "scope": "",
+                }))
+                .into_response();
+            }),
+        )
+        .route(
+            "/api/user",
+            get(|headers: HeaderMap| async move {
+                if !has_token(&headers) {
+                    return StatusCode::UNAUTHORIZED.into_response();
+                }
+                if !headers.contains_key(header::USER_AGENT) {
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                return Json(json!({"id": 1234, "login": "someone"})).into_response();
+            }),
+        );
+
+    return serve(router).await;
+}
+
+///
+/// an unsigned JWT with `claims`
+///
+pub fn jwt(claims: &Value) -> String {
+    let header = URL_SAFE_NO_PAD.encode(json!({"alg": "none"}).to_string());
+    let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+    return format!("{header}.{claims}.");
+}
+
+pub fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+}
+
+fn has_token(headers: &HeaderMap) -> bool {
+    return headers
+        .get(header::AUTHORIZATION)
+        .is_some_and(|v| v == &format!("Bearer {ACCESS_TOKEN}"));
+}
+
+///
+/// a directory of its own under the temp dir, removed when dropped
+///
+pub struct TempDir(std::path::PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("ink-test-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&path).unwrap();
+        return TempDir(path);
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        return &self.0;
+    }
+
+    ///
+    /// `name` in the directory, as a string for the config
+    ///
+    pub fn join(&self, name: &str) -> String {
+        return self.0.join(name).to_str().unwrap().to_string();
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
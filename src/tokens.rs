@@ -0,0 +1,632 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, anyhow};
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::IntoResponse,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use http::{Method, StatusCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::{
+    User,
+    api_error::{ApiError, ErrorBody},
+    app_state::AppState,
+    db::ApiToken,
+    roles::{self, Role},
+};
+
+/// prefix of every token, so they are easy to spot (and scan for if leaked)
+static TOKEN_PREFIX: &str = "ink_";
+
+/// how many tokens a user can have at once
+const MAX_TOKENS_PER_USER: usize = 20;
+
+///
+/// what an API token can be used for
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// GET requests, such as listing instances
+    Read,
+
+    /// everything else, such as creating and removing instances
+    Write,
+
+    /// the admin API. the owner of the token must also be an admin
+    Admin,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            TokenScope::Read => "read",
+            TokenScope::Write => "write",
+            TokenScope::Admin => "admin",
+        };
+    }
+
+    pub fn parse(scope: &str) -> Result<TokenScope> {
+        return match scope {
+            "read" => Ok(TokenScope::Read),
+            "write" => Ok(TokenScope::Write),
+            "admin" => Ok(TokenScope::Admin),
+            _ => Err(anyhow!("unknown token scope '{scope}'")),
+        };
+    }
+
+    ///
+    /// the scope a token needs to make a request, or None if tokens cannot be used for it at all
+    ///
+    pub fn required_for(method: &Method, path: &str) -> Option<TokenScope> {
+        // tokens cannot be used to make more tokens
+        if path.starts_with("/api/v1/tokens") {
+            return None;
+        }
+
+        if path.starts_with("/api/v1/admin") {
+            return Some(TokenScope::Admin);
+        }
+
+        return match *method {
+            Method::GET | Method::HEAD => Some(TokenScope::Read),
+            _ => Some(TokenScope::Write),
+        };
+    }
+}
+
+impl fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(self.as_str());
+    }
+}
+
+///
+/// hash of a token, which is what is kept in the DB. tokens are random, so a plain sha256 is enough
+///
+pub fn hash(token: &str) -> String {
+    return format!("{:x}", Sha256::digest(token.as_bytes()));
+}
+
+fn generate() -> String {
+    let bytes: [u8; 32] = rand::rng().random();
+    return format!("{TOKEN_PREFIX}{}", URL_SAFE_NO_PAD.encode(bytes));
+}
+
+///
+/// list the tokens of the user. the tokens themselves are not kept, so they cannot be shown again
+///
+#[utoipa::path(
+    get,
+    path = "/api/v1/tokens",
+    tag = "tokens",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "the tokens of the user", body = Vec<ApiToken>),
+        (status = 401, description = "not logged in", body = ErrorBody),
+    )
+)]
+pub async fn list_tokens(
+    State(state): State<AppState>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    return Ok(Json(state.db.tokens_by_owner(&user.id)?));
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateToken {
+    /// what the token is for, such as "tournament bot"
+    name: String,
+
+    scopes: Vec<TokenScope>,
+
+    /// how long until the token stops working, in seconds. if not set, tokens last as long as the
+    /// server allows, which may be until revoked
+    expires_in_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedToken {
+    id: i64,
+    name: String,
+    scopes: Vec<TokenScope>,
+    #[schema(value_type = Option<crate::openapi::Timestamp>)]
+    expires_at: Option<SystemTime>,
+
+    /// the token itself. this is the only time it is shown
+    token: String,
+}
+
+///
+/// make a new API token for the user
+///
+#[utoipa::path(
+    post,
+    path = "/api/v1/tokens",
+    tag = "tokens",
+    security(("session" = [])),
+    request_body = CreateToken,
+    responses(
+        (status = 201, description = "the new token", body = CreatedToken),
+        (status = 400, description = "the name, scopes or expiry are not valid, or the user has too many tokens", body = ErrorBody),
+        (status = 403, description = "only admins can make tokens with the admin scope", body = ErrorBody),
+    )
+)]
+pub async fn create_token(
+    State(state): State<AppState>,
+    user: User,
+    Json(body): Json<CreateToken>,
+) -> Result<impl IntoResponse, ApiError> {
+    let name = body.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(ApiError::BadRequest(
+            "token name must be between 1 and 100 characters".to_string(),
+        ));
+    }
+
+    let mut scopes = body.scopes;
+    scopes.sort();
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(ApiError::BadRequest(
+            "token needs at least one scope".to_string(),
+        ));
+    }
+
+    if scopes.contains(&TokenScope::Admin)
+        && roles::role_of(&state.config, &state.db, &user.id)? != Role::Admin
+    {
+        return Err(ApiError::Forbidden(
+            "only admins can make tokens with the admin scope".to_string(),
+        ));
+    }
+
+    if state.db.tokens_by_owner(&user.id)?.len() >= MAX_TOKENS_PER_USER {
+        return Err(ApiError::BadRequest(format!(
+            "cannot have more than {MAX_TOKENS_PER_USER} tokens, revoke one first"
+        )));
+    }
+
+    let max_lifetime = state.config.access.token_max_lifetime_secs;
+    let expires_in = match (body.expires_in_secs, max_lifetime) {
+        (Some(secs), max) if max > 0 && secs > max => {
+            return Err(ApiError::BadRequest(format!(
+                "tokens cannot last longer than {max} seconds"
+            )));
+        }
+        (Some(secs), _) => Some(secs),
+        (None, 0) => None,
+        (None, max) => Some(max),
+    };
+    let expires_at = match expires_in {
+        Some(secs) => Some(
+            SystemTime::now()
+                .checked_add(Duration::from_secs(secs))
+                .ok_or_else(|| {
+                    ApiError::BadRequest(format!("{secs} seconds is too long for a token to last"))
+                })?,
+        ),
+        None => None,
+    };
+
+    let token = generate();
+    let id = state
+        .db
+        .insert_token(
+            &user.id,
+            &user.username,
+            name,
+            &hash(&token),
+            &scopes,
+            expires_at,
+        )
+        .context("failed to save token")?;
+
+    tracing::info!(
+        "{}/{} made token {} ({}) with scopes {:?}",
+        &user.id,
+        &user.username,
+        id,
+        name,
+        scopes
+    );
+
+    return Ok((
+        StatusCode::CREATED,
+        Json(CreatedToken {
+            id,
+            name: name.to_string(),
+            scopes,
+            expires_at,
+            token,
+        }),
+    ));
+}
+
+///
+/// revoke one of the user's tokens, so it can no longer be used
+///
+#[utoipa::path(
+    delete,
+    path = "/api/v1/tokens/{id}",
+    tag = "tokens",
+    security(("session" = [])),
+    params(("id" = i64, Path, description = "ID of the token")),
+    responses(
+        (status = 204, description = "the token was revoked"),
+        (status = 404, description = "the token does not exist", body = ErrorBody),
+    )
+)]
+pub async fn revoke_token(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    user: User,
+) -> Result<impl IntoResponse, ApiError> {
+    if !state.db.revoke_token(id, &user.id)? {
+        return Err(ApiError::NotFound(format!("token {id} does not exist")));
+    }
+
+    tracing::info!("{}/{} revoked token {}", &user.id, &user.username, id);
+    return Ok(StatusCode::NO_CONTENT);
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body};
+    use http::{Request, header};
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{config::Config, test_util};
+
+    ///
+    /// the API, where user 1 is an admin and user 2 is not
+    ///
+    async fn api() -> (Router, AppState) {
+        let mut config = Config::default();
+        config.access.admins = vec!["1".to_string()];
+        let state = test_util::state(config).await;
+
+        return (crate::api().0.with_state(state.clone()), state);
+    }
+
+    ///
+    /// make a token for `owner` straight in the DB
+    ///
+    fn token(
+        state: &AppState,
+        owner: &str,
+        scopes: &[TokenScope],
+        expires_at: Option<SystemTime>,
+    ) -> String {
+        let token = generate();
+        state
+            .db
+            .insert_token(
+                owner,
+                &format!("user{owner}"),
+                "test",
+                &hash(&token),
+                scopes,
+                expires_at,
+            )
+            .unwrap();
+        return token;
+    }
+
+    ///
+    /// send a request with `headers`, returning the status and the error code if it failed
+    ///
+    async fn send(
+        router: &Router,
+        method: Method,
+        path: &str,
+        headers: &[(header::HeaderName, &str)],
+    ) -> (StatusCode, Option<String>) {
+        let mut request = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let code = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|b| b["error"]["code"].as_str().map(str::to_string));
+
+        return (status, code);
+    }
+
+    async fn send_token(
+        router: &Router,
+        method: Method,
+        path: &str,
+        token: &str,
+    ) -> (StatusCode, Option<String>) {
+        let bearer = format!("Bearer {token}");
+        return send(router, method, path, &[(header::AUTHORIZATION, &bearer)]).await;
+    }
+
+    #[test]
+    fn hashing() {
+        let token = generate();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_ne!(token, generate());
+
+        assert_eq!(hash(&token), hash(&token));
+        assert_ne!(hash(&token), hash(&generate()));
+        assert_eq!(hash(&token).len(), 64);
+        assert!(!hash(&token).contains(&token));
+        assert_eq!(
+            hash("ink_test"),
+            "c3b36016f2a2cba01e6137ff195cc0e8df5422c6ef7db1ed482d94ff93ed35ad"
+        );
+    }
+
+    #[test]
+    fn required_scopes() {
+        use TokenScope::*;
+
+        let cases = [
+            (Method::GET, "/api/v1/instances", Some(Read)),
+            (Method::HEAD, "/api/v1/instances", Some(Read)),
+            (Method::POST, "/api/v1/instances", Some(Write)),
+            (Method::DELETE, "/api/v1/instances/1", Some(Write)),
+            (Method::GET, "/api/v1/admin/instances", Some(Admin)),
+            (Method::PUT, "/api/v1/admin/roles/1", Some(Admin)),
+            (Method::GET, "/api/v1/tokens", None),
+            (Method::POST, "/api/v1/tokens", None),
+            (Method::DELETE, "/api/v1/tokens/1", None),
+        ];
+        for (method, path, scope) in cases {
+            assert_eq!(
+                TokenScope::required_for(&method, path),
+                scope,
+                "{method} {path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn scopes_are_enforced() {
+        let (router, state) = api().await;
+        let read = token(&state, "2", &[TokenScope::Read], None);
+        let write = token(&state, "2", &[TokenScope::Write], None);
+
+        let (status, _) = send_token(&router, Method::GET, "/api/v1/whoami", &read).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, code) = send_token(&router, Method::DELETE, "/api/v1/queue", &read).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(code.as_deref(), Some("missing_scope"));
+
+        let (status, code) = send_token(&router, Method::GET, "/api/v1/whoami", &write).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(code.as_deref(), Some("missing_scope"));
+    }
+
+    #[tokio::test]
+    async fn tokens_cannot_manage_tokens() {
+        let (router, state) = api().await;
+        let all = [TokenScope::Read, TokenScope::Write, TokenScope::Admin];
+        let token = token(&state, "1", &all, None);
+
+        for (method, path) in [
+            (Method::GET, "/api/v1/tokens"),
+            (Method::POST, "/api/v1/tokens"),
+            (Method::DELETE, "/api/v1/tokens/1"),
+        ] {
+            let (status, code) = send_token(&router, method.clone(), path, &token).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{method} {path}");
+            assert_eq!(code.as_deref(), Some("missing_scope"));
+        }
+
+        // the token was not revoked by the request above
+        assert!(state.db.use_token(&hash(&token)).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn admin_api_needs_admin_scope() {
+        let (router, state) = api().await;
+        let path = "/api/v1/admin/instances";
+
+        let read_write = token(&state, "1", &[TokenScope::Read, TokenScope::Write], None);
+        let (status, code) = send_token(&router, Method::GET, path, &read_write).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(code.as_deref(), Some("missing_scope"));
+
+        let admin = token(&state, "1", &[TokenScope::Admin], None);
+        let (status, _) = send_token(&router, Method::GET, path, &admin).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // the scope alone is not enough, the owner has to be an admin too
+        let not_admin = token(&state, "2", &[TokenScope::Admin], None);
+        let (status, code) = send_token(&router, Method::GET, path, &not_admin).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(code.as_deref(), Some("forbidden"));
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_rejected() {
+        let (router, state) = api().await;
+        let expired = token(
+            &state,
+            "2",
+            &[TokenScope::Read],
+            Some(SystemTime::now() - Duration::from_secs(1)),
+        );
+        let current = token(
+            &state,
+            "2",
+            &[TokenScope::Read],
+            Some(SystemTime::now() + Duration::from_secs(60)),
+        );
+
+        let (status, code) = send_token(&router, Method::GET, "/api/v1/whoami", &expired).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(code.as_deref(), Some("invalid_token"));
+
+        let (status, _) = send_token(&router, Method::GET, "/api/v1/whoami", &current).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_rejected() {
+        let (router, state) = api().await;
+        let token = token(&state, "2", &[TokenScope::Read], None);
+        let id = state.db.tokens_by_owner("2").unwrap()[0].id;
+
+        // revoked from a session, as tokens cannot revoke tokens
+        let cookie = test_util::session_cookie(&state, &test_util::user("2")).await;
+        let path = format!("/api/v1/tokens/{id}");
+        let (status, _) = send(&router, Method::DELETE, &path, &[(header::COOKIE, &cookie)]).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, code) = send_token(&router, Method::GET, "/api/v1/whoami", &token).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(code.as_deref(), Some("invalid_token"));
+
+        // already revoked
+        let (status, _) = send(&router, Method::DELETE, &path, &[(header::COOKIE, &cookie)]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unknown_tokens_are_rejected() {
+        let (router, _) = api().await;
+
+        let (status, code) = send_token(&router, Method::GET, "/api/v1/whoami", "ink_nope").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(code.as_deref(), Some("invalid_token"));
+
+        // the scheme is case insensitive
+        let headers = [(header::AUTHORIZATION, "bearer ink_nope")];
+        let (status, _) = send(&router, Method::GET, "/api/v1/whoami", &headers).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn other_authorization_falls_back_to_session() {
+        let (router, state) = api().await;
+        let cookie = test_util::session_cookie(&state, &test_util::user("2")).await;
+        let basic = "Basic dXNlcjpwYXNzd29yZA==";
+
+        let headers = [(header::AUTHORIZATION, basic), (header::COOKIE, &cookie)];
+        let (status, _) = send(&router, Method::GET, "/api/v1/whoami", &headers).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let headers = [(header::AUTHORIZATION, basic)];
+        let (status, code) = send(&router, Method::GET, "/api/v1/whoami", &headers).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(code.as_deref(), Some("not_logged_in"));
+    }
+
+    #[tokio::test]
+    async fn create() {
+        let (_, state) = api().await;
+        let request = |scopes: Vec<TokenScope>, expires_in_secs: Option<u64>| {
+            Json(CreateToken {
+                name: " bot ".to_string(),
+                scopes,
+                expires_in_secs,
+            })
+        };
+
+        let created = create_token(
+            State(state.clone()),
+            test_util::user("2"),
+            request(
+                vec![TokenScope::Write, TokenScope::Read, TokenScope::Read],
+                Some(60),
+            ),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(created.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["name"], "bot");
+        assert_eq!(body["scopes"], json!(["read", "write"]));
+
+        // only the hash is kept
+        let token = body["token"].as_str().unwrap();
+        let owner = state.db.use_token(&hash(token)).unwrap().unwrap();
+        assert_eq!(owner.owner_id, "2");
+        assert!(state.db.use_token(token).unwrap().is_none());
+
+        let not_admin = create_token(
+            State(state.clone()),
+            test_util::user("2"),
+            request(vec![TokenScope::Admin], None),
+        )
+        .await;
+        assert!(matches!(not_admin, Err(ApiError::Forbidden(_))));
+
+        let no_scopes = create_token(
+            State(state.clone()),
+            test_util::user("2"),
+            request(vec![], None),
+        )
+        .await;
+        assert!(matches!(no_scopes, Err(ApiError::BadRequest(_))));
+
+        let admin = create_token(
+            State(state.clone()),
+            test_util::user("1"),
+            request(vec![TokenScope::Admin], None),
+        )
+        .await;
+        assert!(admin.is_ok());
+    }
+
+    #[tokio::test]
+    async fn create_with_max_lifetime() {
+        let mut config = Config::default();
+        config.access.token_max_lifetime_secs = 60;
+        let state = test_util::state(config).await;
+        let request = |expires_in_secs: Option<u64>| {
+            Json(CreateToken {
+                name: "bot".to_string(),
+                scopes: vec![TokenScope::Read],
+                expires_in_secs,
+            })
+        };
+
+        let too_long = create_token(
+            State(state.clone()),
+            test_util::user("2"),
+            request(Some(61)),
+        )
+        .await;
+        assert!(matches!(too_long, Err(ApiError::BadRequest(_))));
+
+        // tokens without an expiry last as long as allowed
+        assert!(
+            create_token(State(state.clone()), test_util::user("2"), request(None))
+                .await
+                .is_ok()
+        );
+        let expires_at = state.db.tokens_by_owner("2").unwrap()[0]
+            .expires_at
+            .unwrap();
+        let lasts = expires_at.duration_since(SystemTime::now()).unwrap();
+        assert!(lasts <= Duration::from_secs(60) && lasts > Duration::from_secs(50));
+    }
+}
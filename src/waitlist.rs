@@ -0,0 +1,278 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{app_state::AppState, db::Snapshot, docker};
+
+///
+/// users waiting for an instance while ink is at the max number of instances. first in, first out.
+/// the queue is only kept in memory, so it is lost if ink restarts
+///
+#[derive(Clone, Default)]
+pub struct Waitlist {
+    queue: Arc<Mutex<VecDeque<QueuedUser>>>,
+
+    /// held while instances are created for queued users, so two slots freeing at once
+    /// do not both create an instance for the same slot
+    provisioning: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Debug, Clone)]
+struct QueuedUser {
+    id: String,
+    username: String,
+    queued_at: SystemTime,
+
+    /// snapshot to create the instance from
+    snapshot: Option<Snapshot>,
+}
+
+///
+/// where a user is in the waitlist
+///
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct QueuePosition {
+    /// 1 is the next user to get an instance
+    pub position: usize,
+
+    /// how many users are waiting in total
+    pub waiting: usize,
+
+    #[schema(value_type = crate::openapi::Timestamp)]
+    pub queued_at: SystemTime,
+}
+
+impl Waitlist {
+    pub fn new() -> Self {
+        return Waitlist::default();
+    }
+
+    ///
+    /// add a user to the end of the queue, or get their position if they are already in it
+    ///
+    pub fn push(&self, id: &str, username: &str, snapshot: Option<Snapshot>) -> QueuePosition {
+        let mut queue = self.queue.lock().unwrap();
+
+        if !queue.iter().any(|u| u.id == id) {
+            queue.push_back(QueuedUser {
+                id: id.to_string(),
+                username: username.to_string(),
+                queued_at: SystemTime::now(),
+                snapshot,
+            });
+        }
+
+        return Waitlist::find(&queue, id).unwrap();
+    }
+
+    ///
+    /// get where `id` is in the queue, or None if they are not waiting
+    ///
+    pub fn position(&self, id: &str) -> Option<QueuePosition> {
+        return Waitlist::find(&self.queue.lock().unwrap(), id);
+    }
+
+    ///
+    /// take a user out of the queue, returning false if they were not in it
+    ///
+    pub fn remove(&self, id: &str) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let len = queue.len();
+        queue.retain(|u| u.id != id);
+
+        return queue.len() != len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.queue.lock().unwrap().is_empty();
+    }
+
+    fn pop(&self) -> Option<QueuedUser> {
+        return self.queue.lock().unwrap().pop_front();
+    }
+
+    ///
+    /// return a user taken with [`Waitlist::pop`] to the front of the queue
+    ///
+    fn put_back(&self, user: QueuedUser) {
+        self.queue.lock().unwrap().push_front(user);
+    }
+
+    fn find(queue: &VecDeque<QueuedUser>, id: &str) -> Option<QueuePosition> {
+        return queue
+            .iter()
+            .position(|u| u.id == id)
+            .map(|i| QueuePosition {
+                position: i + 1,
+                waiting: queue.len(),
+                queued_at: queue[i].queued_at,
+            });
+    }
+}
+
+///
+/// create instances for queued users until either ink is at the max number of instances or
+/// nobody is waiting. called whenever a slot may have freed up
+///
+pub async fn provision_next(state: &AppState) {
+    let _lock = state.waitlist.provisioning.lock().await;
+
+    while state.index.len() < state.config.instances.max_instances {
+        let Some(user) = state.waitlist.pop() else {
+            return;
+        };
+
+        // they could have gotten an instance some other way while waiting
+        if !state.index.active_by_owner(&user.id).is_empty() {
+            continue;
+        }
+
+        tracing::info!(
+            "creating instance for {}/{} from the waitlist, waited {:?}",
+            &user.id,
+            &user.username,
+            SystemTime::now()
+                .duration_since(user.queued_at)
+                .unwrap_or_default()
+        );
+
+        // failures are shown to the user on the instance, the same as any other. failing before there is
+        // an instance means none can be made right now, so the user keeps their place until a slot frees up
+        if let Err(e) =
+            docker::provision_instance(state, &user.id, &user.username, user.snapshot.clone())
+        {
+            tracing::error!(
+                "failed to create instance for {}/{}: {}",
+                &user.id,
+                &user.username,
+                e
+            );
+            state.waitlist.put_back(user);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::test_util;
+
+    use super::*;
+
+    fn positions(waitlist: &Waitlist, ids: &[&str]) -> Vec<Option<usize>> {
+        return ids
+            .iter()
+            .map(|id| waitlist.position(id).map(|p| p.position))
+            .collect();
+    }
+
+    #[test]
+    fn first_in_first_out() {
+        let waitlist = Waitlist::new();
+        assert!(waitlist.is_empty());
+
+        for (i, id) in ["1", "2", "3"].into_iter().enumerate() {
+            let position = waitlist.push(id, &format!("user{id}"), None);
+            assert_eq!(position.position, i + 1);
+            assert_eq!(position.waiting, i + 1);
+        }
+        assert_eq!(
+            positions(&waitlist, &["1", "2", "3", "4"]),
+            [Some(1), Some(2), Some(3), None]
+        );
+        assert_eq!(waitlist.position("3").unwrap().waiting, 3);
+
+        assert_eq!(waitlist.pop().unwrap().id, "1");
+        assert_eq!(positions(&waitlist, &["2", "3"]), [Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn joining_twice_keeps_place() {
+        let waitlist = Waitlist::new();
+        let first = waitlist.push("1", "user1", None);
+        waitlist.push("2", "user2", None);
+
+        let again = waitlist.push("1", "user1", None);
+        assert_eq!(again.position, 1);
+        assert_eq!(again.waiting, 2);
+        assert_eq!(again.queued_at, first.queued_at);
+    }
+
+    #[test]
+    fn leave() {
+        let waitlist = Waitlist::new();
+        waitlist.push("1", "user1", None);
+        waitlist.push("2", "user2", None);
+        waitlist.push("3", "user3", None);
+
+        assert!(waitlist.remove("2"));
+        assert!(!waitlist.remove("2"));
+        assert_eq!(
+            positions(&waitlist, &["1", "2", "3"]),
+            [Some(1), None, Some(2)]
+        );
+        assert_eq!(waitlist.position("3").unwrap().waiting, 2);
+
+        assert!(waitlist.remove("1"));
+        assert!(waitlist.remove("3"));
+        assert!(waitlist.is_empty());
+    }
+
+    #[test]
+    fn put_back_at_front() {
+        let waitlist = Waitlist::new();
+        waitlist.push("1", "user1", None);
+        waitlist.push("2", "user2", None);
+
+        let user = waitlist.pop().unwrap();
+        waitlist.put_back(user);
+        assert_eq!(positions(&waitlist, &["1", "2"]), [Some(1), Some(2)]);
+    }
+
+    #[tokio::test]
+    async fn provision_in_order() {
+        let mut config = Config::default();
+        config.instances.max_instances = 2;
+        let state = test_util::state(config).await;
+        test_util::running_instance(&state, "9").await;
+
+        state.waitlist.push("1", "user1", None);
+        state.waitlist.push("2", "user2", None);
+        provision_next(&state).await;
+
+        // only one slot was free, so the second in line keeps waiting at the front
+        assert_eq!(state.index.active_by_owner("1").len(), 1);
+        assert!(state.index.active_by_owner("2").is_empty());
+        assert_eq!(positions(&state.waitlist, &["1", "2"]), [None, Some(1)]);
+    }
+
+    #[tokio::test]
+    async fn skip_users_with_instance() {
+        let mut config = Config::default();
+        config.instances.max_instances = 2;
+        let state = test_util::state(config).await;
+        test_util::running_instance(&state, "1").await;
+
+        state.waitlist.push("1", "user1", None);
+        state.waitlist.push("2", "user2", None);
+        provision_next(&state).await;
+
+        assert_eq!(state.index.active_by_owner("1").len(), 1);
+        assert_eq!(state.index.active_by_owner("2").len(), 1);
+        assert!(state.waitlist.is_empty());
+    }
+
+    #[tokio::test]
+    async fn nobody_waiting() {
+        let state = test_util::state(Config::default()).await;
+
+        provision_next(&state).await;
+        assert_eq!(state.index.len(), 0);
+    }
+}
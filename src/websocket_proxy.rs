@@ -10,6 +10,8 @@ use tokio::{sync::mpsc, time::Duration, time::timeout};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 use url::Host;
 
+use crate::activity::ConnectionGuard;
+
 // this is just
 // https://github.com/tom-lubenow/axum-reverse-proxy/blob/main/src/websocket.rs
 // cause it's public to crate and i need it here too
@@ -40,9 +42,13 @@ pub fn is_websocket_upgrade(headers: &HeaderMap<HeaderValue>) -> bool {
     return has_upgrade && has_connection && has_websocket_key && has_websocket_version;
 }
 
+///
+/// `guard` is held until the websocket closes, so the instance counts as in use while it is open
+///
 pub async fn handle_websocket(
     req: Request<Body>,
     target: &str,
+    guard: ConnectionGuard,
 ) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
     tracing::trace!("Handling WebSocket upgrade request");
 
@@ -132,6 +138,7 @@ pub async fn handle_websocket(
     let (parts, body) = req.into_parts();
     let req = Request::from_parts(parts, body);
     tokio::spawn(async move {
+        let _guard = guard;
         match handle_websocket_connection(req, request).await {
             Ok(_) => tracing::trace!("WebSocket connection closed gracefully"),
             Err(e) => tracing::error!("WebSocket connection error: {}", e),